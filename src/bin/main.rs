@@ -1,22 +1,102 @@
-use clap::{arg, Parser};
+use clap::{Parser, Subcommand, ValueEnum};
+use shellm::shell::audit::{AuditEntry, AuditFilter, AuditLog, Decision};
+use shellm::shell::exec::Shell;
+use shellm::shell::integration::{init_script, last_command, Integration};
+use shellm::shell::policy::Policy;
 use shellm::shell::shell_tools::{ModelMode, Shellm};
-use shellm::utils::model_tool::{ChatRole, ChatWrapper, ModelContainer, ModelInstance};
+use shellm::utils::color::colorify;
+use shellm::utils::config::Config;
+use shellm::utils::grammar::{Constraint, GrammarError};
+use shellm::utils::model_tool::ModelContainer;
+use std::process::exit;
+use toml::{Table, Value};
 
 #[derive(Parser, Debug)]
 #[command(name = "shellm", about, long_about = None)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Commands>,
+
     /// shellm query
     #[arg(short, long)]
     query: Option<String>,
 
-    /// max number of tokens to generate
-    #[arg(long, default_value="10000", value_name="LENGTH")]
-    max: i32,
+    /// max number of new tokens to generate per answer
+    #[arg(long, value_name="LENGTH")]
+    max: Option<i32>,
+
+    /// path to the gguf model
+    #[arg(long, value_name = "PATH")]
+    model: Option<String>,
+
+    /// size of the context window in tokens
+    #[arg(long, value_name = "TOKENS")]
+    ctx: Option<u32>,
+
+    /// number of threads used for generation
+    #[arg(long, value_name = "N")]
+    threads: Option<i32>,
+
+    /// number of threads used for prompt processing
+    #[arg(long, value_name = "N")]
+    threads_batch: Option<i32>,
+
+    /// number of model layers to offload to the gpu
+    #[arg(long, value_name = "N")]
+    gpu_layers: Option<u32>,
+
+    /// sampling temperature, 0 samples greedily
+    #[arg(long, value_name = "T")]
+    temp: Option<f64>,
+
+    /// keep only the k most likely tokens, 0 disables
+    #[arg(long, value_name = "K")]
+    top_k: Option<i32>,
+
+    /// nucleus sampling threshold, 1.0 disables
+    #[arg(long, value_name = "P")]
+    top_p: Option<f64>,
+
+    /// minimum probability relative to the most likely token, 0.0 disables
+    #[arg(long, value_name = "P")]
+    min_p: Option<f64>,
+
+    /// locally typical sampling threshold, 1.0 disables
+    #[arg(long, value_name = "P")]
+    typical_p: Option<f64>,
+
+    /// penalty for repeating recent tokens, 1.0 disables
+    #[arg(long, value_name = "PENALTY")]
+    repeat_penalty: Option<f64>,
+
+    /// penalty scaled by how often a token already appeared
+    #[arg(long, value_name = "PENALTY")]
+    frequency_penalty: Option<f64>,
+
+    /// penalty for any token that already appeared
+    #[arg(long, value_name = "PENALTY")]
+    presence_penalty: Option<f64>,
+
+    /// mirostat sampling version: 0 (off), 1 or 2
+    #[arg(long, value_name = "VERSION")]
+    mirostat: Option<u8>,
+
+    /// sampling seed, random when not set
+    #[arg(long, value_name = "SEED")]
+    seed: Option<u32>,
 
     /// shellm will produce bash commands for you
     #[arg(short, long)]
     bash: bool,
 
+    /// shellm will plan a task as separate commands, each approved on its own
+    #[arg(long)]
+    plan: bool,
+
+    /// shellm will explain the shell command given as the query, segment by segment
+    #[arg(long)]
+    explain: bool,
+
     /// shellm will produce code for you
     #[arg(short, long)]
     code: bool,
@@ -47,41 +127,428 @@ struct Args {
 
     /// when in coding mode, will save generated code to file of <NAME>
     #[arg(short, long, value_name = "NAME")]
-    prog_out: Option<String>
+    prog_out: Option<String>,
+
+    /// when a command fails, offer up to N model-proposed fixes
+    #[arg(long, value_name = "N")]
+    repair: Option<u32>,
+
+    /// generate N alternative commands and pick one in the confirmation prompt
+    #[arg(long, value_name = "N")]
+    candidates: Option<u32>,
+
+    /// run commands with this shell instead of $SHELL
+    #[arg(long, value_name = "PATH")]
+    shell_path: Option<String>,
+
+    /// stop a command after SECS seconds, 0 disables
+    #[arg(long, value_name = "SECS")]
+    timeout: Option<u64>,
+
+    /// pass piped input on to the executed command
+    #[arg(long)]
+    stdin: bool,
+
+    /// print the generated command instead of running it, for shell integrations
+    #[arg(long, conflicts_with = "shell")]
+    print_only: bool,
+
+    /// constrain the answer to a GBNF grammar (not available with --bash)
+    #[arg(long, value_name = "FILE", conflicts_with = "json_schema")]
+    grammar: Option<String>,
+
+    /// constrain the answer to JSON matching a JSON Schema (not available with --bash)
+    #[arg(long, value_name = "FILE")]
+    json_schema: Option<String>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// inspect the resolved configuration
+    Config {
+        #[command(subcommand)]
+        action: ConfigAction,
+    },
+
+    /// print a script that binds Ctrl-G to replace the command line with a generated command
+    Init {
+        #[arg(value_enum)]
+        shell: InitShell,
+
+        /// bind Ctrl and this letter instead of Ctrl-G
+        #[arg(long, default_value_t = 'g')]
+        key: char,
+    },
+
+    /// ask for a corrected version of the last command that failed
+    Fix {
+        /// the command to fix instead of the last one from the shell
+        #[arg(long, value_name = "COMMAND")]
+        command: Option<String>,
+
+        /// its exit status, when not recorded by the shell integration
+        #[arg(long, value_name = "CODE")]
+        status: Option<i32>,
+
+        /// run the command again to show its output to the model
+        #[arg(long)]
+        rerun: bool,
+    },
+
+    /// search the audit log of proposed and executed commands
+    Log {
+        /// only entries whose command or query contains TEXT
+        #[arg(value_name = "TEXT")]
+        text: Option<String>,
+
+        /// only entries with this decision
+        #[arg(long, value_enum)]
+        decision: Option<LogDecision>,
+
+        /// only entries at or after this time, e.g. 2024-05-01 or 2024-05-01T13:00
+        #[arg(long, value_name = "TIME")]
+        since: Option<String>,
+
+        /// only entries at or before this time
+        #[arg(long, value_name = "TIME")]
+        until: Option<String>,
+
+        /// only entries run in this directory or below it
+        #[arg(long, value_name = "DIR")]
+        cwd: Option<String>,
+
+        /// only entries from this user
+        #[arg(long, value_name = "NAME")]
+        user: Option<String>,
+
+        /// only commands that exited with a non-zero status
+        #[arg(long)]
+        failed: bool,
+
+        /// show only the last N matching entries
+        #[arg(short = 'n', long, value_name = "N")]
+        limit: Option<usize>,
+
+        /// print the matching entries as JSON lines
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum LogDecision {
+    Executed,
+    Aborted,
+    Blocked,
+    Skipped,
+    Printed,
+}
+
+impl From<LogDecision> for Decision {
+    fn from(decision: LogDecision) -> Self {
+        match decision {
+            LogDecision::Executed => Decision::Executed,
+            LogDecision::Aborted => Decision::Aborted,
+            LogDecision::Blocked => Decision::Blocked,
+            LogDecision::Skipped => Decision::Skipped,
+            LogDecision::Printed => Decision::Printed,
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum InitShell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+impl From<InitShell> for Integration {
+    fn from(shell: InitShell) -> Self {
+        match shell {
+            InitShell::Bash => Integration::Bash,
+            InitShell::Zsh => Integration::Zsh,
+            InitShell::Fish => Integration::Fish,
+        }
+    }
+}
+
+#[derive(Subcommand, Debug)]
+enum ConfigAction {
+    /// print every setting and where its value came from
+    Show,
+}
+
+impl Args {
+    fn config_overrides(&self) -> Table {
+        let mut table = Table::new();
+        if let Some(max) = self.max {
+            table.insert("max_gen".to_string(), Value::Integer(max as i64));
+        }
+        if let Some(model) = &self.model {
+            table.insert("model_path".to_string(), Value::String(model.clone()));
+        }
+        if let Some(ctx) = self.ctx {
+            table.insert("ctx_window".to_string(), Value::Integer(ctx as i64));
+        }
+        if let Some(threads) = self.threads {
+            table.insert("threads".to_string(), Value::Integer(threads as i64));
+        }
+        if let Some(threads_batch) = self.threads_batch {
+            table.insert("threads_batch".to_string(), Value::Integer(threads_batch as i64));
+        }
+        if let Some(gpu_layers) = self.gpu_layers {
+            table.insert("gpu_layers".to_string(), Value::Integer(gpu_layers as i64));
+        }
+        if let Some(repair) = self.repair {
+            table.insert("repair_attempts".to_string(), Value::Integer(repair as i64));
+        }
+        if let Some(candidates) = self.candidates {
+            table.insert("candidates".to_string(), Value::Integer(candidates as i64));
+        }
+        if let Some(shell) = &self.shell_path {
+            table.insert("shell".to_string(), Value::String(shell.clone()));
+        }
+        if let Some(timeout) = self.timeout {
+            table.insert("command_timeout".to_string(), Value::Integer(timeout as i64));
+        }
+        if self.stdin {
+            table.insert("pass_stdin".to_string(), Value::Boolean(true));
+        }
+
+        let mut sampling = Table::new();
+        let floats = [
+            ("temperature", self.temp),
+            ("top_p", self.top_p),
+            ("min_p", self.min_p),
+            ("typical_p", self.typical_p),
+            ("repeat_penalty", self.repeat_penalty),
+            ("frequency_penalty", self.frequency_penalty),
+            ("presence_penalty", self.presence_penalty),
+        ];
+        for (key, value) in floats {
+            if let Some(value) = value {
+                sampling.insert(key.to_string(), Value::Float(value));
+            }
+        }
+        let ints = [
+            ("top_k", self.top_k.map(|v| v as i64)),
+            ("mirostat", self.mirostat.map(|v| v as i64)),
+            ("seed", self.seed.map(|v| v as i64)),
+        ];
+        for (key, value) in ints {
+            if let Some(value) = value {
+                sampling.insert(key.to_string(), Value::Integer(value));
+            }
+        }
+        if !sampling.is_empty() {
+            table.insert("sampling".to_string(), Value::Table(sampling));
+        }
+        table
+    }
+
+    fn constraint(&self) -> Option<Result<Constraint, GrammarError>> {
+        if let Some(path) = &self.grammar {
+            Some(Constraint::from_gbnf_file(path))
+        } else {
+            self.json_schema.as_ref().map(|path| Constraint::from_json_schema_file(path))
+        }
+    }
+
+    fn model_mode(&self, config: &Config) -> Option<ModelMode> {
+        if self.bash {
+            Some(ModelMode::CMD)
+        } else if self.plan {
+            Some(ModelMode::PLAN)
+        } else if self.explain {
+            Some(ModelMode::EXPLAIN)
+        } else if self.code {
+            Some(ModelMode::CODE)
+        } else if self.math {
+            Some(ModelMode::MATH)
+        } else if self.writing {
+            Some(ModelMode::WRITING)
+        } else if self.general {
+            Some(ModelMode::GENERAL)
+        } else {
+            ModelMode::from_name(&config.default_mode)
+        }
+    }
+}
+
+fn fail(msg: &str) -> ! {
+    eprintln!("{}", colorify(msg, 247., 89., 89.));
+    exit(1);
+}
+
+fn show_config(config: &Config) {
+    let entries = config.describe();
+    let key_width = entries.iter().map(|(k, _, _)| k.len()).max().unwrap_or(0);
+    let value_width = entries.iter().map(|(_, v, _)| v.len()).max().unwrap_or(0);
+
+    for (key, value, source) in entries {
+        println!(
+            "{:key_width$} = {:value_width$}  {}",
+            key,
+            value,
+            colorify(&format!("({})", source), 150., 150., 150.),
+        );
+    }
+    if config.max_gen > config.ctx_window as i32 / 2 {
+        let note = format!("max_gen is capped at {}, half of ctx_window, to leave room for the history", config.ctx_window / 2);
+        println!("{}", colorify(&note, 150., 150., 150.));
+    }
+}
+
+fn show_log(config: &Config, filter: &AuditFilter, limit: Option<usize>, json: bool) {
+    let log = match AuditLog::new(config.audit_log.as_deref()) {
+        Ok(log) => log,
+        Err(e) => fail(&e.0),
+    };
+    let (entries, malformed) = match log.read() {
+        Ok(read) => read,
+        Err(e) => fail(&e.0),
+    };
+
+    let matching: Vec<&AuditEntry> = entries.iter().filter(|e| filter.matches(e)).collect();
+    let skip = limit.map_or(0, |n| matching.len().saturating_sub(n));
+    for entry in &matching[skip..] {
+        if json {
+            println!("{}", serde_json::to_string(entry).unwrap());
+            continue;
+        }
+
+        let (r, g, b) = match entry.decision {
+            Decision::Executed if entry.exit_status == Some(0) => (59., 235., 115.),
+            Decision::Executed | Decision::Blocked => (247., 89., 89.),
+            Decision::Aborted | Decision::Skipped | Decision::Printed => (150., 150., 150.),
+        };
+        let status = match (entry.exit_status, entry.duration_ms) {
+            (Some(code), Some(ms)) => format!(" exit {} in {:.1}s", code, ms as f64 / 1000.),
+            (None, Some(ms)) => format!(" killed after {:.1}s", ms as f64 / 1000.),
+            _ => String::new(),
+        };
+        println!(
+            "{} {} {}{} {}",
+            colorify(&entry.timestamp, 150., 150., 150.),
+            entry.user,
+            colorify(&entry.decision.to_string(), r, g, b),
+            status,
+            colorify(&entry.cwd, 59., 150., 235.),
+        );
+        println!("    {}", colorify(&entry.command, 59., 235., 115.));
+        if let Some(proposed) = &entry.edited_from {
+            println!("    {}", colorify(&format!("edited from: {}", proposed), 150., 150., 150.));
+        }
+        println!("    {}", colorify(&format!("query: {}", entry.query), 150., 150., 150.));
+    }
+
+    if malformed > 0 {
+        eprintln!("{}", colorify(&format!("Skipped {} unreadable lines in {}", malformed, log.path().display()), 247., 89., 89.));
+    }
 }
 
 fn main() {
     let arguments = Args::parse();
 
-    let model_mode = if arguments.bash {
-        ModelMode::CMD
-    } else if arguments.code {
-        ModelMode::CODE
-    } else if arguments.ma {
-        ModelMode::MATH
-    } else if arguments.writing {
-        ModelMode::WRITING
-    } else {
-        ModelMode::GENERAL
+    let config = match Config::load(arguments.config_overrides()) {
+        Ok(config) => config,
+        Err(e) => fail(&e.0),
+    };
+
+    match &arguments.command {
+        Some(Commands::Config { action: ConfigAction::Show }) => {
+            show_config(&config);
+            return;
+        }
+        Some(Commands::Log { text, decision, since, until, cwd, user, failed, limit, json }) => {
+            let filter = AuditFilter {
+                text: text.clone(),
+                decision: decision.map(Decision::from),
+                since: since.clone(),
+                until: until.clone(),
+                cwd: cwd.clone(),
+                user: user.clone(),
+                failed: *failed,
+            };
+            show_log(&config, &filter, *limit, *json);
+            return;
+        }
+        Some(Commands::Init { shell, key }) => {
+            if !key.is_ascii_alphabetic() {
+                fail("--key must be a letter");
+            }
+            // the absolute path keeps the binding working when shellm is not on PATH
+            let binary = std::env::current_exe().map(|p| p.display().to_string()).unwrap_or_else(|_| "shellm".to_string());
+            print!("{}", init_script(Integration::from(*shell), &binary, *key));
+            return;
+        }
+        Some(Commands::Fix { .. }) | None => {}
+    }
+
+    let fix = match &arguments.command {
+        Some(Commands::Fix { command: Some(command), status, rerun }) => Some((command.clone(), *status, *rerun)),
+        Some(Commands::Fix { command: None, status, rerun }) => match last_command(&Shell::resolve(config.shell.as_deref())) {
+            Some((command, recorded)) => Some((command, status.or(recorded), *rerun)),
+            None => fail("No command to fix. Set up `shellm init` or pass --command"),
+        },
+        _ => None,
+    };
+
+    let model_mode = match arguments.model_mode(&config) {
+        _ if fix.is_some() => ModelMode::CMD,
+        Some(mode) => mode,
+        None => fail(&format!("Unknown default_mode '{}'", config.default_mode)),
+    };
+
+    if arguments.print_only && !matches!(model_mode, ModelMode::CMD) {
+        fail("--print-only only works with --bash");
+    }
+
+    let constraint = match arguments.constraint() {
+        Some(Ok(_)) if matches!(model_mode, ModelMode::CMD | ModelMode::PLAN | ModelMode::EXPLAIN) => fail("--grammar and --json-schema cannot be combined with --bash, --plan or --explain"),
+        Some(Ok(constraint)) => Some(constraint),
+        Some(Err(e)) => fail(&e.0),
+        None => None,
+    };
+
+    let policy = match Policy::load() {
+        Ok(policy) => policy,
+        Err(e) => fail(&e.0),
     };
 
-    let container = ModelContainer::new(
-        "/home/v18/Documents/Code/ml/gguf_models/qwen2.5-coder-7b-instruct-q4_k_m.gguf",
-    );
+    let model_path = match &config.model_path {
+        Some(path) => path.clone(),
+        None => fail("No model configured. Set model_path in ~/.config/shellm/config.toml, SHELLM_MODEL_PATH or --model"),
+    };
 
-    let max_gen = if arguments.max < 30000 { arguments.max } else { 30000 };
+    let container = match ModelContainer::new(&model_path, config.gpu_layers) {
+        Ok(container) => container,
+        Err(_e) => fail(&format!("Could not load model {}", model_path)),
+    };
 
     let mut shellm = Shellm::new(
         arguments.query,
         model_mode,
-        max_gen,
         arguments.shell,
         arguments.load,
         arguments.save,
         arguments.prog_out,
         &container,
-        30000,
+        &config,
+        policy,
     )
     .unwrap();
-    shellm.run();
-}
\ No newline at end of file
+    shellm.set_print_only(arguments.print_only);
+    if let Some(constraint) = constraint {
+        if let Err(e) = shellm.set_output_constraint(&constraint) {
+            fail(&e.0);
+        }
+    }
+    let code = match fix {
+        Some((command, status, rerun)) => shellm.fix(&command, status, rerun),
+        None => shellm.run(),
+    };
+    drop(shellm);
+    exit(code);
+}
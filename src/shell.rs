@@ -1 +1,15 @@
-pub mod shell_tools;
\ No newline at end of file
+pub mod shell_tools;
+pub mod proposal;
+pub mod command_parser;
+pub mod safety;
+pub mod policy;
+pub mod audit;
+pub mod exec;
+pub mod editor;
+pub mod context;
+pub mod listing;
+pub mod git;
+pub mod integration;
+pub mod explain;
+pub mod manual;
+pub mod validate;
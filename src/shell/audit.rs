@@ -0,0 +1,300 @@
+use crate::utils::sampling::SamplingConfig;
+use crate::utils::utils::format_timestamp;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::path::PathBuf;
+use std::time::SystemTime;
+use std::{env, fmt};
+
+const DATA_DIR_NAME: &str = "shellm";
+const AUDIT_FILE_NAME: &str = "audit.jsonl";
+
+#[derive(Debug)]
+pub struct AuditError(pub String);
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Decision {
+    Executed,
+    Aborted,
+    Blocked,
+    Skipped,
+    Printed, // handed to the shell for review instead of being run
+}
+
+impl fmt::Display for Decision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Decision::Executed => write!(f, "executed"),
+            Decision::Aborted => write!(f, "aborted"),
+            Decision::Blocked => write!(f, "blocked"),
+            Decision::Skipped => write!(f, "skipped"),
+            Decision::Printed => write!(f, "printed"),
+        }
+    }
+}
+
+/// One proposed command and what happened to it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AuditEntry {
+    pub timestamp: String, // RFC 3339, UTC
+    pub user: String,
+    pub cwd: String,
+    pub query: String,
+    pub model: String,
+    pub sampling: SamplingConfig,
+    pub command: String,
+    #[serde(default)]
+    pub edited_from: Option<String>, // the proposed command when the user changed it
+    pub risk: String,
+    pub findings: Vec<String>,
+    pub decision: Decision,
+    #[serde(default)]
+    pub attempt: u32, // 0 for the first proposal, then one per repair
+    #[serde(default)]
+    pub step: Option<usize>, // position in a plan, starting at 1
+    pub exit_status: Option<i32>, // none when not executed or killed by a signal
+    pub duration_ms: Option<u64>,
+}
+
+impl AuditEntry {
+    /// An entry stamped with the current time, user and working directory.
+    pub fn now(query: &str, model: &str, sampling: &SamplingConfig, command: &str) -> Self {
+        AuditEntry {
+            timestamp: format_timestamp(SystemTime::now()),
+            user: current_user(),
+            cwd: env::current_dir().map(|p| p.display().to_string()).unwrap_or_default(),
+            query: query.to_string(),
+            model: model.to_string(),
+            sampling: sampling.clone(),
+            command: command.to_string(),
+            edited_from: None,
+            risk: String::new(),
+            findings: vec![],
+            decision: Decision::Aborted,
+            attempt: 0,
+            step: None,
+            exit_status: None,
+            duration_ms: None,
+        }
+    }
+}
+
+/// Narrows down `shellm log` output; unset fields match everything.
+#[derive(Debug, Default)]
+pub struct AuditFilter {
+    pub text: Option<String>,
+    pub decision: Option<Decision>,
+    pub since: Option<String>,
+    pub until: Option<String>,
+    pub cwd: Option<String>,
+    pub user: Option<String>,
+    pub failed: bool,
+}
+
+impl AuditFilter {
+    pub fn matches(&self, entry: &AuditEntry) -> bool {
+        if let Some(text) = &self.text {
+            let text = text.to_lowercase();
+            if !entry.command.to_lowercase().contains(&text) && !entry.query.to_lowercase().contains(&text) {
+                return false;
+            }
+        }
+        if self.decision.is_some_and(|d| d != entry.decision) {
+            return false;
+        }
+        // timestamps share one format, so a prefix like 2024-05-01 compares correctly
+        if self.since.as_ref().is_some_and(|since| entry.timestamp.as_str() < since.as_str()) {
+            return false;
+        }
+        if self.until.as_ref().is_some_and(|until| entry.timestamp.as_str() > until.as_str() && !entry.timestamp.starts_with(until.as_str())) {
+            return false;
+        }
+        if self.cwd.as_ref().is_some_and(|cwd| !entry.cwd.starts_with(cwd.as_str())) {
+            return false;
+        }
+        if self.user.as_ref().is_some_and(|user| entry.user != *user) {
+            return false;
+        }
+        if self.failed && !matches!(entry.exit_status, Some(code) if code != 0) {
+            return false;
+        }
+        true
+    }
+}
+
+pub struct AuditLog {
+    path: PathBuf,
+}
+
+impl AuditLog {
+    /// The log at `path`, or `$XDG_DATA_HOME/shellm/audit.jsonl` when not configured.
+    pub fn new(path: Option<&str>) -> Result<Self, AuditError> {
+        let path = match path {
+            Some(path) => PathBuf::from(path),
+            None => match Self::default_path() {
+                Some(path) => path,
+                None => return Err(AuditError("Cannot locate the audit log: HOME is not set".to_string())),
+            },
+        };
+        Ok(AuditLog { path })
+    }
+
+    pub fn default_path() -> Option<PathBuf> {
+        let base = match env::var_os("XDG_DATA_HOME") {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => PathBuf::from(env::var_os("HOME")?).join(".local").join("share"),
+        };
+        Some(base.join(DATA_DIR_NAME).join(AUDIT_FILE_NAME))
+    }
+
+    pub fn path(&self) -> &PathBuf {
+        &self.path
+    }
+
+    pub fn append(&self, entry: &AuditEntry) -> Result<(), AuditError> {
+        if let Some(dir) = self.path.parent() {
+            if let Err(e) = fs::create_dir_all(dir) {
+                return Err(AuditError(format!("Cannot create {}: {}", dir.display(), e)));
+            }
+        }
+        let mut line = match serde_json::to_string(entry) {
+            Ok(line) => line,
+            Err(e) => return Err(AuditError(format!("Cannot serialize audit entry: {}", e))),
+        };
+        line.push('\n');
+
+        let file = OpenOptions::new().create(true).append(true).mode(0o600).open(&self.path);
+        match file.and_then(|mut file| file.write_all(line.as_bytes())) {
+            Ok(()) => Ok(()),
+            Err(e) => Err(AuditError(format!("Cannot write audit log {}: {}", self.path.display(), e))),
+        }
+    }
+
+    /// Every entry in the log, oldest first, along with the number of lines that could not be read.
+    pub fn read(&self) -> Result<(Vec<AuditEntry>, usize), AuditError> {
+        if !self.path.exists() {
+            return Ok((vec![], 0));
+        }
+        let content = match fs::read_to_string(&self.path) {
+            Ok(content) => content,
+            Err(e) => return Err(AuditError(format!("Cannot read audit log {}: {}", self.path.display(), e))),
+        };
+
+        let mut entries = vec![];
+        let mut malformed = 0;
+        for line in content.lines().filter(|l| !l.trim().is_empty()) {
+            match serde_json::from_str::<AuditEntry>(line) {
+                Ok(entry) => entries.push(entry),
+                Err(_e) => malformed += 1,
+            }
+        }
+        Ok((entries, malformed))
+    }
+}
+
+fn current_user() -> String {
+    match env::var("USER").or_else(|_| env::var("LOGNAME")) {
+        Ok(user) if !user.is_empty() => user,
+        _ => match fs::metadata("/proc/self") {
+            Ok(meta) => format!("uid {}", meta.uid()),
+            Err(_e) => "unknown".to_string(),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(timestamp: &str, command: &str, decision: Decision, exit_status: Option<i32>) -> AuditEntry {
+        let mut entry = AuditEntry::now("clean up", "test-model", &SamplingConfig::default(), command);
+        entry.timestamp = timestamp.to_string();
+        entry.cwd = "/home/test/project".to_string();
+        entry.user = "test".to_string();
+        entry.decision = decision;
+        entry.exit_status = exit_status;
+        entry
+    }
+
+    fn temp_log(name: &str) -> AuditLog {
+        let path = env::temp_dir().join(format!("shellm-audit-{}-{}", name, std::process::id())).join(AUDIT_FILE_NAME);
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+        AuditLog { path }
+    }
+
+    #[test]
+    fn time_range() {
+        let entry = entry("2024-05-01T12:30:00Z", "ls", Decision::Executed, Some(0));
+        let filter = |since: Option<&str>, until: Option<&str>| {
+            AuditFilter { since: since.map(str::to_string), until: until.map(str::to_string), ..Default::default() }.matches(&entry)
+        };
+        assert!(filter(Some("2024-05-01"), None));
+        assert!(filter(Some("2024-04"), None));
+        assert!(!filter(Some("2024-05-02"), None));
+        assert!(!filter(Some("2024-05-01T13"), None));
+        // an until prefix includes everything it covers
+        assert!(filter(None, Some("2024-05-01")));
+        assert!(filter(None, Some("2024-05")));
+        assert!(filter(None, Some("2024-05-01T12:30")));
+        assert!(!filter(None, Some("2024-04-30")));
+        assert!(!filter(None, Some("2024-05-01T12:29")));
+        assert!(filter(Some("2024-05-01"), Some("2024-05-01")));
+    }
+
+    #[test]
+    fn failed_commands() {
+        let failed = AuditFilter { failed: true, ..Default::default() };
+        assert!(failed.matches(&entry("2024-05-01T12:00:00Z", "false", Decision::Executed, Some(1))));
+        assert!(!failed.matches(&entry("2024-05-01T12:00:00Z", "true", Decision::Executed, Some(0))));
+        // killed by a signal or never run
+        assert!(!failed.matches(&entry("2024-05-01T12:00:00Z", "sleep 9", Decision::Executed, None)));
+        assert!(!failed.matches(&entry("2024-05-01T12:00:00Z", "rm -rf /", Decision::Blocked, None)));
+    }
+
+    #[test]
+    fn other_fields() {
+        let entry = entry("2024-05-01T12:00:00Z", "rm *.TMP", Decision::Aborted, None);
+        assert!(AuditFilter { text: Some("tmp".to_string()), ..Default::default() }.matches(&entry));
+        assert!(AuditFilter { text: Some("CLEAN".to_string()), ..Default::default() }.matches(&entry));
+        assert!(!AuditFilter { text: Some("mv".to_string()), ..Default::default() }.matches(&entry));
+        assert!(AuditFilter { decision: Some(Decision::Aborted), ..Default::default() }.matches(&entry));
+        assert!(!AuditFilter { decision: Some(Decision::Executed), ..Default::default() }.matches(&entry));
+        assert!(AuditFilter { cwd: Some("/home/test".to_string()), ..Default::default() }.matches(&entry));
+        assert!(!AuditFilter { cwd: Some("/tmp".to_string()), ..Default::default() }.matches(&entry));
+        assert!(!AuditFilter { user: Some("root".to_string()), ..Default::default() }.matches(&entry));
+    }
+
+    #[test]
+    fn appended_entries_read_back() {
+        let log = temp_log("roundtrip");
+        assert_eq!(log.read().unwrap().0.len(), 0);
+        log.append(&entry("2024-05-01T12:00:00Z", "ls -la", Decision::Executed, Some(0))).unwrap();
+        log.append(&entry("2024-05-02T08:00:00Z", "rm -rf build", Decision::Aborted, None)).unwrap();
+        let (entries, malformed) = log.read().unwrap();
+        let mode = fs::metadata(log.path()).unwrap().mode() & 0o777;
+        fs::remove_dir_all(log.path().parent().unwrap()).unwrap();
+
+        assert_eq!(malformed, 0);
+        let commands: Vec<&str> = entries.iter().map(|e| e.command.as_str()).collect();
+        assert_eq!(commands, vec!["ls -la", "rm -rf build"]);
+        assert_eq!(entries[1].decision, Decision::Aborted);
+        assert_eq!(mode, 0o600);
+    }
+
+    #[test]
+    fn malformed_lines_are_counted() {
+        let log = temp_log("malformed");
+        log.append(&entry("2024-05-01T12:00:00Z", "ls", Decision::Executed, Some(0))).unwrap();
+        let mut file = OpenOptions::new().append(true).open(log.path()).unwrap();
+        file.write_all(b"{\"timestamp\": \"cut off\n\nnot json at all\n{}\n").unwrap();
+        log.append(&entry("2024-05-01T13:00:00Z", "pwd", Decision::Executed, Some(0))).unwrap();
+        let (entries, malformed) = log.read().unwrap();
+        fs::remove_dir_all(log.path().parent().unwrap()).unwrap();
+
+        assert_eq!(entries.len(), 2);
+        assert_eq!(malformed, 3);
+    }
+}
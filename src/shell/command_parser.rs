@@ -0,0 +1,723 @@
+//! A best-effort parser for the subset of POSIX shell that generated commands use: pipelines,
+//! `&&`/`||`/`;`/`&` lists, subshells and groups, command and process substitution,
+//! redirections, quoting and here-documents. Scripts run through `sh -c`, `su -c`, `eval` and
+//! `find -exec` are parsed too. It never fails; anything it cannot make sense of is reported
+//! through `Script::incomplete`.
+
+use std::env;
+use std::path::PathBuf;
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Word {
+    /// The word with quotes removed and escapes resolved. Variables and substitutions are kept
+    /// verbatim, e.g. `"$HOME/x"` becomes `$HOME/x`.
+    pub text: String,
+    /// The bodies of every `$(..)`, `` `..` ``, `<(..)` and `>(..)` inside the word.
+    pub substitutions: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Redirect {
+    /// The operator including an explicit descriptor, e.g. `>`, `>>`, `2>`, `&>` or `<<`.
+    pub op: String,
+    pub target: String,
+}
+
+impl Redirect {
+    /// True for redirections that truncate their target.
+    pub fn overwrites(&self) -> bool {
+        let op = self.op.trim_start_matches(|c: char| c.is_ascii_digit());
+        matches!(op, ">" | ">|" | "&>")
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct SimpleCommand {
+    pub words: Vec<Word>,
+    pub redirects: Vec<Redirect>,
+}
+
+impl SimpleCommand {
+    pub fn argv(&self) -> Vec<&str> {
+        self.words.iter().map(|w| w.text.as_str()).collect()
+    }
+
+    /// The argv that actually runs, without leading assignments and wrappers like `sudo` or `env`.
+    pub fn effective_argv(&self) -> Vec<&str> {
+        self.split_wrappers().1
+    }
+
+    /// The wrappers in front of the command that actually runs, e.g. `sudo` or `xargs`.
+    pub fn wrappers(&self) -> Vec<&str> {
+        self.split_wrappers().0
+    }
+
+    /// True when the command runs through `sudo`, `doas`, `pkexec` or `su`.
+    pub fn is_privileged(&self) -> bool {
+        self.wrappers().iter().any(|w| PRIVILEGE_WRAPPERS.contains(&base_name(w)))
+    }
+
+    /// The command line run by `sh -c`, `su -c` or `eval`, without its quotes.
+    pub fn inline_script(&self) -> Option<String> {
+        let argv = self.argv();
+        let su = self.wrappers().iter().any(|w| base_name(w) == "su");
+        if let Some(su) = argv.iter().position(|a| su && base_name(a) == "su") {
+            let c = argv[su + 1..].iter().position(|a| *a == "-c")?;
+            return argv.get(su + c + 2).map(|script| script.to_string());
+        }
+
+        let argv = self.effective_argv();
+        let name = base_name(argv.first()?);
+        if name == "eval" {
+            return Some(argv[1..].join(" "));
+        }
+        if !SHELLS.contains(&name) {
+            return None;
+        }
+        // the script is the first operand once -c was given, also in clusters like -ec
+        let mut inline = false;
+        let mut i = 1;
+        while i < argv.len() && argv[i].starts_with(['-', '+']) && argv[i] != "--" {
+            inline |= argv[i].starts_with('-') && !argv[i].starts_with("--") && argv[i].contains('c');
+            i += if matches!(argv[i], "-o" | "+o" | "-O" | "+O") { 2 } else { 1 };
+        }
+        if argv.get(i) == Some(&"--") {
+            i += 1;
+        }
+        argv.get(i).filter(|_| inline).map(|script| script.to_string())
+    }
+
+    /// The commands `find` runs for its matches with `-exec`, `-execdir`, `-ok` and `-okdir`.
+    pub fn exec_commands(&self) -> Vec<SimpleCommand> {
+        let argv = self.effective_argv();
+        if argv.first().map(|a| base_name(a)) != Some("find") {
+            return vec![];
+        }
+        // the words of the effective argv are the last ones of the command
+        let words = &self.words[self.words.len() - argv.len()..];
+        let mut commands = vec![];
+        let mut i = 0;
+        while i < words.len() {
+            if matches!(words[i].text.as_str(), "-exec" | "-execdir" | "-ok" | "-okdir") {
+                let end = words[i + 1..].iter().position(|w| w.text == ";" || w.text == "+").map_or(words.len(), |end| i + 1 + end);
+                if end > i + 1 {
+                    commands.push(SimpleCommand { words: words[i + 1..end].to_vec(), redirects: vec![] });
+                }
+                i = end;
+            }
+            i += 1;
+        }
+        commands
+    }
+
+    fn split_wrappers(&self) -> (Vec<&str>, Vec<&str>) {
+        let argv = self.argv();
+        let mut wrappers = vec![];
+        let mut i = 0;
+        while i < argv.len() {
+            let arg = argv[i];
+            if is_assignment(arg) {
+                i += 1;
+                continue;
+            }
+            if !is_wrapper(arg) {
+                break;
+            }
+            let wrapper = base_name(arg);
+            if wrapper == "command" && argv.get(i + 1).is_some_and(|o| matches!(*o, "-v" | "-V")) {
+                // command -v only looks the name up
+                break;
+            }
+            wrappers.push(arg);
+            i += 1;
+            if wrapper == "su" {
+                // su only runs a command line given with -c, see `inline_script`
+                return (wrappers, vec![]);
+            }
+            // options of the wrapper, including the ones that take a value
+            while i < argv.len() && argv[i].starts_with('-') {
+                let takes_value = wrapper_option_takes_value(wrapper, argv[i]);
+                i += if takes_value { 2 } else { 1 };
+            }
+            if wrapper == "timeout" && i < argv.len() {
+                i += 1; // the duration
+            }
+        }
+        let rest = argv[i.min(argv.len())..].to_vec();
+        (wrappers, rest)
+    }
+}
+
+/// Commands joined by `|` or `|&`.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Pipeline {
+    pub commands: Vec<SimpleCommand>,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Script {
+    /// Every pipeline in source order. Subshells, groups and substitutions are flattened into
+    /// this list, since they run the same commands.
+    pub pipelines: Vec<Pipeline>,
+    /// Set when a quote, substitution or here-document was left unterminated.
+    pub incomplete: bool,
+}
+
+impl Script {
+    pub fn commands(&self) -> impl Iterator<Item = &SimpleCommand> {
+        self.pipelines.iter().flat_map(|p| p.commands.iter())
+    }
+}
+
+const PRIVILEGE_WRAPPERS: [&str; 4] = ["sudo", "doas", "pkexec", "su"];
+const SHELLS: [&str; 7] = ["sh", "bash", "zsh", "dash", "ksh", "mksh", "fish"];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Token {
+    Word(Word),
+    Op(String),
+}
+
+// words that only structure a command list; dropped when they start a command
+pub const RESERVED_WORDS: [&str; 13] = ["{", "}", "!", "if", "then", "elif", "else", "fi", "while", "until", "do", "done", "esac"];
+
+pub fn parse(command: &str) -> Script {
+    let mut lexer = Lexer::new(command);
+    let tokens = lexer.run();
+
+    let mut script = Script { pipelines: vec![], incomplete: lexer.incomplete };
+    let mut pipeline = Pipeline::default();
+    let mut current = SimpleCommand::default();
+    let mut pending_redirect: Option<String> = None;
+    let mut nested: Vec<String> = vec![];
+
+    for (token, _) in tokens {
+        match token {
+            Token::Word(word) => {
+                nested.extend(word.substitutions.iter().cloned());
+                if let Some(op) = pending_redirect.take() {
+                    current.redirects.push(Redirect { op, target: word.text });
+                } else if current.words.is_empty() && RESERVED_WORDS.contains(&word.text.as_str()) {
+                    continue;
+                } else {
+                    current.words.push(word);
+                }
+            }
+            Token::Op(op) if is_redirect(&op) => pending_redirect = Some(op),
+            Token::Op(op) => {
+                pending_redirect = None;
+                if !current.words.is_empty() || !current.redirects.is_empty() {
+                    pipeline.commands.push(std::mem::take(&mut current));
+                }
+                if op != "|" && op != "|&" && !pipeline.commands.is_empty() {
+                    script.pipelines.push(std::mem::take(&mut pipeline));
+                }
+            }
+        }
+    }
+    if !current.words.is_empty() || !current.redirects.is_empty() {
+        pipeline.commands.push(current);
+    }
+    if !pipeline.commands.is_empty() {
+        script.pipelines.push(pipeline);
+    }
+    add_nested(&mut script);
+
+    for body in nested {
+        let inner = parse(&body);
+        script.incomplete |= inner.incomplete;
+        script.pipelines.extend(inner.pipelines);
+    }
+    script
+}
+
+/// Adds the commands run through `sh -c`, `su -c`, `eval` and `find -exec` to `script`, since
+/// they run just like the others.
+fn add_nested(script: &mut Script) {
+    let mut pending: Vec<SimpleCommand> = script.commands().cloned().collect();
+    while let Some(command) = pending.pop() {
+        if let Some(body) = command.inline_script() {
+            let inner = parse(&body);
+            script.incomplete |= inner.incomplete;
+            script.pipelines.extend(inner.pipelines);
+        }
+        for exec in command.exec_commands() {
+            pending.push(exec.clone());
+            script.pipelines.push(Pipeline { commands: vec![exec] });
+        }
+    }
+}
+
+/// The tokens of `command` along with the source text each one came from, quotes included.
+/// Here-document bodies are left out.
+pub fn tokenize(command: &str) -> Vec<(Token, String)> {
+    let mut lexer = Lexer::new(command);
+    let tokens = lexer.run();
+    tokens
+        .into_iter()
+        .map(|(token, (start, end))| (token, lexer.chars[start..end.min(lexer.chars.len())].iter().collect()))
+        .collect()
+}
+
+/// True for the wrapper options that are followed by a separate value, like `sudo -u root`.
+pub fn wrapper_option_takes_value(wrapper: &str, option: &str) -> bool {
+    match base_name(wrapper) {
+        "sudo" => matches!(option, "-u" | "-g" | "-C" | "-h" | "-p" | "-U"),
+        "xargs" => matches!(option, "-I" | "-n" | "-P" | "-L" | "-d" | "-s" | "-E" | "-a"),
+        "nice" => option == "-n",
+        "timeout" => matches!(option, "-s" | "-k"),
+        _ => false,
+    }
+}
+
+/// True for `sudo`, `env`, `xargs` and the other commands that run the command after them.
+pub fn is_wrapper(arg: &str) -> bool {
+    let name = base_name(arg);
+    PRIVILEGE_WRAPPERS.contains(&name) || matches!(name, "env" | "nohup" | "time" | "nice" | "command" | "exec" | "xargs" | "timeout" | "stdbuf")
+}
+
+pub fn is_assignment(arg: &str) -> bool {
+    match arg.split_once('=') {
+        Some((name, _)) => !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') && !name.starts_with(|c: char| c.is_ascii_digit()),
+        None => false,
+    }
+}
+
+pub fn base_name(arg: &str) -> &str {
+    arg.rsplit('/').next().unwrap_or(arg)
+}
+
+/// Expands a leading `~` or `$HOME`.
+pub fn expand_home(path: &str) -> PathBuf {
+    if matches!(path, "~" | "$HOME" | "${HOME}") {
+        if let Some(home) = env::var_os("HOME") {
+            return PathBuf::from(home);
+        }
+    }
+    for prefix in ["~/", "$HOME/", "${HOME}/"] {
+        if let Some(rest) = path.strip_prefix(prefix) {
+            if let Some(home) = env::var_os("HOME") {
+                return PathBuf::from(home).join(rest);
+            }
+        }
+    }
+    PathBuf::from(path)
+}
+
+pub fn is_redirect(op: &str) -> bool {
+    op.contains('>') || op.contains('<')
+}
+
+struct Lexer {
+    chars: Vec<char>,
+    pos: usize,
+    incomplete: bool,
+    heredocs: Vec<(String, bool)>, // delimiter, strip leading tabs
+}
+
+impl Lexer {
+    fn new(source: &str) -> Self {
+        Lexer { chars: source.chars().collect(), pos: 0, incomplete: false, heredocs: vec![] }
+    }
+
+    fn peek(&self, offset: usize) -> Option<char> {
+        self.chars.get(self.pos + offset).copied()
+    }
+
+    /// Every token with its span in `chars`.
+    fn run(&mut self) -> Vec<(Token, (usize, usize))> {
+        let mut tokens = vec![];
+        let mut word = Word::default();
+        let mut in_word = false;
+        let mut start = 0;
+        let mut heredoc_next: Option<bool> = None;
+
+        macro_rules! finish_word {
+            () => {
+                if in_word {
+                    let finished = std::mem::take(&mut word);
+                    if let Some(strip) = heredoc_next.take() {
+                        self.heredocs.push((finished.text.clone(), strip));
+                    }
+                    tokens.push((Token::Word(finished), (start, self.pos)));
+                    in_word = false;
+                }
+            };
+        }
+
+        while let Some(c) = self.peek(0) {
+            if !in_word {
+                start = self.pos;
+            }
+            match c {
+                ' ' | '\t' => {
+                    finish_word!();
+                    self.pos += 1;
+                }
+                '\n' => {
+                    finish_word!();
+                    self.pos += 1;
+                    tokens.push((Token::Op(";".to_string()), (self.pos - 1, self.pos)));
+                    self.skip_heredocs();
+                }
+                '#' if !in_word => {
+                    while self.peek(0).is_some_and(|c| c != '\n') {
+                        self.pos += 1;
+                    }
+                }
+                '\\' => {
+                    in_word = true;
+                    match self.peek(1) {
+                        Some('\n') => {}
+                        Some(escaped) => word.text.push(escaped),
+                        None => {}
+                    }
+                    self.pos += 2;
+                }
+                '\'' => {
+                    in_word = true;
+                    self.pos += 1;
+                    match self.take_until('\'') {
+                        Some(quoted) => word.text.push_str(&quoted),
+                        None => self.incomplete = true,
+                    }
+                }
+                '"' => {
+                    in_word = true;
+                    self.pos += 1;
+                    self.double_quoted(&mut word);
+                }
+                '`' => {
+                    in_word = true;
+                    self.pos += 1;
+                    let body = self.take_until('`');
+                    self.push_substitution(&mut word, "`", body, "`");
+                }
+                '$' if self.peek(1) == Some('(') => {
+                    in_word = true;
+                    self.pos += 2;
+                    let body = self.take_balanced();
+                    self.push_substitution(&mut word, "$(", body, ")");
+                }
+                '<' | '>' if self.peek(1) == Some('(') => {
+                    in_word = true;
+                    self.pos += 2;
+                    let open = format!("{}(", c);
+                    let body = self.take_balanced();
+                    self.push_substitution(&mut word, &open, body, ")");
+                }
+                '<' | '>' => {
+                    // a bare number directly before the operator is a file descriptor
+                    let fd = if in_word && !word.text.is_empty() && word.text.chars().all(|c| c.is_ascii_digit()) && word.substitutions.is_empty() {
+                        in_word = false;
+                        std::mem::take(&mut word).text
+                    } else {
+                        finish_word!();
+                        String::new()
+                    };
+                    let op_start = if fd.is_empty() { self.pos } else { start };
+                    let op = self.redirect_op();
+                    if op.starts_with("<<") && op != "<<<" {
+                        heredoc_next = Some(op == "<<-");
+                    }
+                    tokens.push((Token::Op(format!("{}{}", fd, op)), (op_start, self.pos)));
+                }
+                '&' if self.peek(1) == Some('>') => {
+                    finish_word!();
+                    let op_start = self.pos;
+                    self.pos += 2;
+                    let op = if self.peek(0) == Some('>') {
+                        self.pos += 1;
+                        "&>>"
+                    } else {
+                        "&>"
+                    };
+                    tokens.push((Token::Op(op.to_string()), (op_start, self.pos)));
+                }
+                '|' | '&' | ';' | '(' | ')' => {
+                    finish_word!();
+                    let op_start = self.pos;
+                    let next = self.peek(1);
+                    let op = match (c, next) {
+                        ('|', Some('|')) | ('&', Some('&')) | (';', Some(';')) | ('|', Some('&')) => {
+                            self.pos += 2;
+                            format!("{}{}", c, next.unwrap())
+                        }
+                        _ => {
+                            self.pos += 1;
+                            c.to_string()
+                        }
+                    };
+                    tokens.push((Token::Op(op), (op_start, self.pos)));
+                }
+                _ => {
+                    in_word = true;
+                    word.text.push(c);
+                    self.pos += 1;
+                }
+            }
+        }
+        if in_word {
+            tokens.push((Token::Word(word), (start, self.pos)));
+        }
+        if !self.heredocs.is_empty() || heredoc_next.is_some() {
+            self.incomplete = true;
+        }
+        tokens
+    }
+
+    fn redirect_op(&mut self) -> String {
+        let mut op = String::new();
+        let first = self.chars[self.pos];
+        op.push(first);
+        self.pos += 1;
+        match (first, self.peek(0)) {
+            ('>', Some('>')) | ('>', Some('|')) | ('>', Some('&')) | ('<', Some('&')) | ('<', Some('>')) => {
+                op.push(self.chars[self.pos]);
+                self.pos += 1;
+            }
+            ('<', Some('<')) => {
+                op.push('<');
+                self.pos += 1;
+                if let Some(next @ ('<' | '-')) = self.peek(0) {
+                    op.push(next);
+                    self.pos += 1;
+                }
+            }
+            _ => {}
+        }
+        op
+    }
+
+    fn double_quoted(&mut self, word: &mut Word) {
+        while let Some(c) = self.peek(0) {
+            match c {
+                '"' => {
+                    self.pos += 1;
+                    return;
+                }
+                '\\' => {
+                    match self.peek(1) {
+                        Some(escaped @ ('"' | '\\' | '$' | '`')) => word.text.push(escaped),
+                        Some('\n') => {}
+                        Some(other) => {
+                            word.text.push('\\');
+                            word.text.push(other);
+                        }
+                        None => {}
+                    }
+                    self.pos += 2;
+                }
+                '`' => {
+                    self.pos += 1;
+                    let body = self.take_until('`');
+                    self.push_substitution(word, "`", body, "`");
+                }
+                '$' if self.peek(1) == Some('(') => {
+                    self.pos += 2;
+                    let body = self.take_balanced();
+                    self.push_substitution(word, "$(", body, ")");
+                }
+                _ => {
+                    word.text.push(c);
+                    self.pos += 1;
+                }
+            }
+        }
+        self.incomplete = true;
+    }
+
+    fn push_substitution(&mut self, word: &mut Word, open: &str, body: Option<String>, close: &str) {
+        match body {
+            Some(body) => {
+                word.text.push_str(&format!("{}{}{}", open, body, close));
+                word.substitutions.push(body);
+            }
+            None => self.incomplete = true,
+        }
+    }
+
+    /// Consumes up to and including `end`, returning what came before it.
+    fn take_until(&mut self, end: char) -> Option<String> {
+        let start = self.pos;
+        while let Some(c) = self.peek(0) {
+            self.pos += 1;
+            if c == end {
+                return Some(self.chars[start..self.pos - 1].iter().collect());
+            }
+        }
+        None
+    }
+
+    /// Consumes up to the `)` closing an already consumed `(`, skipping over quotes.
+    fn take_balanced(&mut self) -> Option<String> {
+        let start = self.pos;
+        let mut depth = 1;
+        while let Some(c) = self.peek(0) {
+            self.pos += 1;
+            match c {
+                '\\' => self.pos += 1,
+                '\'' => {
+                    self.take_until('\'')?;
+                }
+                '"' => loop {
+                    match self.peek(0) {
+                        Some('\\') => self.pos += 2,
+                        Some('"') => {
+                            self.pos += 1;
+                            break;
+                        }
+                        Some(_) => self.pos += 1,
+                        None => return None,
+                    }
+                },
+                '(' => depth += 1,
+                ')' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Some(self.chars[start..self.pos - 1].iter().collect());
+                    }
+                }
+                _ => {}
+            }
+        }
+        None
+    }
+
+    /// Skips the bodies of here-documents opened on the line that just ended.
+    fn skip_heredocs(&mut self) {
+        let heredocs = std::mem::take(&mut self.heredocs);
+        for (delimiter, strip_tabs) in heredocs {
+            loop {
+                if self.pos >= self.chars.len() {
+                    self.incomplete = true;
+                    return;
+                }
+                let start = self.pos;
+                while self.peek(0).is_some_and(|c| c != '\n') {
+                    self.pos += 1;
+                }
+                let line: String = self.chars[start..self.pos].iter().collect();
+                self.pos += 1;
+                let line = if strip_tabs { line.trim_start_matches('\t') } else { line.as_str() };
+                if line == delimiter {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn argvs(command: &str) -> Vec<Vec<String>> {
+        parse(command).commands().map(|c| c.argv().iter().map(|a| a.to_string()).collect()).collect()
+    }
+
+    fn redirects(command: &str) -> Vec<(String, String)> {
+        parse(command).commands().flat_map(|c| c.redirects.iter().map(|r| (r.op.clone(), r.target.clone()))).collect()
+    }
+
+    #[test]
+    fn quoting() {
+        assert_eq!(argvs(r#"echo 'a b' "c $HOME" d\ e "q\"uote" 'it'\''s'"#), vec![vec!["echo", "a b", "c $HOME", "d e", "q\"uote", "it's"]]);
+        assert_eq!(argvs(r#"grep "a\.b" 'x\y'"#), vec![vec!["grep", "a\\.b", "x\\y"]]);
+        // operators inside quotes are text
+        assert_eq!(argvs("echo 'a | b; c' \"&&\""), vec![vec!["echo", "a | b; c", "&&"]]);
+        assert!(!parse("echo 'done'").incomplete);
+    }
+
+    #[test]
+    fn lists_and_pipelines() {
+        let script = parse("cd /tmp && ls -la | grep x || echo none; sleep 1 & wait");
+        let pipelines: Vec<usize> = script.pipelines.iter().map(|p| p.commands.len()).collect();
+        assert_eq!(pipelines, vec![1, 2, 1, 1, 1]);
+        assert_eq!(argvs("if true; then echo yes; fi"), vec![vec!["true"], vec!["echo", "yes"]]);
+        assert_eq!(argvs("(cd x && make) | tee log"), vec![vec!["cd", "x"], vec!["make"], vec!["tee", "log"]]);
+        assert_eq!(argvs("# only a comment"), Vec::<Vec<String>>::new());
+    }
+
+    #[test]
+    fn heredocs() {
+        let script = parse("cat <<EOF > out.txt\nrm -rf /\n$(reboot)\nEOF\necho done");
+        assert!(!script.incomplete);
+        assert_eq!(argvs("cat <<EOF > out.txt\nrm -rf /\nEOF\necho done"), vec![vec!["cat"], vec!["echo", "done"]]);
+        assert_eq!(redirects("cat <<'EOF' > out.txt\nx\nEOF"), vec![("<<".to_string(), "EOF".to_string()), (">".to_string(), "out.txt".to_string())]);
+        // <<- strips leading tabs from the delimiter line
+        assert!(!parse("cat <<-END\n\tbody\n\tEND\n").incomplete);
+        assert_eq!(redirects("tr a b <<< 'abc'"), vec![("<<<".to_string(), "abc".to_string())]);
+    }
+
+    #[test]
+    fn substitutions() {
+        let script = parse("echo \"today: $(date +%F)\" `whoami` $(ls $(pwd))");
+        let word = &script.pipelines[0].commands[0].words[1];
+        assert_eq!(word.text, "today: $(date +%F)");
+        assert_eq!(word.substitutions, vec!["date +%F"]);
+        let binaries: Vec<String> = script.commands().map(|c| c.argv()[0].to_string()).collect();
+        assert_eq!(binaries, vec!["echo", "date", "whoami", "ls", "pwd"]);
+        assert_eq!(argvs("echo $(echo ')' \")\")"), vec![vec!["echo", "$(echo ')' \")\")"], vec!["echo", ")", ")"]]);
+    }
+
+    #[test]
+    fn process_substitution() {
+        let script = parse("diff <(sort a) <(sort b) > >(tee log)");
+        let binaries: Vec<String> = script.commands().map(|c| c.argv()[0].to_string()).collect();
+        assert_eq!(binaries, vec!["diff", "sort", "sort", "tee"]);
+        assert_eq!(script.pipelines[0].commands[0].argv(), vec!["diff", "<(sort a)", "<(sort b)"]);
+        assert_eq!(redirects("diff <(sort a) <(sort b) > >(tee log)")[0], (">".to_string(), ">(tee log)".to_string()));
+    }
+
+    #[test]
+    fn descriptor_redirects() {
+        assert_eq!(
+            redirects("make 2>&1 >build.log 2>>errors &> all.log &>> more.log <input 3<&0"),
+            vec![
+                ("2>&".to_string(), "1".to_string()),
+                (">".to_string(), "build.log".to_string()),
+                ("2>>".to_string(), "errors".to_string()),
+                ("&>".to_string(), "all.log".to_string()),
+                ("&>>".to_string(), "more.log".to_string()),
+                ("<".to_string(), "input".to_string()),
+                ("3<&".to_string(), "0".to_string()),
+            ]
+        );
+        assert_eq!(argvs("make 2>&1 | tee log"), vec![vec!["make"], vec!["tee", "log"]]);
+        // a number that is not right before the operator is an argument
+        assert_eq!(argvs("echo 2 > out"), vec![vec!["echo", "2"]]);
+        let overwrites: Vec<bool> = parse("a > x >> y 2> z &> w >| v").commands().flat_map(|c| c.redirects.iter().map(|r| r.overwrites())).collect();
+        assert_eq!(overwrites, vec![true, false, true, true, true]);
+    }
+
+    #[test]
+    fn incomplete_commands() {
+        assert!(parse("echo 'unterminated").incomplete);
+        assert!(parse("echo \"unterminated").incomplete);
+        assert!(parse("echo $(date").incomplete);
+        assert!(parse("echo `date").incomplete);
+        assert!(parse("diff <(sort a").incomplete);
+        assert!(parse("cat <<EOF\nno end").incomplete);
+        assert!(parse("cat <<EOF").incomplete);
+        assert!(parse("bash -c 'echo \"open'").incomplete);
+        assert!(!parse("echo \"$(date)\" 'x' `id`").incomplete);
+    }
+
+    #[test]
+    fn wrappers_and_assignments() {
+        let script = parse("LANG=C sudo -u www nice -n 5 env FOO=1 make install");
+        let command = script.commands().next().unwrap();
+        assert_eq!(command.effective_argv(), vec!["make", "install"]);
+        assert_eq!(command.wrappers(), vec!["sudo", "nice", "env"]);
+        assert!(command.is_privileged());
+    }
+
+    #[test]
+    fn nested_scripts() {
+        assert_eq!(argvs("bash -lc 'cd /tmp && ls'"), vec![vec!["bash", "-lc", "cd /tmp && ls"], vec!["cd", "/tmp"], vec!["ls"]]);
+        assert_eq!(argvs("eval rm x"), vec![vec!["eval", "rm", "x"], vec!["rm", "x"]]);
+        assert_eq!(argvs(r"find . -exec rm {} \; -print"), vec![vec!["find", ".", "-exec", "rm", "{}", ";", "-print"], vec!["rm", "{}"]]);
+        assert_eq!(parse("bash script.sh").commands().count(), 1);
+    }
+}
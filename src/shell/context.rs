@@ -0,0 +1,262 @@
+use crate::shell::exec::Shell;
+use serde::{Deserialize, Serialize};
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::{env, fs};
+
+const CACHE_DIR_NAME: &str = "shellm";
+const CACHE_FILE_NAME: &str = "context.json";
+const CACHE_MAX_AGE_SECS: u64 = 24 * 60 * 60;
+
+/// Package managers with the distributions (`ID` / `ID_LIKE` in os-release) that ship them.
+const PACKAGE_MANAGERS: [(&str, &[&str]); 10] = [
+    ("apt", &["debian", "ubuntu"]),
+    ("dnf", &["fedora", "rhel", "centos"]),
+    ("yum", &["rhel", "centos", "amzn"]),
+    ("pacman", &["arch"]),
+    ("zypper", &["suse", "opensuse"]),
+    ("apk", &["alpine"]),
+    ("xbps-install", &["void"]),
+    ("emerge", &["gentoo"]),
+    ("nix-env", &["nixos"]),
+    ("brew", &[]),
+];
+
+/// Tools worth knowing about because the model would otherwise avoid or assume them.
+const KNOWN_TOOLS: [&str; 32] = [
+    "rg", "fd", "fdfind", "fzf", "bat", "jq", "yq", "git", "curl", "wget", "rsync", "docker", "podman",
+    "kubectl", "systemctl", "journalctl", "python3", "pip3", "node", "npm", "cargo", "go", "make", "gcc",
+    "ffmpeg", "convert", "tmux", "unzip", "7z", "sqlite3", "psql", "ip",
+];
+
+const SUDO_GROUPS: [&str; 3] = ["sudo", "wheel", "admin"];
+
+/// What the model should know about the machine before proposing commands.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SystemContext {
+    pub os: String,
+    pub package_manager: Option<String>,
+    pub shell: String,
+    pub root: bool,
+    pub sudo: bool, // the sudo binary exists and the user is in an admin group
+    pub tools: Vec<String>,
+
+    collected_at: u64,
+    path: String, // PATH at collection time, a different one invalidates the cache
+}
+
+impl SystemContext {
+    /// The cached context when it is recent and was collected with the same PATH, otherwise a
+    /// fresh one, which is then cached.
+    pub fn load(shell: &Shell) -> Self {
+        let path = env::var("PATH").unwrap_or_default();
+        let now = now_secs();
+        let cached = Self::cache_path()
+            .and_then(|file| fs::read_to_string(file).ok())
+            .and_then(|content| serde_json::from_str::<SystemContext>(&content).ok())
+            .filter(|c| c.path == path && now.saturating_sub(c.collected_at) < CACHE_MAX_AGE_SECS);
+
+        let mut context = match cached {
+            Some(context) => context,
+            None => {
+                let context = Self::collect(path, now);
+                context.save();
+                context
+            }
+        };
+        // the shell can change with every run, so it is never taken from the cache
+        context.shell = shell.name().to_string();
+        context
+    }
+
+    fn collect(path: String, now: u64) -> Self {
+        let release = read_os_release();
+        let os = os_name(&release);
+        let package_manager = package_manager(&release, |manager| find_in_path(manager).is_some());
+
+        let root = proc_status_field("Uid").and_then(|uid| uid.split_whitespace().next().map(|u| u == "0")).unwrap_or(false);
+        let sudo = find_in_path("sudo").is_some() && user_groups().iter().any(|g| SUDO_GROUPS.contains(&g.as_str()));
+        let tools = KNOWN_TOOLS.iter().filter(|tool| find_in_path(tool).is_some()).map(|t| t.to_string()).collect();
+
+        SystemContext { os, package_manager, shell: String::new(), root, sudo, tools, collected_at: now, path }
+    }
+
+    fn cache_path() -> Option<PathBuf> {
+        let base = match env::var_os("XDG_CACHE_HOME") {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => PathBuf::from(env::var_os("HOME")?).join(".cache"),
+        };
+        Some(base.join(CACHE_DIR_NAME).join(CACHE_FILE_NAME))
+    }
+
+    // a cache that cannot be written only costs the next run a few file lookups
+    fn save(&self) {
+        let Some(path) = Self::cache_path() else { return };
+        if let Some(dir) = path.parent() {
+            let _ = fs::create_dir_all(dir);
+        }
+        if let Ok(content) = serde_json::to_string(self) {
+            let _ = fs::write(path, content);
+        }
+    }
+
+    /// One line for the system prompt.
+    pub fn render(&self) -> String {
+        let privileges = if self.root {
+            "running as root, sudo is not needed"
+        } else if self.sudo {
+            "sudo available"
+        } else {
+            "no sudo access"
+        };
+        let tools = if self.tools.is_empty() { "none of the common extras".to_string() } else { self.tools.join(", ") };
+        format!(
+            "SYSTEM: {}; package manager: {}; shell: {}; {}; tools on PATH: {}.",
+            self.os,
+            self.package_manager.as_deref().unwrap_or("unknown"),
+            self.shell,
+            privileges,
+            tools
+        )
+    }
+}
+
+/// The full path of `binary` when an executable with that name is on PATH.
+pub fn find_in_path(binary: &str) -> Option<PathBuf> {
+    if binary.contains('/') {
+        return is_executable(&PathBuf::from(binary)).then(|| PathBuf::from(binary));
+    }
+    let path = env::var_os("PATH")?;
+    env::split_paths(&path).map(|dir| dir.join(binary)).find(is_executable)
+}
+
+fn is_executable(path: &PathBuf) -> bool {
+    fs::metadata(path).map(|m| m.is_file() && m.permissions().mode() & 0o111 != 0).unwrap_or(false)
+}
+
+fn read_os_release() -> Vec<(String, String)> {
+    let content = fs::read_to_string("/etc/os-release")
+        .or_else(|_| fs::read_to_string("/usr/lib/os-release"))
+        .unwrap_or_default();
+    parse_os_release(&content)
+}
+
+/// The `KEY=value` assignments of an os-release file, with the quotes around values removed.
+fn parse_os_release(content: &str) -> Vec<(String, String)> {
+    content
+        .lines()
+        .filter(|line| !line.trim_start().starts_with('#'))
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| {
+            let value = value.trim();
+            let unquoted = ['"', '\''].iter().find_map(|q| value.strip_prefix(*q)?.strip_suffix(*q)).unwrap_or(value);
+            (key.trim().to_string(), unquoted.to_string())
+        })
+        .collect()
+}
+
+fn release_field(release: &[(String, String)], key: &str) -> Option<String> {
+    release.iter().find(|(k, _)| k == key).map(|(_, v)| v.clone()).filter(|v| !v.is_empty())
+}
+
+fn os_name(release: &[(String, String)]) -> String {
+    let field = |key: &str| release_field(release, key);
+    field("PRETTY_NAME")
+        .or_else(|| field("NAME").map(|name| format!("{} {}", name, field("VERSION_ID").unwrap_or_default())))
+        .map(|os| os.trim().to_string())
+        .unwrap_or_else(|| "unknown linux".to_string())
+}
+
+/// The installed package manager of the distribution, or of one it is like (`ID_LIKE`), else
+/// the first installed one.
+fn package_manager(release: &[(String, String)], installed: impl Fn(&str) -> bool) -> Option<String> {
+    let mut distros: Vec<String> = release_field(release, "ID").into_iter().collect();
+    distros.extend(release_field(release, "ID_LIKE").unwrap_or_default().split_whitespace().map(|s| s.to_string()));
+    let available: Vec<&(&str, &[&str])> = PACKAGE_MANAGERS.iter().filter(|(name, _)| installed(name)).collect();
+    distros
+        .iter()
+        .find_map(|distro| available.iter().find(|(_, ids)| ids.contains(&distro.as_str())))
+        .or_else(|| available.first())
+        .map(|(name, _)| name.to_string())
+}
+
+fn proc_status_field(name: &str) -> Option<String> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    status.lines().find_map(|line| line.strip_prefix(name)?.strip_prefix(':').map(|v| v.trim().to_string()))
+}
+
+/// Names of the groups this process belongs to.
+fn user_groups() -> Vec<String> {
+    let gids: Vec<String> = proc_status_field("Groups").unwrap_or_default().split_whitespace().map(|g| g.to_string()).collect();
+    let groups = fs::read_to_string("/etc/group").unwrap_or_default();
+    groups
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split(':');
+            let name = fields.next()?;
+            let gid = fields.nth(1)?;
+            gids.iter().any(|g| g == gid).then(|| name.to_string())
+        })
+        .collect()
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UBUNTU: &str = "PRETTY_NAME=\"Ubuntu 22.04.4 LTS\"\nNAME=\"Ubuntu\"\nVERSION_ID=\"22.04\"\nID=ubuntu\nID_LIKE=debian\n";
+
+    fn release(content: &str) -> Vec<(String, String)> {
+        parse_os_release(content)
+    }
+
+    #[test]
+    fn quoted_values() {
+        let fields = release("# comment\nNAME='Rocky Linux'\nVERSION=\"9.3 (Blue Onyx)\"\nID=rocky\nHOME_URL=\"https://rockylinux.org/\"\nEMPTY=\n");
+        assert_eq!(release_field(&fields, "NAME").as_deref(), Some("Rocky Linux"));
+        assert_eq!(release_field(&fields, "VERSION").as_deref(), Some("9.3 (Blue Onyx)"));
+        assert_eq!(release_field(&fields, "HOME_URL").as_deref(), Some("https://rockylinux.org/"));
+        assert_eq!(release_field(&fields, "EMPTY"), None);
+        assert_eq!(fields.len(), 5);
+
+        assert_eq!(os_name(&release(UBUNTU)), "Ubuntu 22.04.4 LTS");
+        assert_eq!(os_name(&release("NAME=\"Alpine Linux\"\nVERSION_ID=3.19.1")), "Alpine Linux 3.19.1");
+        assert_eq!(os_name(&release("NAME=Gentoo")), "Gentoo");
+    }
+
+    #[test]
+    fn package_manager_by_distribution() {
+        let all = |_: &str| true;
+        assert_eq!(package_manager(&release(UBUNTU), all).as_deref(), Some("apt"));
+        assert_eq!(package_manager(&release("ID=fedora"), all).as_deref(), Some("dnf"));
+        // yum is listed for rhel as well, but dnf comes first
+        assert_eq!(package_manager(&release("ID=rhel"), |m| m != "apt").as_deref(), Some("dnf"));
+        assert_eq!(package_manager(&release("ID=rhel"), |m| m == "yum" || m == "brew").as_deref(), Some("yum"));
+    }
+
+    #[test]
+    fn id_like_fallback() {
+        let mint = release("ID=linuxmint\nID_LIKE=\"ubuntu debian\"");
+        assert_eq!(package_manager(&mint, |_| true).as_deref(), Some("apt"));
+        let rocky = release("ID=\"rocky\"\nID_LIKE=\"rhel centos fedora\"");
+        assert_eq!(package_manager(&rocky, |m| m == "yum" || m == "apk").as_deref(), Some("yum"));
+        // the distribution's own manager wins over one of a distribution it is like
+        let manjaro = release("ID=manjaro\nID_LIKE=arch");
+        assert_eq!(package_manager(&manjaro, |m| m == "pacman" || m == "brew").as_deref(), Some("pacman"));
+    }
+
+    #[test]
+    fn unknown_distribution() {
+        let unknown = release("ID=plan10\nNAME=Plan10");
+        assert_eq!(package_manager(&unknown, |m| m == "brew" || m == "apk").as_deref(), Some("apk"));
+        assert_eq!(package_manager(&unknown, |_| false), None);
+        assert_eq!(package_manager(&release(""), |m| m == "nix-env").as_deref(), Some("nix-env"));
+        assert_eq!(os_name(&release("")), "unknown linux");
+        assert_eq!(os_name(&unknown), "Plan10");
+    }
+}
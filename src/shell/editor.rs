@@ -0,0 +1,256 @@
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use crossterm::terminal::{self, ClearType};
+use crossterm::{cursor, queue};
+use std::fs::{DirBuilder, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, IsTerminal, Write};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::PathBuf;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::{env, fs};
+
+const PROMPT: &str = "> ";
+const TEMP_DIR_ATTEMPTS: u32 = 16;
+
+/// Lets the user change `command` before it runs. Single-line commands are edited in place,
+/// multi-line ones (or any after Ctrl-X Ctrl-E) in `$VISUAL`/`$EDITOR`. Returns none when the
+/// edit was cancelled or left the command empty. With `pass_stdin` the piped input is meant for
+/// the command, so a replacement line is read from the terminal instead.
+pub fn edit_command(command: &str, pass_stdin: bool) -> Option<String> {
+    if !io::stdin().is_terminal() || !io::stdout().is_terminal() {
+        return read_plain(command, pass_stdin);
+    }
+    if command.contains('\n') {
+        return edit_external(command);
+    }
+
+    let edited = match edit_inline(command) {
+        Ok(edited) => edited,
+        Err(_e) => return read_plain(command, pass_stdin),
+    };
+    match edited {
+        Edit::Done(line) => non_empty(line),
+        Edit::Cancelled => None,
+        Edit::External(line) => edit_external(&line),
+    }
+}
+
+enum Edit {
+    Done(String),
+    Cancelled,
+    External(String),
+}
+
+/// Restores the terminal even when editing bails out early.
+struct RawMode;
+
+impl RawMode {
+    fn enable() -> io::Result<Self> {
+        terminal::enable_raw_mode()?;
+        Ok(RawMode)
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+fn edit_inline(command: &str) -> io::Result<Edit> {
+    let _raw = RawMode::enable()?;
+    let mut stdout = io::stdout();
+    let mut line: Vec<char> = command.chars().collect();
+    let mut pos = line.len();
+    let mut ctrl_x = false;
+
+    loop {
+        render(&mut stdout, &line, pos)?;
+
+        let Event::Key(KeyEvent { code, modifiers, kind, .. }) = event::read()? else { continue };
+        if kind == KeyEventKind::Release {
+            continue;
+        }
+        let ctrl = modifiers.contains(KeyModifiers::CONTROL);
+        let alt = modifiers.contains(KeyModifiers::ALT);
+
+        if std::mem::take(&mut ctrl_x) && ctrl && code == KeyCode::Char('e') {
+            finish(&mut stdout)?;
+            return Ok(Edit::External(line.iter().collect()));
+        }
+
+        match code {
+            KeyCode::Enter => {
+                finish(&mut stdout)?;
+                return Ok(Edit::Done(line.iter().collect()));
+            }
+            KeyCode::Esc => {
+                finish(&mut stdout)?;
+                return Ok(Edit::Cancelled);
+            }
+            KeyCode::Char('c') if ctrl => {
+                finish(&mut stdout)?;
+                return Ok(Edit::Cancelled);
+            }
+            KeyCode::Char('d') if ctrl && line.is_empty() => {
+                finish(&mut stdout)?;
+                return Ok(Edit::Cancelled);
+            }
+            KeyCode::Char('x') if ctrl => ctrl_x = true,
+            KeyCode::Char('a') if ctrl => pos = 0,
+            KeyCode::Char('e') if ctrl => pos = line.len(),
+            KeyCode::Char('b') if ctrl => pos = pos.saturating_sub(1),
+            KeyCode::Char('f') if ctrl => pos = (pos + 1).min(line.len()),
+            KeyCode::Char('b') if alt => pos = word_start(&line, pos),
+            KeyCode::Char('f') if alt => pos = word_end(&line, pos),
+            KeyCode::Char('u') if ctrl => {
+                line.drain(..pos);
+                pos = 0;
+            }
+            KeyCode::Char('k') if ctrl => line.truncate(pos),
+            KeyCode::Char('w') if ctrl => {
+                let start = word_start(&line, pos);
+                line.drain(start..pos);
+                pos = start;
+            }
+            KeyCode::Char('d') if ctrl && pos < line.len() => {
+                line.remove(pos);
+            }
+            KeyCode::Char(c) if !ctrl => {
+                line.insert(pos, c);
+                pos += 1;
+            }
+            KeyCode::Backspace if pos > 0 => {
+                pos -= 1;
+                line.remove(pos);
+            }
+            KeyCode::Delete if pos < line.len() => {
+                line.remove(pos);
+            }
+            KeyCode::Left if ctrl || alt => pos = word_start(&line, pos),
+            KeyCode::Right if ctrl || alt => pos = word_end(&line, pos),
+            KeyCode::Left => pos = pos.saturating_sub(1),
+            KeyCode::Right => pos = (pos + 1).min(line.len()),
+            KeyCode::Home => pos = 0,
+            KeyCode::End => pos = line.len(),
+            _ => {}
+        }
+    }
+}
+
+/// Draws the prompt and the part of the line around the cursor that fits the terminal.
+fn render(stdout: &mut io::Stdout, line: &[char], pos: usize) -> io::Result<()> {
+    let width = terminal::size().map(|(w, _)| w as usize).unwrap_or(80);
+    let room = width.saturating_sub(PROMPT.len() + 1).max(1);
+    let start = if pos < room { 0 } else { pos + 1 - room };
+    let end = (start + room).min(line.len());
+    let visible: String = line[start..end].iter().collect();
+
+    queue!(stdout, cursor::MoveToColumn(0), terminal::Clear(ClearType::CurrentLine))?;
+    write!(stdout, "{}{}", PROMPT, visible)?;
+    queue!(stdout, cursor::MoveToColumn((PROMPT.len() + pos - start) as u16))?;
+    stdout.flush()
+}
+
+fn finish(stdout: &mut io::Stdout) -> io::Result<()> {
+    write!(stdout, "\r\n")?;
+    stdout.flush()
+}
+
+fn word_start(line: &[char], pos: usize) -> usize {
+    let mut i = pos;
+    while i > 0 && line[i - 1].is_whitespace() {
+        i -= 1;
+    }
+    while i > 0 && !line[i - 1].is_whitespace() {
+        i -= 1;
+    }
+    i
+}
+
+fn word_end(line: &[char], pos: usize) -> usize {
+    let mut i = pos;
+    while i < line.len() && line[i].is_whitespace() {
+        i += 1;
+    }
+    while i < line.len() && !line[i].is_whitespace() {
+        i += 1;
+    }
+    i
+}
+
+/// Opens `command` in `$VISUAL`, `$EDITOR` or vi and reads it back once the editor exits.
+fn edit_external(command: &str) -> Option<String> {
+    let editor = env::var("VISUAL")
+        .or_else(|_| env::var("EDITOR"))
+        .ok()
+        .filter(|e| !e.trim().is_empty())
+        .unwrap_or_else(|| "vi".to_string());
+    let Some(dir) = private_dir() else {
+        eprintln!("Cannot create a private directory in {}", env::temp_dir().display());
+        return None;
+    };
+    let path = dir.join("command.sh");
+    let written = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&path)
+        .and_then(|mut file| file.write_all(format!("{}\n", command).as_bytes()));
+    if written.is_err() {
+        eprintln!("Cannot create {}", path.display());
+        let _ = fs::remove_dir_all(&dir);
+        return None;
+    }
+
+    // the editor variable may carry its own arguments, e.g. "code --wait"
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(format!("{} \"$1\"", editor))
+        .arg("sh")
+        .arg(&path)
+        .status();
+    let edited = fs::read_to_string(&path);
+    let _ = fs::remove_dir_all(&dir);
+
+    match (status, edited) {
+        (Ok(status), Ok(edited)) if status.success() => non_empty(edited.trim_end().to_string()),
+        _ => {
+            eprintln!("Editor '{}' did not exit cleanly, keeping the command unchanged", editor);
+            None
+        }
+    }
+}
+
+/// A new directory in the temp dir that only the user can enter, so nobody else can swap or
+/// change the file in it while the editor is open.
+fn private_dir() -> Option<PathBuf> {
+    for attempt in 0..TEMP_DIR_ATTEMPTS {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.subsec_nanos()).unwrap_or(0);
+        let dir = env::temp_dir().join(format!("shellm-{}-{:x}{}", std::process::id(), nanos, attempt));
+        // fails instead of reusing a directory or symlink that is already there
+        if DirBuilder::new().mode(0o700).create(&dir).is_ok() {
+            return Some(dir);
+        }
+    }
+    None
+}
+
+/// Fallback when there is no terminal to edit in: read a replacement line, from the terminal
+/// rather than stdin under `pass_stdin`, like the confirmation prompt.
+fn read_plain(command: &str, pass_stdin: bool) -> Option<String> {
+    println!("{}", command);
+    print!("New command (empty keeps the current one): ");
+    io::stdout().flush().unwrap();
+    let mut buffer = String::new();
+    let tty = if pass_stdin && !io::stdin().is_terminal() { File::open("/dev/tty").ok() } else { None };
+    match tty {
+        Some(tty) => BufReader::new(tty).read_line(&mut buffer).ok()?,
+        None => io::stdin().read_line(&mut buffer).ok()?,
+    };
+    non_empty(buffer.trim().to_string())
+}
+
+fn non_empty(command: String) -> Option<String> {
+    if command.trim().is_empty() { None } else { Some(command) }
+}
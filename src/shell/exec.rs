@@ -0,0 +1,334 @@
+use crate::shell::command_parser::base_name;
+use crate::utils::color::colorify;
+use std::io::{self, IsTerminal, Read, Write};
+use std::os::unix::process::ExitStatusExt;
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+use std::{env, fs, thread};
+
+// only the tail of long outputs is kept for the model
+const CAPTURE_LIMIT: usize = 4000;
+// how long a timed out command gets to exit after SIGTERM before it is killed
+const KILL_GRACE: Duration = Duration::from_secs(2);
+
+const POSIX_SHELLS: [&str; 9] = ["sh", "bash", "zsh", "dash", "ksh", "mksh", "ash", "yash", "busybox"];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ShellKind {
+    Posix,
+    Fish,
+}
+
+/// The shell generated commands are run with.
+#[derive(Debug, Clone)]
+pub struct Shell {
+    pub path: String,
+    pub kind: ShellKind,
+}
+
+impl Shell {
+    /// `configured` when set, otherwise `$SHELL`, as long as it is a shell shellm knows,
+    /// otherwise sh.
+    pub fn resolve(configured: Option<&str>) -> Self {
+        Self::resolve_with(configured, env::var("SHELL").ok())
+    }
+
+    fn resolve_with(configured: Option<&str>, login_shell: Option<String>) -> Self {
+        if let Some(path) = configured.filter(|p| !p.trim().is_empty()) {
+            if is_known(path) {
+                return Shell::from_path(path);
+            }
+            let warning = format!("{} is not a shell shellm knows how to write commands for, using sh", path);
+            eprintln!("{}", colorify(&warning, 247., 200., 89.));
+            return Shell::from_path("sh");
+        }
+        match login_shell {
+            Some(path) if is_known(&path) => Shell::from_path(&path),
+            _ => Shell::from_path("sh"),
+        }
+    }
+
+    fn from_path(path: &str) -> Self {
+        let kind = if base_name(path) == "fish" { ShellKind::Fish } else { ShellKind::Posix };
+        Shell { path: path.to_string(), kind }
+    }
+
+    pub fn name(&self) -> &str {
+        base_name(&self.path)
+    }
+
+    /// Quotes `arg` as a single word for this shell.
+    pub fn quote(&self, arg: &str) -> String {
+        match self.kind {
+            ShellKind::Posix => format!("'{}'", arg.replace('\'', "'\\''")),
+            // fish also treats backslashes inside single quotes as escapes
+            ShellKind::Fish => format!("'{}'", arg.replace('\\', "\\\\").replace('\'', "\\'")),
+        }
+    }
+}
+
+fn is_known(path: &str) -> bool {
+    base_name(path) == "fish" || POSIX_SHELLS.contains(&base_name(path))
+}
+
+/// How commands are run.
+#[derive(Debug, Clone)]
+pub struct ExecOptions {
+    pub shell: Shell,
+    pub timeout: Option<Duration>,
+    /// Hand shellm's stdin to the command even when it is not a terminal.
+    pub pass_stdin: bool,
+}
+
+/// How a command ended. `stdout` and `stderr` are only filled when the output was captured.
+#[derive(Debug, Clone)]
+pub struct ExecOutcome {
+    pub exit_status: Option<i32>, // none when killed by a signal
+    pub signal: Option<i32>,
+    pub timed_out: bool,
+    pub duration: Duration,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl ExecOutcome {
+    pub fn success(&self) -> bool {
+        self.exit_status == Some(0)
+    }
+
+    /// The status a shell would report: 124 after a timeout, like timeout(1), and 128 + N when
+    /// killed by signal N.
+    pub fn exit_code(&self) -> i32 {
+        match (self.timed_out, self.exit_status, self.signal) {
+            (true, _, _) => 124,
+            (false, Some(code), _) => code,
+            (false, None, Some(signal)) => 128 + signal,
+            (false, None, None) => 1,
+        }
+    }
+
+    /// What ended the command, e.g. "exit code 2" or "a timeout after 30s".
+    pub fn describe(&self) -> String {
+        match (self.timed_out, self.exit_status, self.signal) {
+            (true, _, _) => format!("a timeout after {}s", self.duration.as_secs()),
+            (false, Some(code), _) => format!("exit code {}", code),
+            (false, None, Some(signal)) => format!("signal {}", signal),
+            (false, None, None) => "an unknown status".to_string(),
+        }
+    }
+}
+
+/// Runs `command` with `<shell> -c`. With `capture`, stdout and stderr are still shown on the
+/// terminal but also kept, which makes programs see a pipe instead of a terminal. Piped input
+/// only reaches the command with `pass_stdin`, so it cannot swallow shellm's own prompts.
+pub fn run(command: &str, options: &ExecOptions, capture: bool) -> io::Result<ExecOutcome> {
+    let start = Instant::now();
+    let output = if capture { Stdio::piped } else { Stdio::inherit };
+    let input = if options.pass_stdin || io::stdin().is_terminal() { Stdio::inherit() } else { Stdio::null() };
+    let mut child = Command::new(&options.shell.path)
+        .arg("-c")
+        .arg(command)
+        .stdin(input)
+        .stdout(output())
+        .stderr(output())
+        .spawn()?;
+
+    let stdout = child.stdout.take().map(|out| thread::spawn(move || tee(out, io::stdout())));
+    let stderr = child.stderr.take().map(|err| thread::spawn(move || tee(err, io::stderr())));
+    let (status, timed_out) = match options.timeout {
+        Some(timeout) => wait_timeout(&mut child, timeout)?,
+        None => (child.wait()?, false),
+    };
+
+    Ok(ExecOutcome {
+        exit_status: status.code(),
+        signal: status.signal(),
+        timed_out,
+        duration: start.elapsed(),
+        stdout: stdout.and_then(|h| h.join().ok()).unwrap_or_default(),
+        stderr: stderr.and_then(|h| h.join().ok()).unwrap_or_default(),
+    })
+}
+
+/// Runs a helper program like `man` without a terminal: stdout, stderr and whether it exited
+/// with 0. None when it could not start or timed out.
+pub fn output_within(command: &mut Command, timeout: Duration) -> Option<(String, String, bool)> {
+    let mut child = command.stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::piped()).spawn().ok()?;
+    let mut stdout = child.stdout.take()?;
+    let mut stderr = child.stderr.take()?;
+    let stdout = thread::spawn(move || {
+        let mut buffer = vec![];
+        let _ = stdout.read_to_end(&mut buffer);
+        String::from_utf8_lossy(&buffer).into_owned()
+    });
+    let stderr = thread::spawn(move || {
+        let mut buffer = vec![];
+        let _ = stderr.read_to_end(&mut buffer);
+        String::from_utf8_lossy(&buffer).into_owned()
+    });
+
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(status) = child.try_wait().ok()? {
+            return Some((stdout.join().ok()?, stderr.join().ok()?, status.success()));
+        }
+        if Instant::now() >= deadline {
+            // the readers are left behind, a grandchild may still hold the pipes open
+            let _ = child.kill();
+            let _ = child.wait();
+            return None;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+/// Waits up to `timeout`, then terminates the shell along with everything it started.
+fn wait_timeout(child: &mut Child, timeout: Duration) -> io::Result<(std::process::ExitStatus, bool)> {
+    let deadline = Instant::now() + timeout;
+    while Instant::now() < deadline {
+        if let Some(status) = child.try_wait()? {
+            return Ok((status, false));
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+
+    // the shell stays in the terminal's process group, so its children are found through /proc;
+    // it is killed first so it cannot go on with the next command in a list
+    let pids = descendants(child.id());
+    child.kill()?;
+    let status = child.wait()?;
+    signal(&pids, "TERM");
+    let grace = Instant::now() + KILL_GRACE;
+    while Instant::now() < grace && !still_running(&pids).is_empty() {
+        thread::sleep(Duration::from_millis(20));
+    }
+    signal(&still_running(&pids), "KILL");
+    Ok((status, true))
+}
+
+fn signal(pids: &[u32], name: &str) {
+    if pids.is_empty() {
+        return;
+    }
+    let _ = Command::new("kill")
+        .arg("-s")
+        .arg(name)
+        .arg("--")
+        .args(pids.iter().map(|p| p.to_string()))
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status();
+}
+
+/// Every process below `pid`, children first.
+fn descendants(pid: u32) -> Vec<u32> {
+    let parents = parent_pids();
+    let mut found = vec![];
+    let mut queue = vec![pid];
+    while let Some(parent) = queue.pop() {
+        for (child, _) in parents.iter().filter(|(_, p)| *p == parent) {
+            found.push(*child);
+            queue.push(*child);
+        }
+    }
+    found
+}
+
+/// The ones in `pids` that have not exited yet.
+fn still_running(pids: &[u32]) -> Vec<u32> {
+    let alive: Vec<u32> = parent_pids().into_iter().map(|(pid, _)| pid).collect();
+    pids.iter().copied().filter(|pid| alive.contains(pid)).collect()
+}
+
+/// `(pid, parent pid)` of every running process, zombies left out.
+fn parent_pids() -> Vec<(u32, u32)> {
+    let Ok(entries) = fs::read_dir("/proc") else { return vec![] };
+    entries
+        .filter_map(|entry| {
+            let pid = entry.ok()?.file_name().to_str()?.parse::<u32>().ok()?;
+            let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+            // the command name may contain spaces and parentheses, the fields after it cannot
+            let fields: Vec<&str> = stat[stat.rfind(')')? + 1..].split_whitespace().collect();
+            if fields.first() == Some(&"Z") {
+                return None;
+            }
+            Some((pid, fields.get(1)?.parse().ok()?))
+        })
+        .collect()
+}
+
+fn tee<R: Read, W: Write>(mut from: R, mut to: W) -> String {
+    let mut captured: Vec<u8> = vec![];
+    let mut buffer = [0u8; 4096];
+    loop {
+        match from.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => {
+                let _ = to.write_all(&buffer[..n]);
+                let _ = to.flush();
+                captured.extend_from_slice(&buffer[..n]);
+                if captured.len() > 2 * CAPTURE_LIMIT {
+                    captured.drain(..captured.len() - CAPTURE_LIMIT);
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(_e) => break,
+        }
+    }
+
+    let text = String::from_utf8_lossy(&captured).into_owned();
+    match text.char_indices().rev().nth(CAPTURE_LIMIT - 1) {
+        Some((at, _)) if at > 0 => format!("...{}", &text[at..]),
+        _ => text,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AWKWARD: [&str; 6] = ["plain", "two words", "it's", r"back\slash", "$HOME `id` $(id)", "'; rm -rf ~; '"];
+
+    #[test]
+    fn posix_quoting_survives_the_shell() {
+        let shell = Shell::from_path("sh");
+        for arg in AWKWARD {
+            let options = ExecOptions { shell: shell.clone(), timeout: Some(Duration::from_secs(5)), pass_stdin: false };
+            let outcome = run(&format!("printf %s {}", shell.quote(arg)), &options, true).unwrap();
+            assert_eq!(outcome.stdout, arg);
+        }
+    }
+
+    #[test]
+    fn fish_quoting() {
+        let fish = Shell::from_path("/usr/bin/fish");
+        assert_eq!(fish.kind, ShellKind::Fish);
+        assert_eq!(fish.quote("two words"), "'two words'");
+        assert_eq!(fish.quote("it's"), r"'it\'s'");
+        assert_eq!(fish.quote(r"back\slash"), r"'back\\slash'");
+        assert_eq!(fish.quote("$HOME"), "'$HOME'");
+    }
+
+    #[test]
+    fn unknown_shells_fall_back_to_sh() {
+        assert_eq!(Shell::resolve_with(Some("/usr/bin/zsh"), Some("/bin/fish".to_string())).path, "/usr/bin/zsh");
+        assert_eq!(Shell::resolve_with(Some("fish"), None).kind, ShellKind::Fish);
+        assert_eq!(Shell::resolve_with(Some("/usr/bin/nu"), Some("/bin/bash".to_string())).path, "sh");
+        assert_eq!(Shell::resolve_with(Some("pwsh"), None).kind, ShellKind::Posix);
+        assert_eq!(Shell::resolve_with(Some(" "), Some("/bin/bash".to_string())).path, "/bin/bash");
+        assert_eq!(Shell::resolve_with(None, Some("/usr/bin/xonsh".to_string())).path, "sh");
+        assert_eq!(Shell::resolve_with(None, None).path, "sh");
+    }
+
+    #[test]
+    fn exit_codes() {
+        let shell = Shell::from_path("sh");
+        let options = ExecOptions { shell, timeout: Some(Duration::from_millis(300)), pass_stdin: false };
+        assert_eq!(run("exit 3", &options, true).unwrap().exit_code(), 3);
+        let killed = run("kill -s KILL $$", &options, true).unwrap();
+        assert_eq!((killed.exit_status, killed.exit_code()), (None, 137));
+        let timed_out = run("sleep 5", &options, true).unwrap();
+        assert!(timed_out.timed_out);
+        assert_eq!(timed_out.exit_code(), 124);
+    }
+}
@@ -0,0 +1,335 @@
+use crate::shell::command_parser::{base_name, is_assignment, is_redirect, is_wrapper, tokenize, wrapper_option_takes_value, Token, RESERVED_WORDS};
+use crate::shell::manual::Manual;
+use crate::shell::policy::Policy;
+use crate::utils::color::colorify;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::fmt;
+
+const LOOP_KEYWORDS: [&str; 3] = ["for", "select", "case"];
+// longer segments get their explanation on the next line
+const SEGMENT_COLUMN_MAX: usize = 28;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SegmentKind {
+    Binary,
+    Flag,
+    Argument,
+    Assignment,
+    Redirect,
+    Pipe,
+    Operator,
+    Keyword,
+}
+
+impl fmt::Display for SegmentKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            SegmentKind::Binary => write!(f, "binary"),
+            SegmentKind::Flag => write!(f, "flag"),
+            SegmentKind::Argument => write!(f, "argument"),
+            SegmentKind::Assignment => write!(f, "assignment"),
+            SegmentKind::Redirect => write!(f, "redirect"),
+            SegmentKind::Pipe => write!(f, "pipe"),
+            SegmentKind::Operator => write!(f, "operator"),
+            SegmentKind::Keyword => write!(f, "keyword"),
+        }
+    }
+}
+
+/// One part of an explained command, as written in it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Segment {
+    pub kind: SegmentKind,
+    pub text: String,
+    pub binary: Option<String>, // the program a flag or argument is passed to
+}
+
+/// Splits `command` into its binaries, their flags and arguments, pipes, list operators and
+/// redirections, in source order. Substitutions stay inside the word they appear in.
+pub fn segment(command: &str) -> Vec<Segment> {
+    let mut segments = vec![];
+    let mut binary: Option<String> = None;
+    let mut expect_binary = true; // at the start of a command or after a wrapper like sudo
+    let mut wrapper_value = false; // the next word is the value of a wrapper option
+    let mut options_ended = false;
+    let mut pending_redirect: Option<String> = None;
+
+    for (token, text) in tokenize(command) {
+        let kind = match token {
+            Token::Word(word) => {
+                if let Some(op) = pending_redirect.take() {
+                    // "2>&1" stays together, "> out.txt" keeps its space
+                    let joined = if op.ends_with('&') { format!("{}{}", op, text) } else { format!("{} {}", op, text) };
+                    segments.push(Segment { kind: SegmentKind::Redirect, text: joined, binary: binary.clone() });
+                    continue;
+                }
+                if wrapper_value {
+                    wrapper_value = false;
+                    SegmentKind::Argument
+                } else if expect_binary && binary.is_some() && word.text.starts_with('-') {
+                    wrapper_value = wrapper_option_takes_value(binary.as_deref().unwrap_or_default(), &word.text);
+                    SegmentKind::Flag
+                } else if expect_binary && binary.as_deref() == Some("timeout") && word.text.starts_with(|c: char| c.is_ascii_digit()) {
+                    SegmentKind::Argument // the duration
+                } else if expect_binary && binary.is_none() && RESERVED_WORDS.contains(&word.text.as_str()) {
+                    SegmentKind::Keyword
+                } else if expect_binary && binary.is_none() && LOOP_KEYWORDS.contains(&word.text.as_str()) {
+                    // the words up to the next operator belong to the loop, e.g. "f in *.txt"
+                    expect_binary = false;
+                    segments.push(Segment { kind: SegmentKind::Keyword, text, binary: None });
+                    binary = Some(word.text);
+                    continue;
+                } else if expect_binary && is_assignment(&word.text) {
+                    SegmentKind::Assignment
+                } else if expect_binary {
+                    expect_binary = is_wrapper(&word.text);
+                    options_ended = false;
+                    segments.push(Segment { kind: SegmentKind::Binary, text, binary: None });
+                    binary = Some(base_name(&word.text).to_string());
+                    continue;
+                } else if !options_ended && word.text.starts_with('-') && word.text.len() > 1 {
+                    options_ended = word.text == "--";
+                    SegmentKind::Flag
+                } else {
+                    SegmentKind::Argument
+                }
+            }
+            Token::Op(op) if is_redirect(&op) => {
+                pending_redirect = Some(text);
+                continue;
+            }
+            Token::Op(op) => {
+                binary = None;
+                expect_binary = true;
+                wrapper_value = false;
+                if text.trim().is_empty() {
+                    continue; // a newline between commands
+                }
+                if op == "|" || op == "|&" {
+                    SegmentKind::Pipe
+                } else {
+                    SegmentKind::Operator
+                }
+            }
+        };
+        segments.push(Segment { kind, text, binary: binary.clone() });
+    }
+    if let Some(op) = pending_redirect {
+        segments.push(Segment { kind: SegmentKind::Redirect, text: op, binary });
+    }
+    segments
+}
+
+/// The question for the model: the command, its numbered segments and what the local manual
+/// pages, or the `--help` output of binaries `policy` allows, say about the binaries and flags
+/// in it.
+pub fn explain_query(command: &str, segments: &[Segment], policy: &Policy) -> String {
+    let mut query = format!("Explain this command: {}\nSEGMENTS:\n", command.trim());
+    for (i, segment) in segments.iter().enumerate() {
+        match &segment.binary {
+            Some(binary) if segment.kind != SegmentKind::Binary => query.push_str(&format!("{}. {} ({} of {})\n", i + 1, segment.text, segment.kind, binary)),
+            _ => query.push_str(&format!("{}. {} ({})\n", i + 1, segment.text, segment.kind)),
+        }
+    }
+
+    // flags grouped by the binary they belong to, in order of appearance
+    let mut binaries: Vec<(String, Vec<String>)> = vec![];
+    for segment in segments {
+        let (name, flag) = match (segment.kind, &segment.binary) {
+            (SegmentKind::Binary, _) => (base_name(&segment.text).to_string(), None),
+            (SegmentKind::Flag, Some(binary)) => (binary.clone(), Some(segment.text.clone())),
+            _ => continue,
+        };
+        let index = match binaries.iter().position(|(b, _)| *b == name) {
+            Some(index) => index,
+            None => {
+                binaries.push((name, vec![]));
+                binaries.len() - 1
+            }
+        };
+        binaries[index].1.extend(flag);
+    }
+    for (binary, flags) in binaries {
+        // --help only runs for binaries in the system directories, not for one the command brings along
+        if let Some(manual) = Manual::load(&binary, policy) {
+            query.push_str(&format!("MANUAL {}:\n{}\n", binary, manual.excerpt(&flags)));
+        }
+    }
+    query
+}
+
+/// JSON schema for the answer, with exactly one explanation per segment.
+pub fn explanation_schema(segments: usize) -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "summary": { "type": "string", "minLength": 1 },
+            "explanations": {
+                "type": "array",
+                "items": { "type": "string", "minLength": 1 },
+                "minItems": segments,
+                "maxItems": segments
+            }
+        },
+        "required": ["summary", "explanations"]
+    })
+}
+
+#[derive(Debug)]
+pub struct ExplanationError(pub String);
+
+/// An EXPLAIN answer: what the whole command does and one explanation per segment.
+#[derive(Deserialize, Debug, Clone)]
+pub struct Explanation {
+    pub summary: String,
+    pub explanations: Vec<String>,
+}
+
+impl Explanation {
+    pub fn parse(content: &str) -> Result<Self, ExplanationError> {
+        serde_json::from_str(content.trim()).map_err(|e| ExplanationError(format!("Could not parse the explanation: {}", e)))
+    }
+
+    /// The command followed by its segments, each aligned with its explanation.
+    pub fn render(&self, command: &str, segments: &[Segment]) -> String {
+        let mut output = String::new();
+        output.push_str(&format!("{}\n", colorify("Command:", 150., 150., 150.)));
+        output.push('\n');
+        output.push_str(&format!("      {}\n", colorify(command.trim(), 59., 235., 115.)));
+        output.push('\n');
+
+        let width = segments.iter().map(|s| s.text.chars().count()).filter(|w| *w <= SEGMENT_COLUMN_MAX).max().unwrap_or(0);
+        let kind_width = segments.iter().map(|s| s.kind.to_string().len()).max().unwrap_or(0);
+        for (i, segment) in segments.iter().enumerate() {
+            let explanation = self.explanations.get(i).map(|e| e.trim()).unwrap_or("?");
+            let (r, g, b) = match segment.kind {
+                SegmentKind::Binary => (59., 235., 115.),
+                SegmentKind::Flag => (201., 168., 255.),
+                SegmentKind::Redirect | SegmentKind::Pipe | SegmentKind::Operator => (247., 200., 89.),
+                _ => (230., 230., 230.),
+            };
+            let kind = colorify(&format!("{:kind_width$}", segment.kind.to_string()), 150., 150., 150.);
+            let length = segment.text.chars().count();
+            if length > width {
+                output.push_str(&format!("  {}\n", colorify(&segment.text, r, g, b)));
+                output.push_str(&format!("  {:width$}  {}  {}\n", "", kind, explanation));
+            } else {
+                let padded = format!("{}{}", segment.text, " ".repeat(width - length));
+                output.push_str(&format!("  {}  {}  {}\n", colorify(&padded, r, g, b), kind, explanation));
+            }
+        }
+        output.push('\n');
+        output.push_str(&format!("{} {}\n", colorify("Summary:", 150., 150., 150.), self.summary.trim()));
+        output.push('\n');
+        output.push_str(&colorify("The explanations come from the model, grounded on the local manual pages where found.\n", 150., 150., 150.));
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds(command: &str) -> Vec<(SegmentKind, String)> {
+        segment(command).into_iter().map(|s| (s.kind, s.text)).collect()
+    }
+
+    fn owners(command: &str) -> Vec<Option<String>> {
+        segment(command).into_iter().map(|s| s.binary).collect()
+    }
+
+    fn expected(segments: &[(SegmentKind, &str)]) -> Vec<(SegmentKind, String)> {
+        segments.iter().map(|(kind, text)| (*kind, text.to_string())).collect()
+    }
+
+    #[test]
+    fn help_output_follows_the_policy() {
+        let query = explain_query("ls -l", &segment("ls -l"), &Policy::default());
+        assert!(query.starts_with("Explain this command: ls -l\nSEGMENTS:\n1. ls (binary)\n2. -l (flag of ls)\n"), "{}", query);
+        assert!(query.contains("MANUAL ls:"), "{}", query);
+
+        let policy: Policy = toml::from_str("deny_binaries = [\"ls\"]").unwrap();
+        if Manual::load_page("ls").is_none() {
+            assert!(!explain_query("ls -l", &segment("ls -l"), &policy).contains("MANUAL"));
+        }
+    }
+
+    #[test]
+    fn pipes_and_operators() {
+        use SegmentKind::*;
+        assert_eq!(
+            kinds("ls -la /tmp | grep -v log && echo done"),
+            expected(&[(Binary, "ls"), (Flag, "-la"), (Argument, "/tmp"), (Pipe, "|"), (Binary, "grep"), (Flag, "-v"), (Argument, "log"), (Operator, "&&"), (Binary, "echo"), (Argument, "done")])
+        );
+        assert_eq!(owners("ls -l | wc -l")[4], Some("wc".to_string()));
+    }
+
+    #[test]
+    fn redirections() {
+        use SegmentKind::*;
+        assert_eq!(
+            kinds("make 2>&1 > out.txt"),
+            expected(&[(Binary, "make"), (Redirect, "2>&1"), (Redirect, "> out.txt")])
+        );
+        assert_eq!(kinds("sort < names.txt"), expected(&[(Binary, "sort"), (Redirect, "< names.txt")]));
+        assert_eq!(kinds("echo hi >"), expected(&[(Binary, "echo"), (Argument, "hi"), (Redirect, ">")]));
+    }
+
+    #[test]
+    fn wrappers() {
+        use SegmentKind::*;
+        assert_eq!(
+            kinds("sudo -u postgres psql -c 'select 1'"),
+            expected(&[(Binary, "sudo"), (Flag, "-u"), (Argument, "postgres"), (Binary, "psql"), (Flag, "-c"), (Argument, "'select 1'")])
+        );
+        assert_eq!(
+            kinds("timeout 5 curl -s example.com"),
+            expected(&[(Binary, "timeout"), (Argument, "5"), (Binary, "curl"), (Flag, "-s"), (Argument, "example.com")])
+        );
+        assert_eq!(owners("sudo -u x id")[1], Some("sudo".to_string()));
+    }
+
+    #[test]
+    fn assignments() {
+        use SegmentKind::*;
+        assert_eq!(
+            kinds("LANG=C RUST_LOG=debug cargo run -- --port 80"),
+            expected(&[(Assignment, "LANG=C"), (Assignment, "RUST_LOG=debug"), (Binary, "cargo"), (Argument, "run"), (Flag, "--"), (Argument, "--port"), (Argument, "80")])
+        );
+    }
+
+    #[test]
+    fn loops() {
+        use SegmentKind::*;
+        assert_eq!(
+            kinds("for f in *.txt; do wc -l \"$f\"; done"),
+            expected(&[
+                (Keyword, "for"),
+                (Argument, "f"),
+                (Argument, "in"),
+                (Argument, "*.txt"),
+                (Operator, ";"),
+                (Keyword, "do"),
+                (Binary, "wc"),
+                (Flag, "-l"),
+                (Argument, "\"$f\""),
+                (Operator, ";"),
+                (Keyword, "done"),
+            ])
+        );
+    }
+
+    #[test]
+    fn empty_commands() {
+        assert!(segment("").is_empty());
+        assert!(segment("   ").is_empty());
+    }
+
+    #[test]
+    fn schema_asks_for_one_explanation_per_segment() {
+        let schema = explanation_schema(3);
+        assert_eq!(schema["properties"]["explanations"]["minItems"], 3);
+        assert_eq!(schema["properties"]["explanations"]["maxItems"], 3);
+    }
+}
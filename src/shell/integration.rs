@@ -0,0 +1,215 @@
+use crate::shell::command_parser::base_name;
+use crate::shell::exec::{Shell, ShellKind};
+use std::path::PathBuf;
+use std::{env, fs};
+
+// passed by the integration to `shellm fix`; outside the SHELLM_ prefix used by the config
+const LAST_COMMAND_VAR: &str = "_SHELLM_LAST_COMMAND";
+const LAST_STATUS_VAR: &str = "_SHELLM_LAST_STATUS";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Integration {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+impl Integration {
+    fn shell(&self) -> Shell {
+        match *self {
+            Integration::Bash => Shell { path: "bash".to_string(), kind: ShellKind::Posix },
+            Integration::Zsh => Shell { path: "zsh".to_string(), kind: ShellKind::Posix },
+            Integration::Fish => Shell { path: "fish".to_string(), kind: ShellKind::Fish },
+        }
+    }
+}
+
+/// A script for the user's rc file: Ctrl-`key` sends the current command line to shellm and
+/// replaces it with the generated command, which is left for review instead of being run. A
+/// hook also records every command and its exit status in shell variables, which a `shellm`
+/// function hands to `shellm fix` only, so other programs never see them.
+pub fn init_script(integration: Integration, binary: &str, key: char) -> String {
+    let shell = integration.shell();
+    let key = key.to_ascii_lowercase();
+    let binary = shell.quote(binary);
+    // the model should write for the shell the command lands in
+    let command = format!("{} --bash --print-only --shell-path {} --query", binary, shell.name());
+
+    match integration {
+        Integration::Bash => format!(
+            r#"# shellm integration for bash, add to ~/.bashrc:
+#   eval "$(shellm init bash)"
+__shellm_insert() {{
+    [[ -n $READLINE_LINE ]] || return
+    local cmd
+    cmd=$({command} "$READLINE_LINE") || return
+    READLINE_LINE=$cmd
+    READLINE_POINT=${{#READLINE_LINE}}
+}}
+bind -x '"\C-{key}": __shellm_insert'
+
+__shellm_record() {{
+    local status=$? cmd
+    # "  42  command", where a star after the number marks an edited entry
+    cmd=$(HISTTIMEFORMAT= builtin history 1)
+    __shellm_command="${{cmd#*[0-9][ *] }}" __shellm_status=$status
+    return $status
+}}
+[[ $PROMPT_COMMAND == *__shellm_record* ]] || PROMPT_COMMAND="__shellm_record${{PROMPT_COMMAND:+; $PROMPT_COMMAND}}"
+
+shellm() {{
+    if [[ $1 == fix ]]; then
+        {LAST_COMMAND_VAR}=$__shellm_command {LAST_STATUS_VAR}=$__shellm_status command {binary} "$@"
+    else
+        command {binary} "$@"
+    fi
+}}
+"#
+        ),
+        Integration::Zsh => format!(
+            r#"# shellm integration for zsh, add to ~/.zshrc:
+#   eval "$(shellm init zsh)"
+__shellm_insert() {{
+    [[ -n $BUFFER ]] || return
+    local cmd
+    zle -I
+    if cmd=$({command} "$BUFFER" </dev/tty); then
+        BUFFER=$cmd
+        CURSOR=${{#BUFFER}}
+    fi
+    zle reset-prompt
+}}
+zle -N __shellm_insert
+bindkey '^{upper}' __shellm_insert
+
+__shellm_preexec() {{
+    __shellm_running=$1
+}}
+__shellm_precmd() {{
+    local ret=$?
+    __shellm_command=$__shellm_running __shellm_status=$ret
+}}
+autoload -Uz add-zsh-hook
+add-zsh-hook preexec __shellm_preexec
+add-zsh-hook precmd __shellm_precmd
+
+shellm() {{
+    if [[ $1 == fix ]]; then
+        {LAST_COMMAND_VAR}=$__shellm_command {LAST_STATUS_VAR}=$__shellm_status command {binary} "$@"
+    else
+        command {binary} "$@"
+    fi
+}}
+"#,
+            upper = key.to_ascii_uppercase()
+        ),
+        Integration::Fish => format!(
+            r#"# shellm integration for fish, add to ~/.config/fish/config.fish:
+#   shellm init fish | source
+function __shellm_insert
+    set -l line (commandline)
+    test -n "$line"; or return
+    set -l cmd ({command} "$line" | string collect)
+    if test -n "$cmd"
+        commandline --replace -- $cmd
+    end
+    commandline -f repaint
+end
+bind \c{key} __shellm_insert
+bind -M insert \c{key} __shellm_insert
+
+function __shellm_record --on-event fish_postexec
+    set -l ret $status
+    set -g __shellm_command $argv[1]
+    set -g __shellm_status $ret
+end
+
+function shellm
+    if test "$argv[1]" = fix
+        env {LAST_COMMAND_VAR}="$__shellm_command" {LAST_STATUS_VAR}="$__shellm_status" {binary} $argv
+    else
+        command {binary} $argv
+    end
+end
+"#
+        ),
+    }
+}
+
+/// The command for `shellm fix` with its exit status: what the integration recorded, or else
+/// the newest entry in the history file of `shell`, whose status is unknown.
+pub fn last_command(shell: &Shell) -> Option<(String, Option<i32>)> {
+    if let Ok(command) = env::var(LAST_COMMAND_VAR) {
+        if !command.trim().is_empty() && !is_fix(&command) {
+            let status = env::var(LAST_STATUS_VAR).ok().and_then(|s| s.trim().parse().ok());
+            return Some((command.trim().to_string(), status));
+        }
+    }
+    last_from_history(shell).map(|command| (command, None))
+}
+
+/// History files are usually written when the shell exits, so this may lag behind.
+fn last_from_history(shell: &Shell) -> Option<String> {
+    let home = PathBuf::from(env::var_os("HOME")?);
+    let histfile = env::var_os("HISTFILE").map(PathBuf::from);
+    let (path, entry): (PathBuf, fn(&str) -> Option<&str>) = match shell.name() {
+        "zsh" => (histfile.unwrap_or_else(|| home.join(".zsh_history")), |line| {
+            // extended history lines look like ": 1700000000:0;command"
+            match line.strip_prefix(": ") {
+                Some(rest) => rest.split_once(';').map(|(_, command)| command),
+                None => Some(line),
+            }
+        }),
+        "fish" => {
+            let data = env::var_os("XDG_DATA_HOME").map(PathBuf::from).unwrap_or_else(|| home.join(".local").join("share"));
+            (data.join("fish").join("fish_history"), |line| line.strip_prefix("- cmd: "))
+        }
+        _ => (histfile.unwrap_or_else(|| home.join(".bash_history")), |line| {
+            // timestamps are stored as "#1700000000" lines
+            let timestamp = line.len() > 1 && line.starts_with('#') && line[1..].chars().all(|c| c.is_ascii_digit());
+            (!timestamp).then_some(line)
+        }),
+    };
+
+    let content = String::from_utf8_lossy(&fs::read(path).ok()?).into_owned();
+    content
+        .lines()
+        .rev()
+        .filter_map(entry)
+        .map(|command| command.trim())
+        .find(|command| !command.is_empty() && !is_fix(command))
+        .map(|command| command.to_string())
+}
+
+fn is_fix(command: &str) -> bool {
+    let mut words = command.split_whitespace();
+    words.next().map(base_name) == Some("shellm") && words.next() == Some("fix")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+    use std::process::Command;
+
+    #[test]
+    fn bash_passes_the_last_command_to_fix_only() {
+        let dir = env::temp_dir().join(format!("shellm-integration-bash-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        // stands in for shellm and prints what it was given
+        let binary = dir.join("fake shellm");
+        fs::write(&binary, format!("#!/bin/sh\necho \"$*|${}|${}\"\n", LAST_COMMAND_VAR, LAST_STATUS_VAR)).unwrap();
+        fs::set_permissions(&binary, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let script = init_script(Integration::Bash, &binary.display().to_string(), 'g');
+        let test = "false; __shellm_record; __shellm_command='tar xf a.tgz'\n\
+                    printenv | grep -c _SHELLM_\n\
+                    shellm fix --rerun\n\
+                    shellm --query hi\n";
+        let output = Command::new("bash").arg("-c").arg(format!("{}\n{}", script, test)).output().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert_eq!(stdout, "0\nfix --rerun|tar xf a.tgz|1\n--query hi||\n");
+    }
+}
@@ -0,0 +1,486 @@
+use crate::utils::utils::format_timestamp;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+// listings this short get sizes and dates in auto mode
+const AUTO_DETAILS_LIMIT: usize = 20;
+// extensions named in a summary before the rest is lumped together
+const SUMMARY_EXTENSIONS: usize = 8;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ListingDetails {
+    Never,
+    Auto,
+    Always,
+}
+
+/// How the working directory is described to the model.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ListingConfig {
+    /// Per directory; beyond it directories are still named but files are summarized by extension.
+    pub max_entries: usize,
+    /// 1 lists only the working directory, 2 also the directories in it, and so on.
+    pub depth: u32,
+    pub gitignore: bool,
+    /// Sizes and modification times, `auto` adds them to short listings only.
+    pub details: ListingDetails,
+}
+
+impl Default for ListingConfig {
+    fn default() -> Self {
+        ListingConfig { max_entries: 40, depth: 1, gitignore: true, details: ListingDetails::Auto }
+    }
+}
+
+struct Entry {
+    name: String, // relative to the listed directory, directories end with a slash
+    size: Option<u64>,
+    modified: Option<SystemTime>,
+}
+
+enum Item {
+    Entry(Entry),
+    Summary(String),
+}
+
+/// Describes `dir` as a comma separated list. Unreadable entries are left out.
+pub fn list_dir(dir: &Path, config: &ListingConfig) -> String {
+    let mut ignore = if config.gitignore { Ignore::load(dir) } else { Ignore::default() };
+    let mut items = vec![];
+    list_into(dir, "", config.depth.max(1), config, &mut ignore, &mut items);
+
+    let entries = items.iter().filter(|item| matches!(item, Item::Entry(_))).count();
+    let details = match config.details {
+        ListingDetails::Never => false,
+        ListingDetails::Auto => entries <= AUTO_DETAILS_LIMIT,
+        ListingDetails::Always => true,
+    };
+    let parts: Vec<String> = items
+        .into_iter()
+        .map(|item| match item {
+            Item::Entry(entry) if details => format!("{} ({})", entry.name, entry.details()),
+            Item::Entry(entry) => entry.name,
+            Item::Summary(summary) => summary,
+        })
+        .collect();
+    parts.join(", ")
+}
+
+fn list_into(dir: &Path, prefix: &str, depth: u32, config: &ListingConfig, ignore: &mut Ignore, items: &mut Vec<Item>) {
+    let Ok(read) = fs::read_dir(dir) else { return };
+    let mut dirs = vec![];
+    let mut files = vec![];
+    for entry in read.flatten() {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let path = entry.path();
+        if name == ".git" {
+            continue;
+        }
+        // follow symlinks, but still list a dangling one
+        let Ok(meta) = fs::metadata(&path).or_else(|_| fs::symlink_metadata(&path)) else { continue };
+        if ignore.is_ignored(&path, meta.is_dir()) {
+            continue;
+        }
+
+        let modified = meta.modified().ok();
+        if meta.is_dir() {
+            dirs.push((path, Entry { name: format!("{}{}/", prefix, name), size: None, modified }));
+        } else {
+            files.push(Entry { name: format!("{}{}", prefix, name), size: Some(meta.len()), modified });
+        }
+    }
+    dirs.sort_by(|a, b| a.1.name.cmp(&b.1.name));
+    files.sort_by(|a, b| a.name.cmp(&b.name));
+
+    if dirs.len() + files.len() <= config.max_entries {
+        // directories and files interleaved alphabetically, each directory followed by its content
+        let mut files = files.into_iter().peekable();
+        for (path, entry) in dirs {
+            while files.peek().is_some_and(|f| f.name < entry.name) {
+                items.push(Item::Entry(files.next().unwrap()));
+            }
+            descend(path, entry, depth, config, ignore, items);
+        }
+        items.extend(files.map(Item::Entry));
+        return;
+    }
+
+    let location = if prefix.is_empty() { String::new() } else { format!(" in {}", prefix) };
+    let hidden = dirs.len().saturating_sub(config.max_entries);
+    for (path, entry) in dirs.into_iter().take(config.max_entries) {
+        descend(path, entry, depth, config, ignore, items);
+    }
+    if hidden > 0 {
+        items.push(Item::Summary(format!("{} more directories{}", hidden, location)));
+    }
+    items.extend(summarize(&files).into_iter().map(|summary| Item::Summary(format!("{}{}", summary, location))));
+}
+
+fn descend(path: PathBuf, entry: Entry, depth: u32, config: &ListingConfig, ignore: &mut Ignore, items: &mut Vec<Item>) {
+    let prefix = entry.name.clone();
+    items.push(Item::Entry(entry));
+    if depth > 1 {
+        if config.gitignore {
+            ignore.add_file(&path.join(".gitignore"), &path);
+        }
+        list_into(&path, &prefix, depth - 1, config, ignore, items);
+    }
+}
+
+/// "412 .png files", most common extension first.
+fn summarize(files: &[Entry]) -> Vec<String> {
+    let mut counts: BTreeMap<String, usize> = BTreeMap::new();
+    for file in files {
+        let extension = Path::new(&file.name).extension().map(|e| format!(".{}", e.to_string_lossy()));
+        *counts.entry(extension.unwrap_or_default()).or_default() += 1;
+    }
+    let mut counts: Vec<(String, usize)> = counts.into_iter().collect();
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+
+    let mut summary = vec![];
+    let mut rest = 0;
+    for (i, (extension, count)) in counts.into_iter().enumerate() {
+        let noun = if count == 1 { "file" } else { "files" };
+        if i >= SUMMARY_EXTENSIONS {
+            rest += count;
+        } else if extension.is_empty() {
+            summary.push(format!("{} {} without extension", count, noun));
+        } else {
+            summary.push(format!("{} {} {}", count, extension, noun));
+        }
+    }
+    if rest > 0 {
+        summary.push(format!("{} other files", rest));
+    }
+    summary
+}
+
+impl Entry {
+    fn details(&self) -> String {
+        let modified = self.modified.map(|time| format_timestamp(time)[..10].to_string());
+        match (self.size, modified) {
+            (Some(size), Some(modified)) => format!("{}, {}", human_size(size), modified),
+            (Some(size), None) => human_size(size),
+            (None, Some(modified)) => modified,
+            (None, None) => "?".to_string(),
+        }
+    }
+}
+
+fn human_size(bytes: u64) -> String {
+    let units = ["B", "K", "M", "G", "T"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024. && unit < units.len() - 1 {
+        size /= 1024.;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{}B", bytes)
+    } else {
+        format!("{:.1}{}", size, units[unit])
+    }
+}
+
+/// The `.gitignore` rules that apply to a listing: `.git/info/exclude` and every `.gitignore`
+/// from the repository root down, later rules taking precedence.
+#[derive(Default)]
+struct Ignore {
+    rules: Vec<IgnoreRule>,
+}
+
+struct IgnoreRule {
+    base: PathBuf,
+    pattern: Vec<char>,
+    negated: bool,
+    dir_only: bool,
+    anchored: bool, // matched against the path below `base` rather than the file name
+}
+
+impl Ignore {
+    fn load(dir: &Path) -> Self {
+        let mut ignore = Ignore::default();
+        let dir = dir.to_path_buf();
+        let Some(root) = dir.ancestors().find(|d| d.join(".git").exists()) else {
+            ignore.add_file(&dir.join(".gitignore"), &dir);
+            return ignore;
+        };
+
+        ignore.add_file(&root.join(".git").join("info").join("exclude"), root);
+        let mut levels: Vec<&Path> = dir.ancestors().take_while(|d| d.starts_with(root)).collect();
+        levels.reverse();
+        for level in levels {
+            ignore.add_file(&level.join(".gitignore"), level);
+        }
+        ignore
+    }
+
+    fn add_file(&mut self, file: &Path, base: &Path) {
+        let Ok(content) = fs::read_to_string(file) else { return };
+        for line in content.lines() {
+            let mut line = line.trim_end();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let negated = line.starts_with('!');
+            if negated {
+                line = &line[1..];
+            }
+            let dir_only = line.ends_with('/');
+            let line = line.trim_end_matches('/');
+            let anchored = line.contains('/');
+            let pattern = line.trim_start_matches('/');
+            if pattern.is_empty() {
+                continue;
+            }
+            self.rules.push(IgnoreRule {
+                base: base.to_path_buf(),
+                pattern: pattern.chars().collect(),
+                negated,
+                dir_only,
+                anchored,
+            });
+        }
+    }
+
+    fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        let mut ignored = false;
+        for rule in &self.rules {
+            if rule.matches(path, is_dir) {
+                ignored = !rule.negated;
+            }
+        }
+        ignored
+    }
+}
+
+impl IgnoreRule {
+    fn matches(&self, path: &Path, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        let Ok(relative) = path.strip_prefix(&self.base) else { return false };
+        let text = if self.anchored {
+            relative.to_string_lossy().into_owned()
+        } else {
+            match relative.file_name() {
+                Some(name) => name.to_string_lossy().into_owned(),
+                None => return false,
+            }
+        };
+        let text: Vec<char> = text.chars().collect();
+        glob_match(&self.pattern, &text)
+    }
+}
+
+/// Gitignore flavoured glob: `*` and `?` stay within one path component, `**` crosses them and
+/// `[a-z]` matches a class.
+fn glob_match(pattern: &[char], text: &[char]) -> bool {
+    match pattern.first() {
+        None => text.is_empty(),
+        Some('*') if pattern.get(1) == Some(&'*') => {
+            if pattern.get(2) == Some(&'/') {
+                // "**/" also matches no directory at all
+                let rest = &pattern[3..];
+                (0..=text.len()).filter(|&i| i == 0 || text[i - 1] == '/').any(|i| glob_match(rest, &text[i..]))
+            } else {
+                (0..=text.len()).any(|i| glob_match(&pattern[2..], &text[i..]))
+            }
+        }
+        Some('*') => (0..=text.len())
+            .take_while(|&i| i == 0 || text[i - 1] != '/')
+            .any(|i| glob_match(&pattern[1..], &text[i..])),
+        Some('?') => matches!(text.first(), Some(c) if *c != '/') && glob_match(&pattern[1..], &text[1..]),
+        Some('[') => match pattern.iter().skip(2).position(|c| *c == ']').map(|i| i + 2) {
+            Some(close) => match text.first() {
+                Some(c) if *c != '/' && class_match(&pattern[1..close], *c) => glob_match(&pattern[close + 1..], &text[1..]),
+                _ => false,
+            },
+            None => text.first() == Some(&'[') && glob_match(&pattern[1..], &text[1..]),
+        },
+        Some('\\') if pattern.len() > 1 => text.first() == Some(&pattern[1]) && glob_match(&pattern[2..], &text[1..]),
+        Some(c) => text.first() == Some(c) && glob_match(&pattern[1..], &text[1..]),
+    }
+}
+
+fn class_match(class: &[char], c: char) -> bool {
+    let (negated, class) = match class.first() {
+        Some('!') | Some('^') => (true, &class[1..]),
+        _ => (false, class),
+    };
+    let mut found = false;
+    let mut i = 0;
+    while i < class.len() {
+        if i + 2 < class.len() && class[i + 1] == '-' {
+            found |= class[i] <= c && c <= class[i + 2];
+            i += 3;
+        } else {
+            found |= class[i] == c;
+            i += 1;
+        }
+    }
+    found != negated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn glob(pattern: &str, text: &str) -> bool {
+        let pattern: Vec<char> = pattern.chars().collect();
+        let text: Vec<char> = text.chars().collect();
+        glob_match(&pattern, &text)
+    }
+
+    /// A fresh directory holding `files`; names ending with a slash are directories.
+    fn tree(name: &str, files: &[&str]) -> PathBuf {
+        let dir = env::temp_dir().join(format!("shellm-listing-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        for file in files {
+            match file.strip_suffix('/') {
+                Some(sub) => fs::create_dir_all(dir.join(sub)).unwrap(),
+                None => fs::write(dir.join(file), "").unwrap(),
+            }
+        }
+        dir
+    }
+
+    fn config(max_entries: usize, depth: u32) -> ListingConfig {
+        ListingConfig { max_entries, depth, gitignore: true, details: ListingDetails::Never }
+    }
+
+    #[test]
+    fn globs() {
+        assert!(glob("*.log", "build.log"));
+        assert!(!glob("*.log", "logs/build.log"));
+        assert!(glob("?.txt", "a.txt"));
+        assert!(!glob("?.txt", "ab.txt"));
+        assert!(glob("**/cache", "cache"));
+        assert!(glob("**/cache", "a/b/cache"));
+        assert!(!glob("**/cache", "a/bcache"));
+        assert!(glob("docs/**", "docs/a/b.md"));
+        assert!(glob("a/**/z", "a/z"));
+        assert!(glob("a/**/z", "a/b/c/z"));
+        assert!(glob("file[0-9].txt", "file7.txt"));
+        assert!(!glob("file[0-9].txt", "filex.txt"));
+        assert!(glob("file[!0-9].txt", "filex.txt"));
+        assert!(glob("[ab]c", "bc"));
+        assert!(glob(r"\*.txt", "*.txt"));
+        assert!(!glob(r"\*.txt", "a.txt"));
+        assert!(glob("[unclosed", "[unclosed"));
+    }
+
+    #[test]
+    fn ignore_rules() {
+        let dir = tree("rules", &[]);
+        fs::write(dir.join(".gitignore"), "# build output\n*.log\n!keep.log\ntarget/\n/top.txt\ndocs/*.pdf\n**/tmp\n").unwrap();
+        let mut ignore = Ignore::default();
+        ignore.add_file(&dir.join(".gitignore"), &dir);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(ignore.is_ignored(&dir.join("build.log"), false));
+        assert!(ignore.is_ignored(&dir.join("sub/build.log"), false));
+        assert!(!ignore.is_ignored(&dir.join("keep.log"), false));
+        // "target/" is only for directories
+        assert!(ignore.is_ignored(&dir.join("target"), true));
+        assert!(!ignore.is_ignored(&dir.join("target"), false));
+        // anchored patterns match from the directory of the .gitignore
+        assert!(ignore.is_ignored(&dir.join("top.txt"), false));
+        assert!(!ignore.is_ignored(&dir.join("sub/top.txt"), false));
+        assert!(ignore.is_ignored(&dir.join("docs/manual.pdf"), false));
+        assert!(!ignore.is_ignored(&dir.join("docs/old/manual.pdf"), false));
+        assert!(ignore.is_ignored(&dir.join("a/b/tmp"), true));
+        assert!(!ignore.is_ignored(Path::new("/elsewhere/build.log"), false));
+    }
+
+    #[test]
+    fn listing_with_gitignore() {
+        let dir = tree("gitignore", &["src/", "src/main.rs", "src/main.rs.bak", "target/", "Cargo.toml", "notes.log"]);
+        fs::write(dir.join(".gitignore"), "target/\n*.log\n").unwrap();
+        fs::write(dir.join("src/.gitignore"), "*.bak\n").unwrap();
+        let listing = list_dir(&dir, &config(40, 2));
+        let unfiltered = list_dir(&dir, &ListingConfig { gitignore: false, ..config(40, 1) });
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(listing, ".gitignore, Cargo.toml, src/, src/.gitignore, src/main.rs");
+        assert_eq!(unfiltered, ".gitignore, Cargo.toml, notes.log, src/, target/");
+    }
+
+    #[test]
+    fn large_directories_are_summarized() {
+        let mut files: Vec<String> = (0..412).map(|i| format!("frame{}.png", i)).collect();
+        files.extend((0..3).map(|i| format!("clip{}.mp4", i)));
+        files.extend(["README".to_string(), "assets/".to_string(), "docs/".to_string()]);
+        let files: Vec<&str> = files.iter().map(|f| f.as_str()).collect();
+        let dir = tree("summary", &files);
+        let listing = list_dir(&dir, &config(10, 1));
+        let fewer_dirs = list_dir(&dir, &config(1, 1));
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(listing, "assets/, docs/, 412 .png files, 3 .mp4 files, 1 file without extension");
+        assert_eq!(fewer_dirs, "assets/, 1 more directories, 412 .png files, 3 .mp4 files, 1 file without extension");
+    }
+
+    #[test]
+    fn summaries_lump_rare_extensions() {
+        let files: Vec<Entry> = ["a.rs", "b.rs", "c.a", "d.b", "e.c", "f.d", "g.e", "h.f", "i.g", "j.h", "k.i"]
+            .iter()
+            .map(|name| Entry { name: name.to_string(), size: None, modified: None })
+            .collect();
+        let summary = summarize(&files);
+        assert_eq!(summary.first().map(|s| s.as_str()), Some("2 .rs files"));
+        assert_eq!(summary.last().map(|s| s.as_str()), Some("2 other files"));
+        assert_eq!(summary.len(), SUMMARY_EXTENSIONS + 1);
+    }
+
+    #[test]
+    fn non_utf8_names() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+        let dir = tree("non-utf8", &["plain.txt"]);
+        fs::write(dir.join(OsStr::from_bytes(b"caf\xe9.txt")), "").unwrap();
+        let listing = list_dir(&dir, &config(40, 1));
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(listing, "caf\u{fffd}.txt, plain.txt");
+    }
+
+    #[test]
+    fn unreadable_entries() {
+        let dir = tree("unreadable", &["locked/", "locked/secret.txt", "open.txt"]);
+        std::os::unix::fs::symlink(dir.join("missing"), dir.join("dangling")).unwrap();
+        fs::set_permissions(dir.join("locked"), fs::Permissions::from_mode(0o000)).unwrap();
+        let readable = fs::read_dir(dir.join("locked")).is_ok(); // root reads it anyway
+        let listing = list_dir(&dir, &config(40, 2));
+        fs::set_permissions(dir.join("locked"), fs::Permissions::from_mode(0o755)).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        if readable {
+            assert_eq!(listing, "dangling, locked/, locked/secret.txt, open.txt");
+        } else {
+            assert_eq!(listing, "dangling, locked/, open.txt");
+        }
+        assert_eq!(list_dir(&dir, &config(40, 1)), "");
+    }
+
+    #[test]
+    fn details() {
+        let dir = tree("details", &["sub/"]);
+        fs::write(dir.join("data.bin"), vec![0u8; 2048]).unwrap();
+        let listing = list_dir(&dir, &ListingConfig { details: ListingDetails::Always, ..config(40, 1) });
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(listing.starts_with("data.bin (2.0K, "), "{}", listing);
+        assert!(listing.contains("sub/ ("), "{}", listing);
+        assert_eq!(human_size(512), "512B");
+        assert_eq!(human_size(5 * 1024 * 1024), "5.0M");
+    }
+}
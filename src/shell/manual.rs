@@ -0,0 +1,420 @@
+use crate::shell::command_parser::{base_name, parse};
+use crate::shell::context::find_in_path;
+use crate::shell::exec::output_within;
+use crate::shell::policy::Policy;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use std::time::Duration;
+
+// man and --help have to answer quickly, a hanging program only loses its grounding
+const MANUAL_TIMEOUT: Duration = Duration::from_secs(3);
+// manual lines kept for one option, including its description
+const OPTION_LINES: usize = 3;
+const OPTION_CHARS: usize = 300;
+// keeps the option overview of large manuals like gcc's within reason
+const OVERVIEW_OPTIONS: usize = 60;
+const OVERVIEW_CHARS: usize = 120;
+// only programs installed here are trusted to answer --help without doing anything else
+const SYSTEM_DIRS: [&str; 4] = ["/usr/bin", "/bin", "/usr/sbin", "/sbin"];
+
+/// The local documentation of a program: its manual page, or its `--help` output when there
+/// is none.
+#[derive(Debug, Clone)]
+pub struct Manual {
+    pub binary: String,
+    pub summary: Option<String>, // e.g. "ls - list directory contents"
+    lines: Vec<String>,
+    options: Vec<String>, // every option the manual documents
+}
+
+impl Manual {
+    /// The manual page of `binary`, or its `--help` output when `policy` allows running it.
+    pub fn load(binary: &str, policy: &Policy) -> Option<Self> {
+        Self::load_page(binary).or_else(|| {
+            if !policy.allows_binary(binary) {
+                return None;
+            }
+            let help = help_output(binary)?;
+            let summary = help.lines().find(|l| !l.trim().is_empty()).map(|l| l.trim().to_string());
+            Some(Self::from_text(binary, &help, summary))
+        })
+    }
+
+    /// Only the manual page, e.g. `git-commit` for the subcommand of git.
+    pub fn load_page(name: &str) -> Option<Self> {
+        let page = man_page(name)?;
+        // the line after the NAME heading
+        let mut lines = page.lines().skip_while(|l| l.trim() != "NAME").skip(1);
+        let summary = lines.find(|l| !l.trim().is_empty()).map(|l| l.trim().to_string());
+        Some(Self::from_text(name, &page, summary))
+    }
+
+    fn from_text(binary: &str, text: &str, summary: Option<String>) -> Self {
+        let lines: Vec<String> = text.lines().map(|l| l.to_string()).collect();
+        let mut options: Vec<String> = vec![];
+        for line in &lines {
+            for name in option_names(line) {
+                if !options.iter().any(|o| o == name) {
+                    options.push(name.to_string());
+                }
+            }
+        }
+        Manual { binary: binary.to_string(), summary, lines, options }
+    }
+
+    /// The line documenting `option` joined with its description lines.
+    pub fn describe(&self, option: &str) -> Option<String> {
+        let start = self.lines.iter().position(|line| option_names(line).contains(&option))?;
+        let indent = indentation(&self.lines[start]);
+        let mut description = vec![self.lines[start].trim()];
+        for line in &self.lines[start + 1..] {
+            // the next option may be indented further when it has no short form
+            if line.trim().is_empty() || indentation(line) <= indent || line.trim_start().starts_with('-') || description.len() >= OPTION_LINES {
+                break;
+            }
+            description.push(line.trim());
+        }
+        Some(truncate(&description.join(" "), OPTION_CHARS))
+    }
+
+    /// True when `flag` as written in a command is an option this manual lists, or cannot be
+    /// judged because the manual lists none. Combined short flags like `-rf` need every letter
+    /// documented, and a documented letter may carry its value, like `-j4` or `-I/usr/include`.
+    pub fn documents(&self, flag: &str) -> bool {
+        let name = flag.split('=').next().unwrap_or(flag);
+        let body = name.trim_start_matches('-');
+        if self.options.is_empty() || body.is_empty() || body.chars().all(|c| c.is_ascii_digit()) {
+            return true;
+        }
+        if self.options.iter().any(|o| o == name) {
+            return true;
+        }
+        if name.starts_with("--") {
+            // getopt takes any unambiguous abbreviation of a long option
+            return name.len() > 3 && self.options.iter().any(|o| o.starts_with(name));
+        }
+        let known = |c: char| self.options.iter().any(|o| *o == format!("-{}", c));
+        let letters: String = body.chars().take_while(|c| c.is_ascii_alphabetic()).collect();
+        let value = &body[letters.len()..];
+        match letters.chars().next() {
+            Some(first) if !value.is_empty() => known(first),
+            Some(_) => letters.chars().all(known),
+            None => false,
+        }
+    }
+
+    /// The summary followed by the description of each of `flags` the manual documents.
+    pub fn excerpt(&self, flags: &[String]) -> String {
+        let mut excerpt: Vec<String> = self.summary.iter().cloned().collect();
+        for flag in flags {
+            let name = flag.split('=').next().unwrap_or(flag);
+            let descriptions = match self.describe(name) {
+                Some(description) => vec![description],
+                None => combined_flags(name).iter().filter_map(|f| self.describe(f)).collect(),
+            };
+            for description in descriptions {
+                if !excerpt.contains(&description) {
+                    excerpt.push(description);
+                }
+            }
+        }
+        excerpt.join("\n")
+    }
+
+    /// The summary and the first line of every documented option, for choosing among them.
+    pub fn overview(&self) -> String {
+        let mut overview: Vec<String> = self.summary.iter().cloned().collect();
+        for line in self.lines.iter().filter(|line| !option_names(line).is_empty()).take(OVERVIEW_OPTIONS) {
+            overview.push(truncate(line.trim(), OVERVIEW_CHARS));
+        }
+        overview.join("\n")
+    }
+}
+
+/// A flag the local manual of `binary` does not list.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UndocumentedFlag {
+    pub binary: String,
+    pub flag: String,
+}
+
+/// The flags in `command` that the manuals on this machine do not list, along with those
+/// manuals. Programs without a manual are not judged.
+pub fn undocumented_flags(command: &str, policy: &Policy) -> (Vec<UndocumentedFlag>, Vec<Manual>) {
+    undocumented_flags_with(command, &|binary| Manual::load(binary, policy), &Manual::load_page)
+}
+
+/// Same as `undocumented_flags`, with `load` finding the manual of a program and `load_page`
+/// the page of a subcommand like `git-commit`.
+pub fn undocumented_flags_with(
+    command: &str,
+    load: &dyn Fn(&str) -> Option<Manual>,
+    load_page: &dyn Fn(&str) -> Option<Manual>,
+) -> (Vec<UndocumentedFlag>, Vec<Manual>) {
+    let mut found: Vec<UndocumentedFlag> = vec![];
+    let mut manuals: Vec<Manual> = vec![];
+    let mut missing: Vec<String> = vec![];
+
+    for simple in parse(command).commands() {
+        let argv = simple.effective_argv();
+        let Some(first) = argv.first() else { continue };
+        let binary = base_name(first).to_string();
+        if missing.contains(&binary) {
+            continue;
+        }
+        let manual = match manuals.iter().find(|m| m.binary == binary) {
+            Some(manual) => manual.clone(),
+            None => match load(&binary) {
+                Some(manual) => {
+                    manuals.push(manual.clone());
+                    manual
+                }
+                None => {
+                    missing.push(binary);
+                    continue;
+                }
+            },
+        };
+
+        let mut positional = false;
+        let mut subcommand: Option<&str> = None;
+        let mut sub_manual: Option<Option<Manual>> = None;
+        for arg in &argv[1..] {
+            if *arg == "--" {
+                break;
+            }
+            if !arg.starts_with('-') || arg.len() == 1 {
+                // a first argument like "commit" may name a subcommand
+                if !positional && arg.starts_with(|c: char| c.is_ascii_lowercase()) && arg.chars().all(|c| c.is_ascii_lowercase() || c == '-') {
+                    subcommand = Some(arg);
+                }
+                positional = true;
+                continue;
+            }
+            if manual.documents(arg) {
+                continue;
+            }
+            if let Some(sub) = subcommand {
+                // "git commit -m" is documented on the page of the subcommand; without one the
+                // flag cannot be judged
+                let page = sub_manual.get_or_insert_with(|| load_page(&format!("{}-{}", binary, sub)));
+                match page {
+                    Some(page) if !page.documents(arg) => {}
+                    _ => continue,
+                }
+            }
+            let flag = UndocumentedFlag { binary: binary.clone(), flag: arg.to_string() };
+            if !found.contains(&flag) {
+                found.push(flag);
+            }
+        }
+    }
+    manuals.retain(|m| found.iter().any(|f| f.binary == m.binary));
+    (found, manuals)
+}
+
+/// The short flags combined in `-rf`, for when it is not a single-dash long option like `-name`.
+fn combined_flags(flag: &str) -> Vec<String> {
+    if flag.starts_with("--") || flag.len() <= 2 || !flag[1..].chars().all(|c| c.is_ascii_alphanumeric()) {
+        return vec![];
+    }
+    flag[1..].chars().map(|c| format!("-{}", c)).collect()
+}
+
+/// The options a manual line documents, e.g. `-r`, `-R` and `--recursive` for
+/// "-r, -R, --recursive  remove directories".
+fn option_names(line: &str) -> Vec<&str> {
+    let line = line.trim_start();
+    if !line.starts_with('-') {
+        return vec![];
+    }
+    let head = line.split("  ").next().unwrap_or(line);
+    head.split([',', ' ', '=', '[', '<']).filter(|part| part.starts_with('-')).collect()
+}
+
+fn indentation(line: &str) -> usize {
+    line.len() - line.trim_start().len()
+}
+
+fn truncate(text: &str, max_chars: usize) -> String {
+    match text.char_indices().nth(max_chars) {
+        Some((end, _)) => format!("{}...", &text[..end]),
+        None => text.to_string(),
+    }
+}
+
+fn man_page(name: &str) -> Option<String> {
+    let mut command = Command::new("man");
+    command.arg("-P").arg("cat").arg(name).env("MANWIDTH", "100").env("GROFF_NO_SGR", "1").env_remove("MAN_KEEP_FORMATTING");
+    let (stdout, _, success) = output_within(&mut command, MANUAL_TIMEOUT)?;
+    let page = strip_formatting(&stdout);
+    (success && !page.trim().is_empty()).then_some(page)
+}
+
+/// Only for programs on PATH that are installed in the system directories: a path in a command
+/// or a script in the user's own bin directory may do anything, and running it is what the user
+/// has yet to decide about.
+fn help_output(binary: &str) -> Option<String> {
+    if binary.contains('/') {
+        return None;
+    }
+    let path = fs::canonicalize(find_in_path(binary)?).ok()?;
+    if !SYSTEM_DIRS.iter().any(|dir| path.parent() == Some(Path::new(dir))) {
+        return None;
+    }
+    let (stdout, stderr, _) = output_within(Command::new(path).arg("--help"), MANUAL_TIMEOUT)?;
+    // some programs print their usage to stderr
+    let help = if stdout.trim().is_empty() { stderr } else { stdout };
+    let help = strip_formatting(&help);
+    (!help.trim().is_empty()).then_some(help)
+}
+
+/// Drops the overstrikes and color codes manual pages use for bold and underline.
+fn strip_formatting(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\u{8}' => {
+                result.pop();
+            }
+            '\u{1b}' if chars.peek() == Some(&'[') => {
+                chars.next();
+                while chars.next().is_some_and(|c| !c.is_ascii_alphabetic()) {}
+            }
+            c => result.push(c),
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RM_HELP: &str = "Usage: rm [OPTION]... [FILE]...
+Remove (unlink) the FILE(s).
+
+  -f, --force           ignore nonexistent files and arguments, never prompt
+  -i                    prompt before every removal
+  -r, -R, --recursive   remove directories and their contents recursively
+      --preserve-root[=all]  do not remove '/' (default)
+  -v, --verbose         explain what is being done
+";
+
+    const MAKE_HELP: &str = "Usage: make [options] [target] ...
+  -C DIRECTORY, --directory=DIRECTORY
+                              Change to DIRECTORY before doing anything.
+  -j [N], --jobs[=N]          Allow N jobs at once; infinite jobs with no arg.
+  -k, --keep-going            Keep going when some targets can't be made.
+";
+
+    fn manual(binary: &str, text: &str) -> Manual {
+        Manual::from_text(binary, text, None)
+    }
+
+    #[test]
+    fn option_names_of_a_line() {
+        assert_eq!(option_names("  -r, -R, --recursive   remove directories"), vec!["-r", "-R", "--recursive"]);
+        assert_eq!(option_names("  -C DIRECTORY, --directory=DIRECTORY"), vec!["-C", "--directory"]);
+        assert_eq!(option_names("      --preserve-root[=all]  do not remove '/'"), vec!["--preserve-root"]);
+        assert_eq!(option_names("  -j [N], --jobs[=N]          Allow N jobs"), vec!["-j", "--jobs"]);
+        assert!(option_names("Remove (unlink) the FILE(s).").is_empty());
+        assert!(option_names("").is_empty());
+    }
+
+    #[test]
+    fn combined_short_flags() {
+        assert_eq!(combined_flags("-rf"), vec!["-r", "-f"]);
+        assert_eq!(combined_flags("-j4"), vec!["-j", "-4"]);
+        assert!(combined_flags("-r").is_empty());
+        assert!(combined_flags("--force").is_empty());
+        assert!(combined_flags("-I/usr/include").is_empty());
+    }
+
+    #[test]
+    fn documented_flags() {
+        let rm = manual("rm", RM_HELP);
+        assert!(rm.documents("-f"));
+        assert!(rm.documents("--recursive"));
+        assert!(rm.documents("--preserve-root=all"));
+        assert!(!rm.documents("-x"));
+        assert!(!rm.documents("--no-preserve"));
+        // numbers like "head -5" are values, not flags
+        assert!(rm.documents("-5"));
+    }
+
+    #[test]
+    fn combined_flags_need_every_letter() {
+        let rm = manual("rm", RM_HELP);
+        assert!(rm.documents("-rf"));
+        assert!(rm.documents("-Rfv"));
+        assert!(!rm.documents("-rfz"));
+    }
+
+    #[test]
+    fn flags_with_values() {
+        let make = manual("make", MAKE_HELP);
+        assert!(make.documents("-j4"));
+        assert!(make.documents("-C/tmp/build"));
+        assert!(make.documents("--jobs=4"));
+        assert!(make.documents("--directory=/tmp"));
+        assert!(!make.documents("-x4"));
+        assert!(!make.documents("--threads=4"));
+    }
+
+    #[test]
+    fn abbreviated_long_options() {
+        let rm = manual("rm", RM_HELP);
+        assert!(rm.documents("--recur"));
+        assert!(rm.documents("--verb"));
+        // too short to be unambiguous
+        assert!(!rm.documents("--r"));
+        assert!(!rm.documents("--recursively"));
+    }
+
+    #[test]
+    fn manuals_without_options_judge_nothing() {
+        let page = manual("true", "TRUE(1)\n\nNAME\n       true - do nothing, successfully\n");
+        assert!(page.documents("--anything"));
+    }
+
+    #[test]
+    fn descriptions() {
+        let make = manual("make", MAKE_HELP);
+        assert_eq!(make.describe("-C").as_deref(), Some("-C DIRECTORY, --directory=DIRECTORY Change to DIRECTORY before doing anything."));
+        let rm = manual("rm", RM_HELP);
+        assert_eq!(rm.excerpt(&["-rf".to_string()]), "-r, -R, --recursive   remove directories and their contents recursively\n-f, --force           ignore nonexistent files and arguments, never prompt");
+    }
+
+    #[test]
+    fn undocumented_flags_in_a_command() {
+        let load = |binary: &str| (binary == "rm").then(|| manual("rm", RM_HELP));
+        let no_pages = |_: &str| None;
+        let (found, manuals) = undocumented_flags_with("rm -rfz build && ls --color", &load, &no_pages);
+        assert_eq!(found, vec![UndocumentedFlag { binary: "rm".to_string(), flag: "-rfz".to_string() }]);
+        assert_eq!(manuals.len(), 1);
+
+        let (found, manuals) = undocumented_flags_with("rm -rf build -- -x", &load, &no_pages);
+        assert!(found.is_empty());
+        assert!(manuals.is_empty());
+    }
+
+    #[test]
+    fn subcommand_pages() {
+        let git = "  -C <path>   Run as if git was started in <path>\n  --version   Print the version\n";
+        let commit = "  -m <msg>, --message=<msg>\n  -a, --all   Stage modified files\n";
+        let load = |binary: &str| (binary == "git").then(|| manual("git", git));
+        let load_page = |name: &str| (name == "git-commit").then(|| manual("git-commit", commit));
+        let (found, _) = undocumented_flags_with("git commit -am fix", &load, &load_page);
+        assert!(found.is_empty());
+
+        let (found, _) = undocumented_flags_with("git commit --amend-all", &load, &load_page);
+        assert_eq!(found, vec![UndocumentedFlag { binary: "git".to_string(), flag: "--amend-all".to_string() }]);
+
+        // without a page for the subcommand its flags cannot be judged
+        let (found, _) = undocumented_flags_with("git stash --keep-index", &load, &load_page);
+        assert!(found.is_empty());
+    }
+}
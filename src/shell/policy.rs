@@ -0,0 +1,424 @@
+use crate::shell::command_parser::{base_name, expand_home, parse, SimpleCommand};
+use crate::utils::config::Config;
+use serde::Deserialize;
+use std::env;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use toml::Table;
+
+const SYSTEM_POLICY_PATH: &str = "/etc/shellm/policy.toml";
+const POLICY_FILE_NAME: &str = "policy.toml";
+
+#[derive(Debug)]
+pub struct PolicyError(pub String);
+
+/// Which commands shellm may execute. Empty allow lists allow everything; deny lists always win.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Policy {
+    /// Only honoured in the system-wide file: ignore the user policy entirely.
+    pub locked: bool,
+    pub allow_sudo: bool,
+    pub allow_binaries: Vec<String>,
+    pub deny_binaries: Vec<String>,
+    pub allow_paths: Vec<String>,
+    pub deny_paths: Vec<String>,
+    /// Glob patterns (`*` matches anything) checked against the whole command and every part of it.
+    pub allow_patterns: Vec<String>,
+    pub deny_patterns: Vec<String>,
+
+    #[serde(skip)]
+    sources: Vec<PathBuf>,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Policy {
+            locked: false,
+            allow_sudo: true,
+            allow_binaries: vec![],
+            deny_binaries: vec![],
+            allow_paths: vec![],
+            deny_paths: vec![],
+            allow_patterns: vec![],
+            deny_patterns: vec![],
+            sources: vec![],
+        }
+    }
+}
+
+/// Why a command was refused, and which policy files were in effect.
+#[derive(Debug)]
+pub struct PolicyViolation {
+    pub reasons: Vec<String>,
+    pub sources: Vec<PathBuf>,
+}
+
+impl Policy {
+    /// Reads the system-wide policy and, unless it is locked, the user policy on top of it. Keys
+    /// set in the user file replace the system ones.
+    pub fn load() -> Result<Self, PolicyError> {
+        Self::load_from(Path::new(SYSTEM_POLICY_PATH), Self::user_policy_path().as_deref())
+    }
+
+    /// Same as `load`, with the system and user policy read from the given files.
+    pub fn load_from(system: &Path, user: Option<&Path>) -> Result<Self, PolicyError> {
+        let mut merged = Table::new();
+        let mut sources = vec![];
+
+        let mut locked = false;
+        if let Some(table) = Self::read_file(system)? {
+            locked = table.get("locked").and_then(|v| v.as_bool()).unwrap_or(false);
+            merged.extend(table);
+            sources.push(system.to_path_buf());
+        }
+
+        if !locked {
+            if let Some(path) = user {
+                if let Some(mut table) = Self::read_file(path)? {
+                    table.remove("locked");
+                    merged.extend(table);
+                    sources.push(path.to_path_buf());
+                }
+            }
+        }
+
+        let mut policy = match Policy::deserialize(merged) {
+            Ok(policy) => policy,
+            Err(e) => return Err(PolicyError(format!("Invalid policy: {}", e))),
+        };
+        policy.locked = locked;
+        policy.sources = sources;
+        Ok(policy)
+    }
+
+    pub fn user_policy_path() -> Option<PathBuf> {
+        Config::user_config_path().and_then(|path| path.parent().map(|dir| dir.join(POLICY_FILE_NAME)))
+    }
+
+    fn read_file(path: &Path) -> Result<Option<Table>, PolicyError> {
+        if !path.is_file() {
+            return Ok(None);
+        }
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) => return Err(PolicyError(format!("Cannot read policy {}: {}", path.display(), e))),
+        };
+        match content.parse::<Table>() {
+            Ok(table) => {
+                // validate on its own so the error names the file
+                if let Err(e) = Policy::deserialize(table.clone()) {
+                    return Err(PolicyError(format!("Invalid policy in {}: {}", path.display(), e)));
+                }
+                Ok(Some(table))
+            }
+            Err(e) => Err(PolicyError(format!("Cannot parse policy {}: {}", path.display(), e))),
+        }
+    }
+
+    pub fn sources(&self) -> &[PathBuf] {
+        &self.sources
+    }
+
+    /// Checks every command in `command`, including the ones in subshells, substitutions and
+    /// the scripts run by `sh -c`, `eval`, `find -exec` or `xargs`.
+    pub fn check(&self, command: &str) -> Result<(), PolicyViolation> {
+        let mut cwd = env::current_dir().unwrap_or_else(|_| PathBuf::from("/"));
+        let mut reasons = vec![];
+        let mut add = |reason: String| {
+            if !reasons.contains(&reason) {
+                reasons.push(reason);
+            }
+        };
+
+        let script = parse(command);
+        if script.incomplete {
+            add("the command could not be parsed completely".to_string());
+        }
+
+        let parts: Vec<String> = script.commands().map(|c| c.argv().join(" ")).collect();
+        let texts: Vec<&str> = std::iter::once(command.trim()).chain(parts.iter().map(|p| p.as_str())).collect();
+        for pattern in &self.deny_patterns {
+            if texts.iter().any(|text| glob_match(pattern, text)) {
+                add(format!("matches the denied pattern '{}'", pattern));
+            }
+        }
+        if !self.allow_patterns.is_empty() && !self.allow_patterns.iter().any(|p| glob_match(p, command.trim())) {
+            add("does not match any allowed pattern".to_string());
+        }
+
+        for simple in script.commands() {
+            if simple.is_privileged() && !self.allow_sudo {
+                add("sudo and other privilege escalation are disabled".to_string());
+            }
+            for binary in Self::binaries(simple) {
+                if self.deny_binaries.iter().any(|b| b == binary) {
+                    add(format!("the binary '{}' is denied", binary));
+                } else if !self.allow_binaries.is_empty() && !self.allow_binaries.iter().any(|b| b == binary) {
+                    add(format!("the binary '{}' is not in the allowed list", binary));
+                }
+            }
+            for path in Self::paths(simple) {
+                let resolved = normalize(&cwd.join(expand_home(path)));
+                if let Some(prefix) = self.deny_paths.iter().find(|p| resolved.starts_with(normalize(&expand_home(p)))) {
+                    add(format!("touches {}, under the denied path {}", path, prefix));
+                } else if !self.allow_paths.is_empty()
+                    && !self.allow_paths.iter().any(|p| resolved.starts_with(normalize(&expand_home(p))))
+                {
+                    add(format!("touches {}, outside the allowed paths", path));
+                }
+            }
+            // after its own operand, which is relative to the directory it leaves
+            let argv = simple.effective_argv();
+            if argv.first() == Some(&"cd") {
+                cwd = normalize(&cwd.join(expand_home(argv.get(1).unwrap_or(&"~"))));
+            }
+        }
+
+        if reasons.is_empty() {
+            Ok(())
+        } else {
+            Err(PolicyViolation { reasons, sources: self.sources.clone() })
+        }
+    }
+
+    /// True when the binary lists let `binary` run, whatever its arguments.
+    pub fn allows_binary(&self, binary: &str) -> bool {
+        let binary = base_name(binary);
+        !self.deny_binaries.iter().any(|b| b == binary) && (self.allow_binaries.is_empty() || self.allow_binaries.iter().any(|b| b == binary))
+    }
+
+    /// The wrappers and the binary that actually runs, by file name.
+    fn binaries(command: &SimpleCommand) -> Vec<&str> {
+        let mut binaries: Vec<&str> = command.wrappers().into_iter().map(base_name).collect();
+        if let Some(binary) = command.effective_argv().first() {
+            binaries.push(base_name(binary));
+        }
+        binaries
+    }
+
+    /// Every operand, the values of `--option=value` and `key=value` arguments, and redirection
+    /// targets. Relative ones are resolved against the working directory by `check`, so a word
+    /// that is not meant as a path is still judged as one.
+    fn paths(command: &SimpleCommand) -> Vec<&str> {
+        let mut paths = vec![];
+        let mut options_done = false;
+        for arg in command.effective_argv().iter().skip(1) {
+            if !options_done && *arg == "--" {
+                options_done = true;
+                continue;
+            }
+            let value = match arg.split_once('=') {
+                Some((_, value)) if !options_done && arg.starts_with('-') => value,
+                Some((key, value)) if !key.is_empty() && !key.contains('/') => value,
+                // options and a lone "-" for stdin
+                _ if !options_done && arg.starts_with('-') => continue,
+                _ => arg,
+            };
+            if !value.is_empty() {
+                paths.push(value);
+            }
+        }
+        for redirect in &command.redirects {
+            let fd_target = redirect.op.ends_with('&') && redirect.target.chars().all(|c| c.is_ascii_digit() || c == '-');
+            if !fd_target && !redirect.op.starts_with("<<") {
+                paths.push(redirect.target.as_str());
+            }
+        }
+        paths
+    }
+}
+
+/// Resolves `.` and `..` without touching the filesystem.
+fn normalize(path: &Path) -> PathBuf {
+    let mut out = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::ParentDir => {
+                out.pop();
+            }
+            Component::CurDir => {}
+            other => out.push(other.as_os_str()),
+        }
+    }
+    out
+}
+
+/// Shell-style glob where `*` matches any run of characters and `?` a single one.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star, matched)) = backtrack {
+            p = star + 1;
+            t = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(toml: &str) -> Policy {
+        Policy::deserialize(toml.parse::<Table>().unwrap()).unwrap()
+    }
+
+    fn denied(policy: &Policy, command: &str) -> bool {
+        policy.check(command).is_err()
+    }
+
+    /// Writes the system and user policy to a fresh directory and loads them.
+    fn load(name: &str, system: &str, user: &str) -> Policy {
+        let dir = env::temp_dir().join(format!("shellm-policy-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (system_path, user_path) = (dir.join("system.toml"), dir.join("user.toml"));
+        fs::write(&system_path, system).unwrap();
+        fs::write(&user_path, user).unwrap();
+        let policy = Policy::load_from(&system_path, Some(&user_path)).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        policy
+    }
+
+    #[test]
+    fn denied_binaries() {
+        let policy = policy(r#"deny_binaries = ["rm", "curl"]"#);
+        assert!(denied(&policy, "rm -rf build"));
+        assert!(denied(&policy, "sudo /bin/rm x"));
+        assert!(denied(&policy, "ls | xargs rm"));
+        assert!(denied(&policy, "echo $(curl -s example.com)"));
+        assert!(!denied(&policy, "ls -la"));
+    }
+
+    #[test]
+    fn allowed_binaries_without_arguments() {
+        let denying = policy(r#"deny_binaries = ["rm"]"#);
+        assert!(!denying.allows_binary("rm"));
+        assert!(!denying.allows_binary("/bin/rm"));
+        assert!(denying.allows_binary("ls"));
+        let allowing = policy(r#"allow_binaries = ["git", "ls"]"#);
+        assert!(allowing.allows_binary("git"));
+        assert!(!allowing.allows_binary("make"));
+    }
+
+    #[test]
+    fn denied_binaries_in_nested_commands() {
+        let policy = policy(r#"deny_binaries = ["rm", "curl"]"#);
+        assert!(denied(&policy, "bash -c 'rm -rf build'"));
+        assert!(denied(&policy, "sh -c \"curl -s example.com | jq .\""));
+        assert!(denied(&policy, "eval rm -rf x"));
+        assert!(denied(&policy, "find . -exec rm {} +"));
+        assert!(denied(&policy, "find . -name '*.o' -print0 | xargs -0 rm"));
+        assert!(!denied(&policy, "bash -c 'echo hi'"));
+    }
+
+    #[test]
+    fn command_lookups_run_nothing() {
+        let policy = policy(r#"deny_binaries = ["rm"]"#);
+        assert!(!denied(&policy, "command -v rm"));
+        assert!(!denied(&policy, "command -V rm"));
+        assert!(denied(&policy, "command rm x"));
+    }
+
+    #[test]
+    fn sudo() {
+        let policy = policy("allow_sudo = false");
+        assert!(denied(&policy, "sudo apt update"));
+        assert!(denied(&policy, "bash -c 'sudo apt update'"));
+        assert!(denied(&policy, "su -c 'apt update'"));
+        assert!(!denied(&policy, "apt list --installed"));
+    }
+
+    #[test]
+    fn allowed_binaries_and_paths() {
+        let policy = policy(r#"
+            allow_binaries = ["ls", "cat"]
+            deny_paths = ["/etc"]
+        "#);
+        assert!(!denied(&policy, "ls /tmp | cat"));
+        assert!(denied(&policy, "ls | wc -l"));
+        assert!(denied(&policy, "cat /etc/shadow"));
+        assert!(denied(&policy, "cat ../../../../../../etc/passwd"));
+    }
+
+    #[test]
+    fn relative_paths_follow_cd() {
+        let policy = policy(r#"deny_paths = ["/etc"]"#);
+        assert!(denied(&policy, "cd / && cat etc/shadow"));
+        assert!(denied(&policy, "cd /usr; cat ../etc/shadow"));
+        assert!(denied(&policy, "cd / && cp notes etc"));
+        assert!(denied(&policy, "cd /etc && cat shadow"));
+        assert!(denied(&policy, "cd / && dd if=etc/shadow of=copy"));
+        assert!(denied(&policy, "cd / && grep --file=etc/patterns x"));
+        assert!(denied(&policy, "cd / && cat -- etc/shadow"));
+        assert!(!denied(&policy, "cd /tmp && cat etc/shadow"));
+        assert!(!denied(&policy, "cd / && ls -la"));
+    }
+
+    #[test]
+    fn relative_paths_outside_allowed_paths() {
+        let policy = policy(r#"allow_paths = ["/srv/build"]"#);
+        // the tests do not run in /srv/build
+        assert!(denied(&policy, "rm -rf build"));
+        assert!(denied(&policy, "make > log.txt"));
+        assert!(denied(&policy, "cd /srv/build && rm -rf ../other"));
+        assert!(!denied(&policy, "cd /srv/build && rm -rf build"));
+        assert!(!denied(&policy, "cd /srv/build/app && make -j4 CC=/srv/build/bin/cc > log.txt 2>&1"));
+        assert!(!denied(&policy, "ls -la /srv/build"));
+    }
+
+    #[test]
+    fn patterns() {
+        let policy = policy(r#"deny_patterns = ["git push*--force*"]"#);
+        assert!(denied(&policy, "git push origin main --force"));
+        assert!(denied(&policy, "cd repo && git push --force"));
+        assert!(!denied(&policy, "git push origin main"));
+    }
+
+    #[test]
+    fn user_policy_replaces_system_keys() {
+        let policy = load(
+            "merge",
+            "allow_sudo = false\ndeny_binaries = [\"rm\"]",
+            "deny_binaries = [\"curl\"]\nlocked = true",
+        );
+        assert!(!policy.allow_sudo);
+        assert_eq!(policy.deny_binaries, vec!["curl".to_string()]);
+        // only the system file can lock
+        assert!(!policy.locked);
+        assert_eq!(policy.sources().len(), 2);
+    }
+
+    #[test]
+    fn locked_system_policy_ignores_user() {
+        let policy = load("locked", "locked = true\ndeny_binaries = [\"rm\"]", "deny_binaries = []\nallow_sudo = false");
+        assert!(policy.locked);
+        assert_eq!(policy.deny_binaries, vec!["rm".to_string()]);
+        assert!(policy.allow_sudo);
+        assert_eq!(policy.sources().len(), 1);
+    }
+
+    #[test]
+    fn invalid_policy_names_the_file() {
+        let dir = env::temp_dir().join(format!("shellm-policy-invalid-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("system.toml");
+        fs::write(&path, "deny_binary = [\"rm\"]").unwrap();
+        let error = Policy::load_from(&path, None).unwrap_err();
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(error.0.contains("system.toml"));
+    }
+}
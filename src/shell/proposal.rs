@@ -0,0 +1,312 @@
+use crate::utils::color::colorify;
+use serde::Deserialize;
+use std::fmt;
+
+/// JSON schema every CMD answer is constrained to: a proposal, or a question for the user.
+pub const PROPOSAL_SCHEMA: &str = r#"{
+    "anyOf": [
+        {
+            "type": "object",
+            "properties": {
+                "command": { "type": "string", "minLength": 1 },
+                "steps": {
+                    "type": "array",
+                    "minItems": 1,
+                    "items": {
+                        "type": "object",
+                        "properties": {
+                            "command": { "type": "string", "minLength": 1 },
+                            "explanation": { "type": "string" }
+                        },
+                        "required": ["command", "explanation"]
+                    }
+                },
+                "touches": { "type": "array", "items": { "type": "string" } },
+                "risk": { "enum": ["read-only", "network", "modifies-files", "needs-sudo", "destructive"] },
+                "diagnosis": { "type": "string" }
+            },
+            "required": ["command", "steps", "touches", "risk"]
+        },
+        {
+            "type": "object",
+            "properties": {
+                "question": { "type": "string", "minLength": 1 }
+            },
+            "required": ["question"]
+        }
+    ]
+}"#;
+
+pub const PROPOSAL_FORMAT_PROMPT: &str = " Respond with a single JSON object instead of the bare command: {\"command\": the full command, \"steps\": [{\"command\": one part of the command, \"explanation\": what that part does}, ...], \"touches\": [every file, directory or path the command reads or writes], \"risk\": one of \"read-only\", \"network\", \"modifies-files\", \"needs-sudo\" or \"destructive\" (pick the most severe that applies)}. Only when asked to fix a command that failed, add \"diagnosis\": what went wrong and why, after \"risk\". When the request is ambiguous or misses a detail that has no sensible default, such as which of several files or directories is meant, respond with {\"question\": the question for the user} instead and propose the command once it is answered.";
+
+/// Ordered from least to most severe.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "kebab-case")]
+pub enum RiskClass {
+    ReadOnly,
+    Network,
+    ModifiesFiles,
+    NeedsSudo,
+    Destructive,
+}
+
+impl RiskClass {
+    pub fn color(&self) -> (f32, f32, f32) {
+        match *self {
+            RiskClass::ReadOnly => (59., 235., 115.),
+            RiskClass::Network | RiskClass::ModifiesFiles => (247., 200., 89.),
+            RiskClass::NeedsSudo | RiskClass::Destructive => (247., 89., 89.),
+        }
+    }
+}
+
+impl fmt::Display for RiskClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            RiskClass::ReadOnly => write!(f, "read-only"),
+            RiskClass::Network => write!(f, "network"),
+            RiskClass::ModifiesFiles => write!(f, "modifies files"),
+            RiskClass::NeedsSudo => write!(f, "needs sudo"),
+            RiskClass::Destructive => write!(f, "destructive"),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ProposalStep {
+    pub command: String,
+    pub explanation: String,
+}
+
+#[derive(Debug)]
+pub struct ProposalError(pub String);
+
+/// A CMD answer: the command to run, what each part of it does, the paths it touches and
+/// how risky the model considers it.
+#[derive(Deserialize, Debug, Clone)]
+pub struct CommandProposal {
+    pub command: String,
+    pub steps: Vec<ProposalStep>,
+    pub touches: Vec<String>,
+    pub risk: RiskClass,
+    #[serde(default)]
+    pub diagnosis: Option<String>, // only for fixes of failed commands
+    #[serde(skip)]
+    pub warnings: Vec<String>, // found by shellm's own checks
+}
+
+impl CommandProposal {
+    pub fn parse(content: &str) -> Result<Self, ProposalError> {
+        let proposal: CommandProposal = match serde_json::from_str(content.trim()) {
+            Ok(proposal) => proposal,
+            Err(e) => return Err(ProposalError(format!("Could not parse the generated command: {}", e))),
+        };
+        if proposal.command.trim().is_empty() {
+            return Err(ProposalError("No command generated".to_string()));
+        }
+        Ok(proposal)
+    }
+
+    /// The confirmation screen shown before asking to execute.
+    pub fn render(&self) -> String {
+        let mut output = String::new();
+
+        if let Some(diagnosis) = self.diagnosis.as_ref().filter(|d| !d.trim().is_empty()) {
+            output.push_str(&format!("{} {}\n", colorify("What went wrong:", 150., 150., 150.), diagnosis.trim()));
+            output.push('\n');
+        }
+        output.push_str(&format!("{}\n", colorify("Generated command:", 150., 150., 150.)));
+        output.push('\n');
+        output.push_str(&format!("      {}\n", colorify(&self.command, 59., 235., 115.)));
+        output.push('\n');
+
+        output.push_str(&format!("{}\n", colorify("Steps:", 150., 150., 150.)));
+        for (i, step) in self.steps.iter().enumerate() {
+            output.push_str(&format!("  {}. {}\n", i + 1, colorify(&step.command, 59., 235., 115.)));
+            output.push_str(&format!("     {}\n", colorify(&step.explanation, 150., 150., 150.)));
+        }
+        output.push('\n');
+
+        let touches = if self.touches.is_empty() { "nothing".to_string() } else { self.touches.join(", ") };
+        output.push_str(&format!("{} {}\n", colorify("Touches:", 150., 150., 150.), touches));
+
+        let (r, g, b) = self.risk.color();
+        output.push_str(&format!("{} {}\n", colorify("Risk:", 150., 150., 150.), colorify(&self.risk.to_string(), r, g, b)));
+        output.push('\n');
+        if !self.warnings.is_empty() {
+            output.push_str(&format!("{}\n", colorify("Warnings:", 247., 200., 89.)));
+            for warning in &self.warnings {
+                output.push_str(&format!("  - {}\n", warning));
+            }
+            output.push('\n');
+        }
+        output.push_str(&colorify("The explanation and risk come from the model.\nVerify the command if you're uncertain.\n", 150., 150., 150.));
+
+        output
+    }
+}
+
+/// A CMD answer asking the user for a missing detail instead of proposing a command.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ClarifyingQuestion {
+    pub question: String,
+}
+
+impl ClarifyingQuestion {
+    /// None when `content` is not a question, e.g. a proposal.
+    pub fn parse(content: &str) -> Option<Self> {
+        let question: ClarifyingQuestion = serde_json::from_str(content.trim()).ok()?;
+        (!question.question.trim().is_empty()).then_some(question)
+    }
+}
+
+/// JSON schema PLAN answers are constrained to.
+pub const PLAN_SCHEMA: &str = r#"{
+    "type": "object",
+    "properties": {
+        "goal": { "type": "string" },
+        "steps": {
+            "type": "array",
+            "minItems": 1,
+            "items": {
+                "type": "object",
+                "properties": {
+                    "description": { "type": "string" },
+                    "command": { "type": "string", "minLength": 1 },
+                    "risk": { "enum": ["read-only", "network", "modifies-files", "needs-sudo", "destructive"] }
+                },
+                "required": ["description", "command", "risk"]
+            }
+        }
+    },
+    "required": ["goal", "steps"]
+}"#;
+
+pub const PLAN_FORMAT_PROMPT: &str = " Respond with a single JSON object: {\"goal\": a short summary of the task, \"steps\": [{\"description\": what the step does, \"command\": the command for this step, \"risk\": one of \"read-only\", \"network\", \"modifies-files\", \"needs-sudo\" or \"destructive\"}, ...]}.";
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct PlanStep {
+    pub description: String,
+    pub command: String,
+    pub risk: RiskClass,
+}
+
+/// A PLAN answer: an ordered list of commands, each approved and run on its own.
+#[derive(Deserialize, Debug, Clone)]
+pub struct Plan {
+    pub goal: String,
+    pub steps: Vec<PlanStep>,
+}
+
+impl Plan {
+    pub fn parse(content: &str) -> Result<Self, ProposalError> {
+        let plan: Plan = match serde_json::from_str(content.trim()) {
+            Ok(plan) => plan,
+            Err(e) => return Err(ProposalError(format!("Could not parse the generated plan: {}", e))),
+        };
+        if plan.steps.is_empty() || plan.steps.iter().any(|s| s.command.trim().is_empty()) {
+            return Err(ProposalError("The plan has no steps to run".to_string()));
+        }
+        Ok(plan)
+    }
+
+    /// The overview shown before the first step.
+    pub fn render(&self) -> String {
+        let mut output = String::new();
+
+        output.push_str(&format!("{} {}\n", colorify("Plan:", 150., 150., 150.), self.goal));
+        output.push('\n');
+        for (i, step) in self.steps.iter().enumerate() {
+            let (r, g, b) = step.risk.color();
+            output.push_str(&format!("  {}. {} {}\n", i + 1, step.description, colorify(&format!("({})", step.risk), r, g, b)));
+            output.push_str(&format!("     {}\n", colorify(&step.command, 59., 235., 115.)));
+        }
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROPOSAL: &str = r#"{"command": "du -sh * | sort -h", "steps": [{"command": "du -sh *", "explanation": "size of each entry"}, {"command": "sort -h", "explanation": "smallest first"}], "touches": ["."], "risk": "read-only"}"#;
+
+    #[test]
+    fn valid_proposals() {
+        let proposal = CommandProposal::parse(&format!("  {}\n", PROPOSAL)).unwrap();
+        assert_eq!(proposal.command, "du -sh * | sort -h");
+        assert_eq!(proposal.steps.len(), 2);
+        assert_eq!(proposal.steps[1].explanation, "smallest first");
+        assert_eq!(proposal.touches, vec!["."]);
+        assert_eq!(proposal.risk, RiskClass::ReadOnly);
+        assert_eq!(proposal.diagnosis, None);
+        assert!(proposal.warnings.is_empty());
+
+        let fix = r#"{"command": "ls", "steps": [], "touches": [], "risk": "destructive", "diagnosis": "typo"}"#;
+        let proposal = CommandProposal::parse(fix).unwrap();
+        assert_eq!(proposal.diagnosis.as_deref(), Some("typo"));
+        assert_eq!(proposal.risk, RiskClass::Destructive);
+    }
+
+    #[test]
+    fn missing_and_unknown_fields() {
+        assert!(CommandProposal::parse(r#"{"command": "ls", "steps": [], "touches": []}"#).is_err());
+        assert!(CommandProposal::parse(r#"{"steps": [], "touches": [], "risk": "read-only"}"#).is_err());
+        assert!(CommandProposal::parse(r#"{"command": "ls", "steps": [], "touches": [], "risk": "harmless"}"#).is_err());
+        assert!(CommandProposal::parse(r#"{"command": "ls", "steps": [{"command": "ls"}], "touches": [], "risk": "read-only"}"#).is_err());
+        // extra fields the schema does not ask for are ignored
+        let extra = r#"{"command": "ls", "steps": [], "touches": [], "risk": "read-only", "confidence": 0.9}"#;
+        assert_eq!(CommandProposal::parse(extra).unwrap().command, "ls");
+        assert!(CommandProposal::parse("ls -la").is_err());
+        assert!(CommandProposal::parse("").is_err());
+    }
+
+    #[test]
+    fn empty_commands() {
+        for command in ["", "   ", "\n\t"] {
+            let content = format!(r#"{{"command": {:?}, "steps": [], "touches": [], "risk": "read-only"}}"#, command);
+            let error = CommandProposal::parse(&content).unwrap_err();
+            assert_eq!(error.0, "No command generated");
+        }
+    }
+
+    #[test]
+    fn questions_and_proposals() {
+        let question = r#"{"question": "Which of the two logs do you mean?"}"#;
+        assert_eq!(ClarifyingQuestion::parse(question).unwrap().question, "Which of the two logs do you mean?");
+        assert!(CommandProposal::parse(question).is_err());
+
+        assert!(ClarifyingQuestion::parse(PROPOSAL).is_none());
+        assert!(ClarifyingQuestion::parse(r#"{"question": "   "}"#).is_none());
+        assert!(ClarifyingQuestion::parse(r#"{"question": "Which?", "command": "ls"}"#).is_none());
+        assert!(ClarifyingQuestion::parse("Which file?").is_none());
+    }
+
+    #[test]
+    fn risk_order() {
+        assert!(RiskClass::ReadOnly < RiskClass::Network);
+        assert!(RiskClass::NeedsSudo < RiskClass::Destructive);
+        assert_eq!(RiskClass::ModifiesFiles.to_string(), "modifies files");
+    }
+
+    #[test]
+    fn plans() {
+        let plan = Plan::parse(
+            r#"{"goal": "set up a venv", "steps": [
+                {"description": "create it", "command": "python3 -m venv .venv", "risk": "modifies-files"},
+                {"description": "install", "command": ".venv/bin/pip install -r requirements.txt", "risk": "network"}
+            ]}"#,
+        )
+        .unwrap();
+        assert_eq!(plan.goal, "set up a venv");
+        let risks: Vec<RiskClass> = plan.steps.iter().map(|s| s.risk).collect();
+        assert_eq!(risks, vec![RiskClass::ModifiesFiles, RiskClass::Network]);
+
+        assert_eq!(Plan::parse(r#"{"goal": "nothing", "steps": []}"#).unwrap_err().0, "The plan has no steps to run");
+        let blank = r#"{"goal": "x", "steps": [{"description": "a", "command": " ", "risk": "read-only"}]}"#;
+        assert!(Plan::parse(blank).is_err());
+        assert!(Plan::parse(r#"{"steps": [{"description": "a", "command": "ls", "risk": "read-only"}]}"#).is_err());
+        assert!(Plan::parse(PROPOSAL).is_err());
+    }
+}
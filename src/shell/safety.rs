@@ -0,0 +1,412 @@
+use crate::shell::command_parser::{base_name, expand_home, parse, Pipeline, Redirect, SimpleCommand};
+use crate::shell::proposal::RiskClass;
+use crate::utils::color::colorify;
+
+const TYPED_CONFIRMATION: &str = "execute";
+
+const DOWNLOADERS: [&str; 4] = ["curl", "wget", "fetch", "aria2c"];
+const INTERPRETERS: [&str; 12] = ["sh", "bash", "zsh", "dash", "ksh", "fish", "python", "python3", "perl", "ruby", "node", "php"];
+const FORMATTERS: [&str; 4] = ["mkfs", "mke2fs", "mkswap", "wipefs"];
+const PARTITIONERS: [&str; 4] = ["fdisk", "sfdisk", "gdisk", "parted"];
+const HARMLESS_DEVICES: [&str; 6] = ["/dev/null", "/dev/zero", "/dev/stdout", "/dev/stderr", "/dev/tty", "/dev/random"];
+const CRITICAL_PATHS: [&str; 20] = [
+    "/", "~", "$HOME", "${HOME}", "/home", "/root", "/etc", "/usr", "/var", "/bin", "/sbin", "/lib",
+    "/lib64", "/boot", "/dev", "/opt", "/sys", "/proc", "/srv", "/mnt",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Caution,
+    Danger,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Finding {
+    pub severity: Severity,
+    pub risk: RiskClass,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SafetyReport {
+    pub findings: Vec<Finding>,
+}
+
+impl SafetyReport {
+    /// High-risk commands need the confirmation keyword typed out instead of a single key.
+    pub fn is_high_risk(&self) -> bool {
+        self.findings.iter().any(|f| f.severity == Severity::Danger)
+    }
+
+    /// The most severe risk class found, if any.
+    pub fn risk(&self) -> Option<RiskClass> {
+        self.findings.iter().map(|f| f.risk).max()
+    }
+
+    pub fn confirmation_keyword() -> &'static str {
+        TYPED_CONFIRMATION
+    }
+
+    pub fn render(&self) -> String {
+        let mut output = String::new();
+        if self.findings.is_empty() {
+            output.push_str(&format!("{}\n", colorify("Safety check: nothing flagged", 150., 150., 150.)));
+            return output;
+        }
+
+        output.push_str(&format!("{}\n", colorify("Safety check:", 150., 150., 150.)));
+        for finding in &self.findings {
+            let tag = match finding.severity {
+                Severity::Danger => colorify("danger ", 247., 89., 89.),
+                Severity::Caution => colorify("caution", 247., 200., 89.),
+            };
+            output.push_str(&format!("  {} {}\n", tag, finding.message));
+        }
+        output
+    }
+
+    fn flag(&mut self, severity: Severity, risk: RiskClass, message: String) {
+        let finding = Finding { severity, risk, message };
+        if !self.findings.contains(&finding) {
+            self.findings.push(finding);
+        }
+    }
+}
+
+/// Checks `command` without running it, looking up redirection targets on disk.
+pub fn analyze(command: &str) -> SafetyReport {
+    analyze_with(command, &|path| expand_home(path).exists())
+}
+
+/// Same as `analyze`, with `file_exists` deciding whether a redirection overwrites a file.
+pub fn analyze_with(command: &str, file_exists: &dyn Fn(&str) -> bool) -> SafetyReport {
+    let mut report = SafetyReport::default();
+    let script = parse(command);
+
+    if script.incomplete {
+        report.flag(Severity::Caution, RiskClass::ReadOnly, "the command could not be parsed completely".to_string());
+    }
+    if let Some(name) = fork_bomb(command) {
+        report.flag(Severity::Danger, RiskClass::Destructive, format!("fork bomb: '{}' keeps spawning copies of itself", name));
+    }
+
+    let mut cwd: Option<String> = None;
+    for pipeline in &script.pipelines {
+        check_pipeline(pipeline, &mut report);
+        for command in &pipeline.commands {
+            let argv = command.effective_argv();
+            if argv.first() == Some(&"cd") {
+                cwd = Some(argv.get(1).unwrap_or(&"~").to_string());
+            }
+            check_command(command, cwd.as_deref(), &mut report);
+            for redirect in &command.redirects {
+                check_redirect(redirect, file_exists, &mut report);
+            }
+        }
+    }
+    report
+}
+
+fn check_pipeline(pipeline: &Pipeline, report: &mut SafetyReport) {
+    let mut downloading = false;
+    for command in &pipeline.commands {
+        let argv = command.effective_argv();
+        let Some(name) = argv.first().map(|a| base_name(a)) else { continue };
+
+        if downloading && INTERPRETERS.contains(&name) {
+            report.flag(Severity::Danger, RiskClass::Network, format!("runs a downloaded script with {} without showing it first", name));
+        }
+        downloading |= DOWNLOADERS.contains(&name);
+    }
+
+    // find / | xargs rm -rf: the paths to delete only show up when the command runs
+    for (i, command) in pipeline.commands.iter().enumerate() {
+        let argv = command.effective_argv();
+        if argv.first().map(|a| base_name(a)) != Some("rm") || !command.wrappers().iter().any(|w| base_name(w) == "xargs") {
+            continue;
+        }
+        let source = i.checked_sub(1).map(|p| pipeline.commands[p].effective_argv()).unwrap_or_default();
+        let roots: Vec<&str> = match source.first().map(|a| base_name(a)) {
+            Some("find" | "ls") => source[1..].iter().take_while(|a| !a.starts_with('-')).copied().collect(),
+            _ => vec![],
+        };
+        match roots.iter().find(|r| is_critical_path(r)) {
+            Some(root) => report.flag(Severity::Danger, RiskClass::Destructive, format!("xargs rm deletes what {} lists under {}", base_name(source[0]), root)),
+            None => report.flag(Severity::Caution, RiskClass::Destructive, "xargs rm deletes every path in its input".to_string()),
+        }
+    }
+
+    // sh -c "$(curl ..)" and bash <(curl ..)
+    for command in &pipeline.commands {
+        let argv = command.effective_argv();
+        let Some(name) = argv.first().map(|a| base_name(a)) else { continue };
+        if !INTERPRETERS.contains(&name) {
+            continue;
+        }
+        let substituted_download = command.words.iter().flat_map(|w| w.substitutions.iter()).any(|body| {
+            parse(body).commands().any(|c| c.effective_argv().first().is_some_and(|a| DOWNLOADERS.contains(&base_name(a))))
+        });
+        if substituted_download {
+            report.flag(Severity::Danger, RiskClass::Network, format!("runs a downloaded script with {} without showing it first", name));
+        }
+    }
+}
+
+/// `cwd` is the directory the script changed into, so `cd / && rm -rf *` is caught too.
+fn check_command(command: &SimpleCommand, cwd: Option<&str>, report: &mut SafetyReport) {
+    if command.is_privileged() {
+        report.flag(Severity::Caution, RiskClass::NeedsSudo, "runs with root privileges".to_string());
+    }
+
+    let argv = command.effective_argv();
+    let Some(name) = argv.first().map(|a| base_name(a)) else { return };
+    let args = &argv[1..];
+
+    match name {
+        "rm" => check_rm(args, cwd, report),
+        "dd" => {
+            for target in args.iter().filter_map(|a| a.strip_prefix("of=")) {
+                if is_device(target) {
+                    report.flag(Severity::Danger, RiskClass::Destructive, format!("dd writes directly to the device {}", target));
+                }
+            }
+        }
+        "chmod" | "chown" | "chgrp" => check_permissions(name, args, report),
+        "find" if args.contains(&"-delete") || command.exec_commands().iter().any(|c| c.effective_argv().first().is_some_and(|a| base_name(a) == "rm")) => {
+            let roots: Vec<&str> = args.iter().take_while(|a| !a.starts_with('-')).copied().collect();
+            match roots.iter().map(|r| resolve(r, cwd)).find(|r| is_critical_path(r)) {
+                Some(root) => report.flag(Severity::Danger, RiskClass::Destructive, format!("find deletes matches under {}", root)),
+                None => report.flag(Severity::Caution, RiskClass::Destructive, "find deletes every match".to_string()),
+            }
+        }
+        _ if FORMATTERS.contains(&name) || name.starts_with("mkfs.") => {
+            let targets: Vec<&str> = args.iter().filter(|a| !a.starts_with('-')).copied().collect();
+            report.flag(Severity::Danger, RiskClass::Destructive, format!("{} erases the filesystem on {}", name, describe_targets(&targets)));
+        }
+        _ if PARTITIONERS.contains(&name) => {
+            report.flag(Severity::Caution, RiskClass::Destructive, format!("{} can rewrite partition tables", name));
+        }
+        _ => {}
+    }
+}
+
+fn check_rm(args: &[&str], cwd: Option<&str>, report: &mut SafetyReport) {
+    let mut recursive = false;
+    let mut force = false;
+    let mut targets = vec![];
+    let mut options_done = false;
+
+    for arg in args {
+        if *arg == "{}" {
+            continue; // a match of find -exec or xargs -I, judged there
+        } else if options_done || !arg.starts_with('-') || *arg == "-" {
+            targets.push(*arg);
+        } else if *arg == "--" {
+            options_done = true;
+        } else if *arg == "--no-preserve-root" {
+            report.flag(Severity::Danger, RiskClass::Destructive, "rm is told to ignore the protection for /".to_string());
+        } else if let Some(long) = arg.strip_prefix("--") {
+            recursive |= long == "recursive";
+            force |= long == "force";
+        } else {
+            recursive |= arg.contains('r') || arg.contains('R');
+            force |= arg.contains('f');
+        }
+    }
+
+    let flags = match (recursive, force) {
+        (true, true) => "rm -rf",
+        (true, false) => "rm -r",
+        _ => "rm",
+    };
+    let critical: Vec<&str> = targets.iter().map(|t| resolve(t, cwd)).filter(|t| is_critical_path(t)).collect();
+    if recursive && !critical.is_empty() {
+        report.flag(Severity::Danger, RiskClass::Destructive, format!("{} on {}", flags, critical.join(", ")));
+    } else if !targets.is_empty() {
+        report.flag(Severity::Caution, RiskClass::Destructive, format!("{} deletes {}", flags, describe_targets(&targets)));
+    }
+}
+
+fn check_permissions(name: &str, args: &[&str], report: &mut SafetyReport) {
+    let recursive = args.iter().any(|a| *a == "--recursive" || (a.starts_with('-') && !a.starts_with("--") && a.contains('R')));
+    let operands: Vec<&str> = args.iter().filter(|a| !a.starts_with('-')).copied().collect();
+    let world_writable = name == "chmod" && operands.first().is_some_and(|mode| {
+        *mode == "777" || *mode == "0777" || *mode == "666" || mode.contains("o+w") || mode.contains("a+w") || *mode == "a=rwx"
+    });
+    let paths = if operands.is_empty() { &[][..] } else { &operands[1..] };
+    let critical: Vec<&str> = paths.iter().filter(|p| is_critical_path(p)).copied().collect();
+
+    if recursive && !critical.is_empty() {
+        report.flag(Severity::Danger, RiskClass::Destructive, format!("{} -R on {}", name, critical.join(", ")));
+    } else if recursive && world_writable {
+        report.flag(Severity::Danger, RiskClass::ModifiesFiles, format!("chmod -R makes everything under {} world-writable", describe_targets(paths)));
+    } else if world_writable {
+        report.flag(Severity::Caution, RiskClass::ModifiesFiles, format!("chmod makes {} world-writable", describe_targets(paths)));
+    }
+}
+
+fn check_redirect(redirect: &Redirect, file_exists: &dyn Fn(&str) -> bool, report: &mut SafetyReport) {
+    let op = redirect.op.trim_start_matches(|c: char| c.is_ascii_digit());
+    let target = redirect.target.as_str();
+    let writes = matches!(op, ">" | ">>" | ">|" | "&>" | "&>>" | "<>") || (op == ">&" && !target.chars().all(|c| c.is_ascii_digit() || c == '-'));
+    if !writes {
+        return;
+    }
+
+    if is_device(target) {
+        report.flag(Severity::Danger, RiskClass::Destructive, format!("writes directly to the device {}", target));
+    } else if redirect.overwrites() && file_exists(target) {
+        report.flag(Severity::Caution, RiskClass::ModifiesFiles, format!("overwrites the existing file {}", target));
+    }
+}
+
+/// The name of a `name(){ name|name& }` style function, if the command defines one.
+fn fork_bomb(command: &str) -> Option<String> {
+    let compact: String = command.chars().filter(|c| !c.is_whitespace()).collect();
+    let mut search = compact.as_str();
+    while let Some(at) = search.find("(){") {
+        let name: String = search[..at]
+            .chars()
+            .rev()
+            .take_while(|c| !matches!(c, ';' | '&' | '|' | '(' | ')' | '{' | '}'))
+            .collect::<Vec<char>>()
+            .into_iter()
+            .rev()
+            .collect();
+        let name = name.trim_start_matches("function").to_string();
+        let body = &search[at + 3..];
+        let body = &body[..body.find('}').unwrap_or(body.len())];
+        if !name.is_empty() && body.contains(&format!("{}|{}&", name, name)) {
+            return Some(name);
+        }
+        search = &search[at + 3..];
+    }
+    None
+}
+
+fn is_device(path: &str) -> bool {
+    path.starts_with("/dev/") && !HARMLESS_DEVICES.contains(&path) && !path.starts_with("/dev/fd/")
+}
+
+/// `/`, the home directory and the top-level system directories, also as `/*` or `~/`.
+fn is_critical_path(path: &str) -> bool {
+    let mut path = path;
+    loop {
+        let trimmed = path.strip_suffix("/*").or_else(|| path.strip_suffix("/.")).or_else(|| path.strip_suffix('/'));
+        match trimmed {
+            Some(rest) => path = rest,
+            None => break,
+        }
+    }
+    let path = if path.is_empty() { "/" } else { path };
+    CRITICAL_PATHS.contains(&path)
+}
+
+/// Maps the current directory and everything in it to `cwd` when the script changed into one.
+fn resolve<'a>(path: &'a str, cwd: Option<&'a str>) -> &'a str {
+    match cwd {
+        Some(cwd) if matches!(path, "." | "./" | "*" | "./*" | ".*") => cwd,
+        _ => path,
+    }
+}
+
+fn describe_targets(targets: &[&str]) -> String {
+    if targets.is_empty() { "its arguments".to_string() } else { targets.join(", ") }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(command: &str) -> SafetyReport {
+        analyze_with(command, &|_| false)
+    }
+
+    fn is_dangerous(command: &str) -> bool {
+        report(command).is_high_risk()
+    }
+
+    #[test]
+    fn rm_on_critical_paths() {
+        assert!(is_dangerous("rm -rf /"));
+        assert!(is_dangerous("rm -r --force ~/"));
+        assert!(is_dangerous("sudo rm -rf /etc"));
+        assert!(is_dangerous("cd / && rm -rf *"));
+        assert!(is_dangerous("rm --no-preserve-root -rf /tmp/x"));
+        assert!(!is_dangerous("rm -rf build"));
+        assert!(!report("rm -rf build").findings.is_empty());
+        assert!(report("ls -la /").findings.is_empty());
+    }
+
+    #[test]
+    fn downloaded_scripts() {
+        assert!(is_dangerous("curl -fsSL https://example.com/install.sh | sh"));
+        assert!(is_dangerous("wget -qO- https://example.com/x | sudo bash"));
+        assert!(is_dangerous("sh -c \"$(curl -fsSL https://example.com/install.sh)\""));
+        assert!(!is_dangerous("curl -fsSL https://example.com/data.json | jq ."));
+    }
+
+    #[test]
+    fn fork_bombs() {
+        assert!(is_dangerous(":(){ :|:& };:"));
+        assert!(is_dangerous("bomb() { bomb | bomb & }; bomb"));
+        assert!(!is_dangerous("f() { echo hi; }; f"));
+    }
+
+    #[test]
+    fn permissions() {
+        assert!(is_dangerous("chmod -R 777 /"));
+        assert!(is_dangerous("chmod -R 777 ./public"));
+        assert!(is_dangerous("sudo chown -R nobody /usr"));
+        assert!(!is_dangerous("chmod 777 notes.txt"));
+        assert!(!report("chmod 777 notes.txt").findings.is_empty());
+        assert!(report("chmod 644 notes.txt").findings.is_empty());
+    }
+
+    #[test]
+    fn block_devices() {
+        assert!(is_dangerous("dd if=/dev/zero of=/dev/sda bs=1M"));
+        assert!(is_dangerous("cat image.iso > /dev/sdb"));
+        assert!(is_dangerous("mkfs.ext4 /dev/sdb1"));
+        assert!(!is_dangerous("echo hi > /dev/null"));
+        assert!(report("ls 2>/dev/null").findings.is_empty());
+    }
+
+    #[test]
+    fn overwritten_files() {
+        let report = analyze_with("echo hi > notes.txt", &|path| path == "notes.txt");
+        assert_eq!(report.findings.len(), 1);
+        assert!(analyze_with("echo hi >> notes.txt", &|_| true).findings.is_empty());
+    }
+
+    #[test]
+    fn inline_scripts() {
+        assert!(is_dangerous("bash -c 'rm -rf /'"));
+        assert!(is_dangerous("sudo sh -c 'dd if=/dev/zero of=/dev/sda'"));
+        assert!(is_dangerous("su -c 'rm -rf /'"));
+        assert!(is_dangerous("su root -c 'rm -rf /'"));
+        assert!(is_dangerous("eval 'rm -rf /'"));
+        assert!(is_dangerous("bash -ec \"chmod -R 777 /\""));
+        assert!(is_dangerous("env FOO=1 bash -c 'bash -c \"rm -rf ~\"'"));
+        assert!(report("bash -c 'echo hi'").findings.is_empty());
+        assert!(report("bash script.sh -c").findings.is_empty());
+    }
+
+    #[test]
+    fn xargs_and_find_exec() {
+        assert!(is_dangerous("find / | xargs rm -rf"));
+        assert!(is_dangerous("find / -name '*.log' -print0 | xargs -0 rm -f"));
+        assert!(is_dangerous("find / -exec rm -rf {} +"));
+        assert!(is_dangerous(r"find . -exec sh -c 'rm -rf /' \;"));
+        assert!(!report("find . -name '*.tmp' | xargs rm").findings.is_empty());
+        assert!(!report(r"find . -name '*.tmp' -exec rm {} \;").findings.is_empty());
+        assert!(!is_dangerous(r"find . -name '*.tmp' -exec rm {} \;"));
+        assert!(report("find . -name '*.rs' | xargs wc -l").findings.is_empty());
+    }
+
+    #[test]
+    fn privileges_and_parsing() {
+        assert_eq!(report("sudo apt update").risk(), Some(RiskClass::NeedsSudo));
+        assert!(!report("echo 'unterminated").findings.is_empty());
+    }
+}
@@ -1,17 +1,33 @@
+use crate::shell::audit::{AuditEntry, AuditLog, Decision};
+use crate::shell::context::SystemContext;
+use crate::shell::editor;
+use crate::shell::exec::{self, ExecOptions, ExecOutcome, Shell, ShellKind};
+use crate::shell::explain::{explain_query, explanation_schema, segment, Explanation, Segment};
+use crate::shell::git::GitContext;
+use crate::shell::listing::{list_dir, ListingConfig};
+use crate::shell::manual::{undocumented_flags, Manual, UndocumentedFlag};
+use crate::shell::policy::{Policy, PolicyViolation};
+use crate::shell::proposal::{ClarifyingQuestion, CommandProposal, Plan, RiskClass, PLAN_FORMAT_PROMPT, PLAN_SCHEMA, PROPOSAL_FORMAT_PROMPT, PROPOSAL_SCHEMA};
+use crate::shell::safety::{analyze, SafetyReport, Severity};
+use crate::shell::validate::{install_hint, missing_binaries, syntax_error};
 use crate::utils::color::{animate_text, colorify};
-use crate::utils::model_tool::{ChatRole, ChatWrapper, ModelContainer, ModelInstance};
-use crate::utils::utils::get_sys_threads;
-use std::error::Error;
-use std::io::Write;
-use std::process::{Command, Stdio};
+use crate::utils::config::Config;
+use crate::utils::grammar::{allow_literals, Constraint, GrammarError};
+use crate::utils::model_tool::{ChatRole, ChatWrapper, ContextOverflowError, Generation, ModelContainer, ModelInstance, StopReason};
+use crate::utils::sampling::SamplingConfig;
+use llama_cpp_2::token::LlamaToken;
+use std::io::{BufRead, BufReader, IsTerminal, Write};
 use std::sync::{Arc, Mutex};
 use std::thread::sleep;
 use std::time::Duration;
-use std::{env, fs, thread};
+use std::path::Path;
+use std::{env, thread};
 use std::fs::File;
 
 pub enum ModelMode {
     CMD,
+    PLAN,
+    EXPLAIN,
     CODE,
     MATH,
     WRITING,
@@ -19,9 +35,49 @@ pub enum ModelMode {
 }
 
 impl ModelMode {
+    pub fn from_name(name: &str) -> Option<ModelMode> {
+        match name.to_lowercase().as_str() {
+            "cmd" | "bash" => Some(ModelMode::CMD),
+            "plan" => Some(ModelMode::PLAN),
+            "explain" => Some(ModelMode::EXPLAIN),
+            "code" => Some(ModelMode::CODE),
+            "math" => Some(ModelMode::MATH),
+            "writing" => Some(ModelMode::WRITING),
+            "general" => Some(ModelMode::GENERAL),
+            _ => None,
+        }
+    }
+
+    /// CMD, PLAN, EXPLAIN and CODE stay close to greedy so commands and code are reproducible, while
+    /// WRITING and GENERAL get enough temperature to avoid repetitive prose.
+    pub fn default_sampling(&self) -> SamplingConfig {
+        let base = SamplingConfig::default();
+        match *self {
+            ModelMode::CMD | ModelMode::PLAN | ModelMode::EXPLAIN => SamplingConfig { temperature: 0.1, top_k: 20, top_p: 0.9, ..base },
+            ModelMode::CODE => SamplingConfig { temperature: 0.2, top_k: 40, top_p: 0.9, ..base },
+            ModelMode::MATH => SamplingConfig { temperature: 0.3, top_k: 40, top_p: 0.9, ..base },
+            ModelMode::WRITING => SamplingConfig {
+                temperature: 0.9,
+                top_p: 0.95,
+                min_p: 0.05,
+                repeat_penalty: 1.1,
+                ..base
+            },
+            ModelMode::GENERAL => SamplingConfig {
+                temperature: 0.6,
+                top_p: 0.95,
+                min_p: 0.05,
+                repeat_penalty: 1.05,
+                ..base
+            },
+        }
+    }
+
     fn get_system_prompt(&self) -> &str {
         match *self {
-            ModelMode::CMD => "You are a bash command generator assistant for a linux systems. Output only raw bash commands without any explanations, markdown formatting, code blocks, or backticks - each response should be immediately executable in a terminal. Chain multiple commands with && when steps need to be sequential, use ; for independent commands that can run in any order, and default to absolute paths unless working directory is specified. Prefer single-line solutions over multiple lines when possible, using proper command escaping and quoting when needed. When provided, context will appear as 'WD: {path} FILES: {file1, file2, ...}' - use this information only when relevant to command construction. For directory-wide operations, use '.' instead of iterating through files, and respect the current working directory when provided. When details are missing, choose the most common/logical default options, use sudo when operations require elevated privileges, prefer widely available core utilities over optional packages, and include necessary package installation commands if specialized tools are required. Include basic error checking in critical operations, use -e flag with shell commands when appropriate, and add safeguards for destructive operations. Example context format: WD: /home/user/documents FILES: report.pdf, notes.txt, images/. If you require any clarification of the user's system or anything else, ask the user the question before generating the command.",
+            ModelMode::CMD => "You are a shell command generator assistant for a linux systems. Output only raw shell commands without any explanations, markdown formatting, code blocks, or backticks - each response should be immediately executable in a terminal. Chain multiple commands with && when steps need to be sequential, use ; for independent commands that can run in any order, and default to absolute paths unless working directory is specified. Prefer single-line solutions over multiple lines when possible, using proper command escaping and quoting when needed. When provided, context will appear as 'WD: {path} FILES: {file1, file2, ...}' - use this information only when relevant to command construction. For directory-wide operations, use '.' instead of iterating through files, and respect the current working directory when provided. When details are missing, choose the most common/logical default options, use sudo when operations require elevated privileges, prefer widely available core utilities over optional packages, and include the installation command for the system's package manager if a specialized tool is not on PATH. Details about the system appear once as 'SYSTEM: {os}; package manager: ...; shell: ...; tools on PATH: ...' - rely on them instead of guessing the distribution. When a command comes back with 'MANUAL {binary}:' excerpts from the local manual pages or --help output, use only the options they document. Include basic error checking in critical operations, use -e flag with shell commands when appropriate, and add safeguards for destructive operations. Example context format: WD: /home/user/documents FILES: report.pdf, notes.txt, images/. If you require any clarification of the user's system or anything else, ask the user the question before generating the command.",
+            ModelMode::PLAN => "You are a shell assistant that breaks a task into an ordered plan of shell commands for a linux system. Each step is shown to the user, approved and run on its own, so give every step a single purpose and a command that is immediately executable in a terminal, without markdown formatting, code blocks, or backticks. Steps run one after another as separate shell processes in the same working directory, so changes like cd, export or source do not carry over to later steps: use paths relative to the working directory or repeat the needed setup within the step, e.g. call .venv/bin/pip instead of activating a virtual environment. When provided, context will appear as 'WD: {path} FILES: {file1, file2, ...}' - use this information only when relevant. When details are missing, choose the most common/logical default options, use sudo only when operations require elevated privileges, and prefer widely available core utilities over optional packages unless the 'SYSTEM: ...' details list a better tool on PATH. Keep plans short, usually between two and eight steps, and add a step that verifies the result when it is cheap to do so.",
+            ModelMode::EXPLAIN => "You are an assistant that explains shell commands on a linux system to the user who is about to run them. The command comes split into numbered SEGMENTS - binaries, their flags and arguments, pipes, operators and redirections - and may be followed by 'MANUAL {binary}:' excerpts from the local manual pages or --help output; prefer them over what you remember, since they describe the versions installed here. Respond with a single JSON object: {\"summary\": what the whole command does in one or two sentences, \"explanations\": [one short explanation for every segment, in the same order]}. Explain each segment in the context of the command, e.g. what a flag changes for its binary, what is passed through a pipe or where a redirection sends the output, and point out anything destructive, irreversible or surprising. Do not suggest other commands.",
             ModelMode::CODE => "You are a highly intelligent and capable coding assistant whose responses must strictly adhere to providing only working, executable code that directly solves the user's request. The code should include helpful comments to explain key functionality and important logic, default to Python unless another language is more suitable (e.g., JavaScript for frontend web functionality or SQL for database queries), and be structured following best practices for readability and maintainability. Avoid adding text, markdown formatting, or code block markers before or after the code, and do not include follow-up questions or additional explanations. Use clear, descriptive variable names, consistent formatting, and error handling where appropriate. If multiple files are required, separate them with a single line containing the filename in comments. Begin coding immediately upon receiving a request, ensure the code is complete, and end the response without any concluding remarks or markdown formatting such as ```. If you are asked general questions, provide code only. Do not provide any explanations.",
             ModelMode::MATH => "You are a mathematical problem-solving assistant. Your purpose is to provide clear, step-by-step solutions to mathematical problems. Always show your complete work and calculations, explaining your mathematical reasoning throughout the process. Use clear mathematical notation and formatting while double-checking all calculations before providing final answers. For word problems, begin by identifying key variables and constraints, then clearly state the relevant formulas and theorems being applied. When multiple solution methods exist, explain your chosen approach and any assumptions made. Create visual aids like diagrams or graphs for complex problems when helpful. Point out common pitfalls or areas where students often make mistakes, ensuring your explanations help build understanding. Each response should conclude with a clear final answer that you've verified satisfies the original problem constraints.",
             ModelMode::WRITING => "You are a versatile writing assistant focused on producing high-quality written content across various formats and styles. Adapt your writing style to match the requested format and tone while maintaining consistent voice and perspective throughout. Employ varied sentence structure and vocabulary appropriate to the target audience, ensuring logical flow with smooth transitions between ideas. Support main points with specific examples and evidence, paying careful attention to grammar, punctuation, and formatting. Avoid clichés and redundant language while crafting engaging introductions and meaningful conclusions. Structure content with clear paragraphs and sections when appropriate, always addressing the main topic while weaving in relevant supporting details. Consider the context and purpose of each writing task, proofreading for clarity, coherence, and impact before delivering the final product.",
@@ -30,6 +86,47 @@ impl ModelMode {
     }
 }
 
+const SAVE_SENTINEL: &str = "<SAVE>";
+const EXIT_SENTINEL: &str = "<EXIT>";
+
+/// What the model answered with: regular output or one of the control sentinels.
+pub enum Intent {
+    Answer(String),
+    Save,
+    Exit,
+}
+
+impl Intent {
+    fn from_generation(generation: &Generation) -> Intent {
+        match generation.stop_sequence.as_deref() {
+            Some(SAVE_SENTINEL) => Intent::Save,
+            Some(EXIT_SENTINEL) => Intent::Exit,
+            _ => Intent::Answer(generation.text.clone()),
+        }
+    }
+}
+
+#[derive(Clone)]
+enum StepStatus {
+    NotRun,
+    Skipped,
+    Succeeded(ExecOutcome),
+    Failed(Option<ExecOutcome>), // none when the command could not be started
+}
+
+/// One of several CMD answers to the same query, see `Shellm::rank_candidates`.
+struct Candidate {
+    proposal: CommandProposal,
+    tokens: Vec<LlamaToken>, // the generation the proposal was parsed from
+    score: f32, // mean log-probability per token
+}
+
+impl Candidate {
+    fn single(proposal: CommandProposal) -> Self {
+        Candidate { proposal, tokens: vec![], score: 0.0 }
+    }
+}
+
 pub struct ModelStatus(pub bool);
 
 #[derive(Debug)]
@@ -43,26 +140,52 @@ pub struct Shellm<'a> {
     query: ChatWrapper,
     save_path: Option<String>,
     program_out_file: Option<String>,
+    policy: Policy,
+    audit: AuditLog,
+    model_path: String,
+    last_query: String,
+    repair_attempts: u32,
+    last_generation: Vec<LlamaToken>, // the answer the history ends with
+    exec: ExecOptions,
+    listing: ListingConfig,
+    git_context: bool,
+    manual_grounding: bool,
+    candidates: u32,
+    print_only: bool,
+    exit_code: i32,
 }
 
 impl<'a> Shellm<'a> {
     pub fn new(
         query: Option<String>,
         model_mode: ModelMode,
-        max_gen: i32,
         shell_mode: bool,
         load_session: Option<String>,
         save_path: Option<String>,
         program_out_file: Option<String>,
         container: &'a ModelContainer,
-        ctx_window: u32,
+        config: &Config,
+        policy: Policy,
     ) -> Result<Self, ShellCreationError> {
-        let threads = Some((get_sys_threads() * 7 / 8) as i32);
-        let instance = if let Some(load_path) = load_session {
+        let threads = Some(config.threads);
+        let threads_batch = config.threads_batch;
+        let ctx_window = config.ctx_window;
+        let max_gen = config.max_gen.min(ctx_window as i32 / 2); // leave room for the history
+        if max_gen < config.max_gen {
+            eprintln!("{}", colorify(&format!("max_gen {} is capped at {}, half of ctx_window", config.max_gen, max_gen), 247., 200., 89.));
+        }
+        let audit = match AuditLog::new(config.audit_log.as_deref()) {
+            Ok(audit) => audit,
+            Err(e) => {
+                eprintln!("{}", e.0);
+                return Err(ShellCreationError);
+            }
+        };
+        let mut instance = if let Some(load_path) = load_session {
             match ModelInstance::load_from_session(
                 container,
                 threads,
-                None,
+                threads_batch,
                 ctx_window,
                 load_path.clone(),
             ) {
@@ -73,19 +196,44 @@ impl<'a> Shellm<'a> {
                 }
             }
         } else {
-            ModelInstance::new(container, threads, None, ctx_window)
+            ModelInstance::new(container, threads, threads_batch, ctx_window)
         };
+        instance.set_sampling(config.sampling.apply(model_mode.default_sampling()));
+        instance.set_overflow_policy(config.overflow_policy);
+        instance.set_stop_sequences(vec![SAVE_SENTINEL.to_string(), EXIT_SENTINEL.to_string()]);
 
-        let sys_prompt = Self::augment_sys_prompt(model_mode.get_system_prompt().to_string());
+        let mut sys_prompt = model_mode.get_system_prompt().to_string();
+        let format = match model_mode {
+            ModelMode::CMD => Some((PROPOSAL_FORMAT_PROMPT, PROPOSAL_SCHEMA)),
+            ModelMode::PLAN => Some((PLAN_FORMAT_PROMPT, PLAN_SCHEMA)),
+            _ => None,
+        };
+        let exec = ExecOptions {
+            shell: Shell::resolve(config.shell.as_deref()),
+            timeout: Some(Duration::from_secs(config.command_timeout)).filter(|t| !t.is_zero()),
+            pass_stdin: config.pass_stdin,
+        };
+        if let Some((format_prompt, schema)) = format {
+            sys_prompt.push_str(&Self::shell_prompt(&exec.shell));
+            sys_prompt.push_str(format_prompt);
+            sys_prompt.push(' ');
+            sys_prompt.push_str(&SystemContext::load(&exec.shell).render());
+            let schema = serde_json::from_str(schema).unwrap();
+            let grammar = Constraint::JsonSchema(schema).to_gbnf().unwrap();
+            instance.set_constraint(Some(&Constraint::Gbnf(Self::allow_sentinels(&grammar)))).unwrap();
+        }
+        if config.git_context && matches!(model_mode, ModelMode::CMD | ModelMode::PLAN | ModelMode::CODE) {
+            sys_prompt.push_str(" Inside a git repository the query ends with 'GIT: ...', describing the branch, its upstream, uncommitted changes, recent commit subjects and remotes - use it when the request is about the repository.");
+        }
+        let sys_prompt = Self::augment_sys_prompt(sys_prompt);
+        instance.pin_system_prompt(&sys_prompt);
 
         let mut init_query = ChatWrapper::new();
         init_query.add_dialogue(ChatRole::System, &sys_prompt);
 
-        if let Some(mut query) = query {
-            query = match model_mode {
-                ModelMode::CMD => Self::augment_query(query),
-                _ => query,
-            };
+        let last_query = query.clone().unwrap_or_default();
+        if let Some(query) = query {
+            let query = Self::augment_query(query, &model_mode, &config.listing, config.git_context, &policy);
             init_query.add_dialogue(ChatRole::User, &query);
         }
 
@@ -98,9 +246,46 @@ impl<'a> Shellm<'a> {
             query: init_query,
             save_path,
             program_out_file,
+            policy,
+            audit,
+            model_path: config.model_path.clone().unwrap_or_default(),
+            last_query,
+            repair_attempts: config.repair_attempts,
+            last_generation: vec![],
+            exec,
+            listing: config.listing.clone(),
+            git_context: config.git_context,
+            manual_grounding: config.manual_grounding,
+            candidates: config.candidates,
+            print_only: false,
+            exit_code: 0,
         })
     }
 
+    /// Tells the model which shell its commands run in.
+    fn shell_prompt(shell: &Shell) -> String {
+        let mut prompt = format!(" Commands are run with {} -c, so use its syntax.", shell.name());
+        if shell.kind == ShellKind::Fish {
+            prompt.push_str(" In fish, command substitution is (cmd) instead of $(cmd), variables are set with set NAME value instead of NAME=value, && and || need fish 3 or later, and backslashes escape ' and \\ inside single quotes.");
+        }
+        prompt
+    }
+
+    /// Constrains every answer to `constraint`; the session sentinels stay available.
+    pub fn set_output_constraint(&mut self, constraint: &Constraint) -> Result<(), GrammarError> {
+        let grammar = constraint.to_gbnf()?;
+        self.instance.set_constraint(Some(&Constraint::Gbnf(Self::allow_sentinels(&grammar))))
+    }
+
+    /// Prints generated commands instead of offering to run them, see `print_proposal`.
+    pub fn set_print_only(&mut self, print_only: bool) {
+        self.print_only = print_only;
+    }
+
+    fn allow_sentinels(grammar: &str) -> String {
+        allow_literals(grammar, &[SAVE_SENTINEL, EXIT_SENTINEL])
+    }
+
     fn print_shell_start_msg() {
         println!(
             "{}",
@@ -126,34 +311,100 @@ impl<'a> Shellm<'a> {
         println!();
     }
 
-    fn process_query(&mut self) -> String {
+    fn process_query(&mut self) -> Option<Intent> {
         let model_status = ModelStatus(false);
         let state = Arc::new(Mutex::new(model_status));
-        self.loading_indicator(Arc::clone(&state));
+        // stdout belongs to the generated command when printing only
+        if !self.print_only {
+            self.loading_indicator(Arc::clone(&state));
+        }
 
         let toks = self
             .instance
-            .chat_query(&self.query, self.max_gen, false, false, || {}).unwrap();
-        let result = self.instance.decode_tokens(toks, false);
+            .chat_query(&self.query, self.max_gen, false, false, || {});
 
         sleep(Duration::from_millis(50));
         state.lock().unwrap().0 = true;
 
-        result
+        match toks {
+            Ok(toks) => {
+                let generation = toks.unwrap();
+                self.last_generation = generation.tokens.clone();
+                Some(self.finish_generation(&generation))
+            }
+            Err(e) => {
+                sleep(Duration::from_millis(60)); // let the indicator clear its line
+                Self::report_overflow(e);
+                None
+            }
+        }
     }
 
-    fn stream_query(&mut self) -> String {
+    fn stream_query(&mut self) -> Option<Intent> {
         let model_status = ModelStatus(false);
         let state = Arc::new(Mutex::new(model_status));
         self.loading_indicator(Arc::clone(&state));
 
+        let indicator = Arc::clone(&state);
         let toks = self
             .instance
-            .chat_query(&self.query, self.max_gen, false, true, move || { state.clone().lock().unwrap().0 = true; }).unwrap();
+            .chat_query(&self.query, self.max_gen, false, true, move || { indicator.lock().unwrap().0 = true; });
 
-        let result = self.instance.decode_tokens(toks, false);
+        match toks {
+            Ok(toks) => Some(self.finish_generation(&toks.unwrap())),
+            Err(e) => {
+                state.lock().unwrap().0 = true;
+                sleep(Duration::from_millis(60));
+                Self::report_overflow(e);
+                None
+            }
+        }
+    }
 
-        result
+    /// Like `process_query`, with `candidates` answers instead of one.
+    fn process_candidates(&mut self) -> Option<Vec<Generation>> {
+        let state = Arc::new(Mutex::new(ModelStatus(false)));
+        if !self.print_only {
+            self.loading_indicator(Arc::clone(&state));
+        }
+
+        let generations = self.instance.chat_candidates(&self.query, self.max_gen, self.candidates);
+
+        sleep(Duration::from_millis(50));
+        state.lock().unwrap().0 = true;
+
+        match generations {
+            Ok(generations) => Some(generations),
+            Err(e) => {
+                sleep(Duration::from_millis(60)); // let the indicator clear its line
+                Self::report_overflow(e);
+                None
+            }
+        }
+    }
+
+    fn finish_generation(&self, generation: &Generation) -> Intent {
+        let note = match generation.stop_reason {
+            StopReason::Budget => Some("Output truncated: reached the max token limit."),
+            StopReason::ContextFull => Some("Output truncated: the context window is full."),
+            _ => None,
+        };
+        if let Some(note) = note {
+            eprintln!("{}", colorify(note, 150., 150., 150.));
+        }
+        Intent::from_generation(generation)
+    }
+
+    fn report_overflow(e: ContextOverflowError) {
+        eprintln!(
+            "{}",
+            colorify(
+                &format!("Context window is full ({} of {} tokens needed). Start a new session or change overflow_policy.", e.required, e.available),
+                247.,
+                89.,
+                89.
+            )
+        );
     }
 
     fn clean_code_output(content: String) -> String {
@@ -170,15 +421,113 @@ impl<'a> Shellm<'a> {
         result
     }
 
-    fn exec_bash_cmd(cmd: String) {
-        let mut output = String::new();
+    /// Shows the chosen candidate and runs it once confirmed, after any edits the user makes.
+    /// With several candidates, a number key switches to another one. Returns how it ended, or
+    /// none when it was blocked, aborted or could not be started.
+    fn exec_bash_cmd(&mut self, candidates: &[Candidate], chosen: &mut usize, attempt: u32) -> Option<ExecOutcome> {
+        let mut proposal = &candidates[*chosen].proposal;
+        let mut command = proposal.command.clone();
+        loop {
+            let edited = command != proposal.command;
+            let (report, verdict, review) = self.review(&command);
+            let mut entry = self.audit_entry(&command, &report);
+            entry.attempt = attempt;
+            entry.risk = proposal.risk.to_string();
+            if edited {
+                entry.edited_from = Some(proposal.command.clone());
+            }
+
+            if edited {
+                println!("{}", colorify("Modified command:", 150., 150., 150.));
+                println!();
+                println!("      {}", colorify(&command, 59., 235., 115.));
+                println!();
+                for warning in self.command_warnings(&command) {
+                    println!("{}", colorify(&format!("Warning: {}", warning), 247., 200., 89.));
+                }
+                print!("{}", review);
+            } else {
+                let mut output = proposal.render();
+                output.push('\n');
+                output.push_str(&review);
+                Self::animate_output(&output);
+            }
+            let pick = if candidates.len() > 1 {
+                print!("{}", Self::render_candidates(candidates, *chosen));
+                format!("[1-{}] Pick ", candidates.len())
+            } else {
+                String::new()
+            };
 
-        output.push_str(&format!("{}\n", colorify("Generated command:", 150., 150., 150.)));
-        output.push_str("\n");
-        output.push_str(&format!("      {}\n", colorify(&cmd, 59., 235., 115.)));
-        output.push_str("\n");
-        output.push_str(&format!("{}", colorify("Cannot guarantee that the command is 'safe.'\nVerify the command if you're uncertain.\n", 150., 150., 150.)));
+            if verdict.is_err() {
+                entry.decision = Decision::Blocked;
+                self.record(&entry);
+                print!("     [M]odify {}[A]bort (default) ", pick);
+            } else {
+                print!("     [E]xecute [M]odify {}[A]bort (default) ", pick);
+            }
+            let answer = self.read_answer();
+            if let Some(index) = answer.parse::<usize>().ok().filter(|i| candidates.len() > 1 && (1..=candidates.len()).contains(i)) {
+                // the history should hold the command the user went with
+                if !self.instance.replace_generation(&candidates[*chosen].tokens, &candidates[index - 1].tokens) {
+                    eprintln!("{}", colorify("The history still holds the earlier command, the model will not know about this pick", 247., 200., 89.));
+                }
+                *chosen = index - 1;
+                proposal = &candidates[*chosen].proposal;
+                command = proposal.command.clone();
+                continue;
+            }
+            if answer == "m" {
+                command = editor::edit_command(&command, self.exec.pass_stdin).unwrap_or(command);
+                continue;
+            }
+            if verdict.is_err() {
+                return None;
+            }
 
+            let confirmed = answer == "e" && {
+                // the model's own risk only describes the command it proposed
+                let high_risk = report.is_high_risk() || (!edited && proposal.risk == RiskClass::Destructive);
+                !high_risk || self.confirm_high_risk()
+            };
+            if !confirmed {
+                self.record(&entry);
+                println!("{}", colorify("Aborted", 247., 89., 89.));
+                return None;
+            }
+
+            let outcome = self.execute(&command, entry, self.repair_attempts > 0)?;
+            if !outcome.success() {
+                eprintln!(
+                    "{}",
+                    colorify("Command could not execute successfully", 247., 89., 89.)
+                );
+            }
+            return Some(outcome);
+        }
+    }
+
+    /// The numbered list of candidates to pick from, the chosen one marked.
+    fn render_candidates(candidates: &[Candidate], chosen: usize) -> String {
+        let mut output = format!("{}\n", colorify("Candidates:", 150., 150., 150.));
+        for (i, candidate) in candidates.iter().enumerate() {
+            let marker = if i == chosen { "*" } else { " " };
+            let (r, g, b) = candidate.proposal.risk.color();
+            output.push_str(&format!(
+                " {}{}. {} {} {}\n",
+                marker,
+                i + 1,
+                colorify(&candidate.proposal.command, 59., 235., 115.),
+                colorify(&candidate.proposal.risk.to_string(), r, g, b),
+                colorify(&format!("(log-prob {:.2})", candidate.score), 150., 150., 150.)
+            ));
+        }
+        output.push('\n');
+        output
+    }
+
+    /// Prints `output` word by word.
+    fn animate_output(output: &str) {
         let split = output.split(" ");
         let len = split.clone().count();
         for (i, w) in split.enumerate() {
@@ -192,75 +541,539 @@ impl<'a> Shellm<'a> {
                 sleep(Duration::from_millis(100));
             }
         }
+    }
 
-        print!("     [E]xecute [A]bort (default) ");
-        std::io::stdout().flush().unwrap();
+    /// Runs the local safety analysis and the policy check on `command`, along with their part
+    /// of the confirmation screen.
+    fn review(&self, command: &str) -> (SafetyReport, Result<(), PolicyViolation>, String) {
+        let report = analyze(command);
+        let mut output = report.render();
+        output.push('\n');
 
-        let mut buffer = String::new();
+        let verdict = self.policy.check(command);
+        if let Err(violation) = &verdict {
+            output.push_str(&format!("{}\n", colorify("Blocked by policy:", 247., 89., 89.)));
+            for reason in &violation.reasons {
+                output.push_str(&format!("  - {}\n", reason));
+            }
+            let sources: Vec<String> = violation.sources.iter().map(|p| p.display().to_string()).collect();
+            if !sources.is_empty() {
+                output.push_str(&format!("{}\n", colorify(&format!("(policy: {})", sources.join(", ")), 150., 150., 150.)));
+            }
+        }
+        (report, verdict, output)
+    }
+
+    fn confirm_high_risk(&self) -> bool {
+        let keyword = SafetyReport::confirmation_keyword();
+        print!("{} ", colorify(&format!("High-risk command. Type '{}' to run it, anything else aborts:", keyword), 247., 89., 89.));
+        self.read_line().trim() == keyword
+    }
+
+    /// Reads one line of the user's answer, trimmed and lowercased.
+    fn read_answer(&self) -> String {
+        self.read_line().trim().to_lowercase()
+    }
 
+    /// Reads from the terminal instead of stdin when piped input is meant for the command.
+    fn read_line(&self) -> String {
+        std::io::stdout().flush().unwrap();
+        let mut buffer = String::new();
+        if self.exec.pass_stdin && !std::io::stdin().is_terminal() {
+            if let Ok(tty) = File::open("/dev/tty") {
+                BufReader::new(tty).read_line(&mut buffer).unwrap();
+                return buffer;
+            }
+        }
         std::io::stdin().read_line(&mut buffer).unwrap();
+        buffer
+    }
 
-        if buffer.to_lowercase() == "e\n" {
-            let mut child = Command::new("sh")
-                .arg("-c")
-                .arg(cmd)
-                .stdout(Stdio::inherit())
-                .stderr(Stdio::inherit())
-                .spawn()
-                .expect("Failed to execute command");
-
-            let status = child.wait().expect("Something went wrong!");
-            if !status.success() {
-                eprintln!(
-                    "{}",
-                    colorify("Command could not execute successfully", 247., 89., 89.)
-                );
+    fn audit_entry(&self, command: &str, report: &SafetyReport) -> AuditEntry {
+        let mut entry = AuditEntry::now(&self.last_query, &self.model_path, self.instance.sampling(), command);
+        entry.findings = report.findings.iter().map(|f| f.message.clone()).collect();
+        entry
+    }
+
+    /// Runs an approved command and records the result in the audit log.
+    fn execute(&self, command: &str, mut entry: AuditEntry, capture: bool) -> Option<ExecOutcome> {
+        entry.decision = Decision::Executed;
+        let outcome = match exec::run(command, &self.exec, capture) {
+            Ok(outcome) => outcome,
+            Err(e) => {
+                self.record(&entry);
+                eprintln!("{}", colorify(&format!("Failed to execute command: {}", e), 247., 89., 89.));
+                return None;
+            }
+        };
+        entry.exit_status = outcome.exit_status;
+        entry.duration_ms = Some(outcome.duration.as_millis() as u64);
+        self.record(&entry);
+        Some(outcome)
+    }
+
+    /// Walks through `plan` one step at a time. Every step can be executed, modified or skipped,
+    /// and the first failure halts the plan until the user continues, retries or aborts. Returns
+    /// the exit code of the last failed step, 1 when the plan was aborted and 0 otherwise.
+    fn run_plan(&self, plan: &Plan) -> i32 {
+        println!("{}", plan.render());
+
+        let total = plan.steps.len();
+        let mut statuses: Vec<StepStatus> = vec![StepStatus::NotRun; total];
+        let mut i = 0;
+        'steps: while i < total {
+            let step = &plan.steps[i];
+            let mut command = step.command.clone();
+            loop {
+                println!("{} {}", colorify(&format!("Step {}/{}:", i + 1, total), 201., 168., 255.), step.description);
+                println!();
+                println!("      {}", colorify(&command, 59., 235., 115.));
+                println!();
+                let (report, verdict, review) = self.review(&command);
+                print!("{}", review);
+
+                let mut entry = self.audit_entry(&command, &report);
+                entry.step = Some(i + 1);
+                entry.risk = step.risk.to_string();
+                if command != step.command {
+                    entry.edited_from = Some(step.command.clone());
+                }
+
+                if verdict.is_err() {
+                    entry.decision = Decision::Blocked;
+                    self.record(&entry);
+                    print!("     [M]odify [S]kip [A]bort (default) ");
+                } else {
+                    print!("     [E]xecute [M]odify [S]kip [A]bort (default) ");
+                }
+
+                match self.read_answer().as_str() {
+                    "e" if verdict.is_ok() => {
+                        if (report.is_high_risk() || step.risk == RiskClass::Destructive) && !self.confirm_high_risk() {
+                            continue;
+                        }
+                        match self.execute(&command, entry, false) {
+                            Some(outcome) if outcome.success() => {
+                                statuses[i] = StepStatus::Succeeded(outcome);
+                                i += 1;
+                                continue 'steps;
+                            }
+                            outcome => {
+                                let status = match &outcome {
+                                    Some(outcome) => outcome.describe(),
+                                    None => "could not start".to_string(),
+                                };
+                                statuses[i] = StepStatus::Failed(outcome);
+                                eprintln!("{}", colorify(&format!("Step {} failed ({})", i + 1, status), 247., 89., 89.));
+                                print!("     [C]ontinue [R]etry [A]bort (default) ");
+                                match self.read_answer().as_str() {
+                                    "c" => {
+                                        i += 1;
+                                        continue 'steps;
+                                    }
+                                    "r" => continue,
+                                    _ => break 'steps,
+                                }
+                            }
+                        }
+                    }
+                    "m" => command = editor::edit_command(&command, self.exec.pass_stdin).unwrap_or(command),
+                    "s" => {
+                        if verdict.is_ok() {
+                            entry.decision = Decision::Skipped;
+                            self.record(&entry);
+                        }
+                        statuses[i] = StepStatus::Skipped;
+                        i += 1;
+                        continue 'steps;
+                    }
+                    _ => {
+                        if verdict.is_ok() {
+                            self.record(&entry);
+                        }
+                        println!("{}", colorify("Aborted", 247., 89., 89.));
+                        break 'steps;
+                    }
+                }
             }
-        } else {
-            println!("{}", colorify("Aborted", 247., 89., 89.))
         }
+
+        Self::print_plan_summary(plan, &statuses);
+        let failed = statuses.iter().rev().find_map(|status| match status {
+            StepStatus::Failed(outcome) => Some(outcome.as_ref().map_or(1, |o| o.exit_code())),
+            _ => None,
+        });
+        failed.unwrap_or(if i < total { 1 } else { 0 })
     }
 
-    fn get_wd() -> String {
-        env::current_dir().unwrap().display().to_string()
+    fn print_plan_summary(plan: &Plan, statuses: &[StepStatus]) {
+        println!();
+        println!("{}", colorify("Plan summary:", 150., 150., 150.));
+        for (i, (step, status)) in plan.steps.iter().zip(statuses).enumerate() {
+            let status = match status {
+                StepStatus::Succeeded(outcome) => colorify(&format!("done in {:.1}s", outcome.duration.as_secs_f64()), 59., 235., 115.),
+                StepStatus::Failed(Some(outcome)) => colorify(&format!("failed with {}", outcome.describe()), 247., 89., 89.),
+                StepStatus::Failed(None) => colorify("could not start", 247., 89., 89.),
+                StepStatus::Skipped => colorify("skipped", 247., 200., 89.),
+                StepStatus::NotRun => colorify("not run", 150., 150., 150.),
+            };
+            println!("  {}. {} {}", i + 1, status, colorify(&step.description, 150., 150., 150.));
+        }
     }
 
-    fn get_files() -> Result<String, Box<dyn Error>> {
-        let mut entries = Vec::new();
+    fn parse_proposal(result: &str) -> Option<CommandProposal> {
+        match CommandProposal::parse(result) {
+            Ok(proposal) => Some(proposal),
+            Err(e) => {
+                eprintln!("{}", colorify(&e.0, 247., 89., 89.));
+                if !result.trim().is_empty() {
+                    eprintln!("{}", colorify(result, 150., 150., 150.));
+                }
+                None
+            }
+        }
+    }
 
-        let dir_entries = fs::read_dir(Self::get_wd())?;
-        for entry_result in dir_entries {
-            let entry = entry_result?;
-            let path = entry.path();
+    /// Validates a proposal before it is shown: a syntax error is sent back to the model once,
+    /// flags are checked against the manuals, and whatever is still wrong after that ends up in
+    /// the warnings.
+    fn check_proposal(&mut self, proposal: CommandProposal) -> CommandProposal {
+        let proposal = self.repair_syntax(proposal);
+        let mut proposal = self.ground_proposal(proposal);
+        let warnings = self.command_warnings(&proposal.command);
+        proposal.warnings.extend(warnings);
+        proposal
+    }
 
-            let name = path
-                .file_name()
-                .and_then(|n| n.to_str())
-                .ok_or("Invalid file name")?
-                .to_string();
+    /// Parses the candidate answers, drops repeated commands and orders the rest: commands the
+    /// policy blocks go last, then those with the most severe safety findings, and otherwise
+    /// the model's mean log-probability decides. The best one is checked like a single proposal
+    /// and takes the place of the first in the history; the others only get the local warnings.
+    fn rank_candidates(&mut self, generations: Vec<Generation>) -> Vec<Candidate> {
+        let in_history = generations[0].tokens.clone();
+        let first_answer = generations[0].text.clone();
+        // blocked by the policy, the most severe finding and risk class, compared in this order
+        type Safety = (bool, Option<Severity>, Option<RiskClass>);
+        let mut ranked: Vec<(Candidate, Safety)> = vec![];
+        for generation in generations {
+            let Ok(proposal) = CommandProposal::parse(&generation.text) else { continue };
+            let report = analyze(&proposal.command);
+            let safety = (
+                self.policy.check(&proposal.command).is_err(),
+                report.findings.iter().map(|f| f.severity).max(),
+                report.risk(),
+            );
+            let score = generation.logprob / generation.tokens.len().max(1) as f32;
+            ranked.push((Candidate { proposal, tokens: generation.tokens, score }, safety));
+        }
+        ranked.sort_by(|(a, a_safety), (b, b_safety)| a_safety.cmp(b_safety).then(b.score.total_cmp(&a.score)));
 
-            if path.is_dir() {
-                entries.push(format!("{}/", name));
-            } else {
-                entries.push(name);
+        let mut candidates: Vec<Candidate> = vec![];
+        for (candidate, _) in ranked {
+            let words: Vec<&str> = candidate.proposal.command.split_whitespace().collect();
+            if !candidates.iter().any(|c| c.proposal.command.split_whitespace().eq(words.iter().copied())) {
+                candidates.push(candidate);
+            }
+        }
+        let Some(best) = candidates.first_mut() else {
+            Self::parse_proposal(&first_answer); // reports why none parsed
+            return candidates;
+        };
+
+        self.instance.replace_generation(&in_history, &best.tokens);
+        self.last_generation = best.tokens.clone();
+        best.proposal = self.check_proposal(best.proposal.clone());
+        // a syntax repair or grounding asks the model again, the history then ends with its answer
+        best.tokens = self.last_generation.clone();
+        for candidate in candidates.iter_mut().skip(1) {
+            let mut warnings = self.command_warnings(&candidate.proposal.command);
+            if self.manual_grounding {
+                warnings.extend(self.manual_warning(&candidate.proposal.command));
             }
+            candidate.proposal.warnings = warnings;
+        }
+        candidates
+    }
+
+    fn repair_syntax(&mut self, proposal: CommandProposal) -> CommandProposal {
+        let Some(error) = syntax_error(&proposal.command, &self.exec.shell) else { return proposal };
+        eprintln!("{}", colorify(&format!("Syntax error, asking for a fix: {}", error), 150., 150., 150.));
+
+        self.query.clear();
+        let query = format!(
+            "The command `{}` is not valid {} syntax: {}\nPropose a corrected command that achieves the original request.",
+            proposal.command,
+            self.exec.shell.name(),
+            error
+        );
+        self.query.add_dialogue(ChatRole::User, &query);
+        match self.process_query() {
+            Some(Intent::Answer(result)) => Self::parse_proposal(&result).unwrap_or(proposal),
+            _ => proposal,
+        }
+    }
+
+    /// What the local checks find wrong with `command`: a syntax error, and programs that are
+    /// not installed along with the command that installs them.
+    fn command_warnings(&self, command: &str) -> Vec<String> {
+        let mut warnings = vec![];
+        if let Some(error) = syntax_error(command, &self.exec.shell) {
+            warnings.push(format!("{} -n reports a syntax error: {}", self.exec.shell.name(), error));
+        }
+        let missing = missing_binaries(command);
+        if !missing.is_empty() {
+            let system = SystemContext::load(&self.exec.shell);
+            for binary in missing {
+                match install_hint(&binary, &system) {
+                    Some(install) => warnings.push(format!("{} is not installed, try: {}", binary, install)),
+                    None => warnings.push(format!("{} was not found", binary)),
+                }
+            }
+        }
+        warnings
+    }
+
+    /// Checks the flags of `proposal` against the local manuals. When some are not documented,
+    /// the model gets the manuals and one chance to propose the command again; flags still not
+    /// documented after that are shown as warnings with the proposal.
+    fn ground_proposal(&mut self, proposal: CommandProposal) -> CommandProposal {
+        if !self.manual_grounding {
+            return proposal;
+        }
+        let (unknown, manuals) = undocumented_flags(&proposal.command, &self.policy);
+        if unknown.is_empty() {
+            return proposal;
+        }
+        // stderr keeps stdout clean for print-only mode
+        eprintln!("{}", colorify(&format!("Not in the local manuals: {}, asking again", Self::describe_flags(&unknown)), 150., 150., 150.));
+
+        self.query.clear();
+        self.query.add_dialogue(ChatRole::User, &Self::grounding_query(&proposal.command, &unknown, &manuals));
+        let regenerated = match self.process_query() {
+            Some(Intent::Answer(result)) => Self::parse_proposal(&result),
+            _ => None,
+        };
+        let mut proposal = regenerated.unwrap_or(proposal);
+        proposal.warnings.extend(self.manual_warning(&proposal.command));
+        proposal
+    }
+
+    fn manual_warning(&self, command: &str) -> Option<String> {
+        let (unknown, _) = undocumented_flags(command, &self.policy);
+        (!unknown.is_empty()).then(|| format!("not in the local manuals: {}", Self::describe_flags(&unknown)))
+    }
+
+    fn grounding_query(command: &str, unknown: &[UndocumentedFlag], manuals: &[Manual]) -> String {
+        let mut query = format!(
+            "The command `{}` uses options that the documentation on this system does not list: {}. The installed versions may not support them.",
+            command,
+            Self::describe_flags(unknown)
+        );
+        for manual in manuals {
+            query.push_str(&format!("\nMANUAL {}:\n{}", manual.binary, manual.overview()));
         }
+        query.push_str("\nPropose the command again using only documented options, or other tools when these cannot do it.");
+        query
+    }
 
-        entries.sort();
-        Ok(entries.join(", "))
+    /// "`--foo` for tar, `-x` for ls"
+    fn describe_flags(flags: &[UndocumentedFlag]) -> String {
+        flags.iter().map(|f| format!("`{}` for {}", f.flag, f.binary)).collect::<Vec<String>>().join(", ")
+    }
+
+    /// Writes the command alone to stdout, for a shell integration to put on the command line.
+    /// Safety findings go to stderr, and a command blocked by the policy is not printed at all.
+    fn print_proposal(&mut self, proposal: &CommandProposal) {
+        let (report, verdict, review) = self.review(&proposal.command);
+        let mut entry = self.audit_entry(&proposal.command, &report);
+        entry.risk = proposal.risk.to_string();
+        if !report.findings.is_empty() || verdict.is_err() {
+            eprint!("{}", review);
+        }
+        for warning in &proposal.warnings {
+            eprintln!("{}", colorify(&format!("Warning: {}", warning), 247., 200., 89.));
+        }
+
+        if verdict.is_err() {
+            entry.decision = Decision::Blocked;
+            self.record(&entry);
+            self.exit_code = 1;
+            return;
+        }
+        entry.decision = Decision::Printed;
+        self.record(&entry);
+        println!("{}", proposal.command);
+        self.exit_code = 0;
+    }
+
+    /// Runs the candidate the user picks; when it fails, feeds the output back to the model and
+    /// offers its fix, at most `repair_attempts` times. Returns false when the model asked to end
+    /// the session.
+    fn run_proposal(&mut self, mut candidates: Vec<Candidate>) -> bool {
+        let mut attempt = 0;
+        let mut chosen = 0;
+        loop {
+            let outcome = match self.exec_bash_cmd(&candidates, &mut chosen, attempt) {
+                Some(outcome) => outcome,
+                None => {
+                    self.exit_code = 1;
+                    return true;
+                }
+            };
+            self.exit_code = outcome.exit_code();
+            if outcome.success() || attempt >= self.repair_attempts {
+                return true;
+            }
+            attempt += 1;
+
+            print!("Ask shellm for a fix? ({} of {}) [y/N] ", attempt, self.repair_attempts);
+            if self.read_answer() != "y" {
+                return true;
+            }
+
+            self.query.clear();
+            let query = Self::repair_query(&candidates[chosen].proposal.command, &outcome);
+            self.query.add_dialogue(ChatRole::User, &Self::augment_query(query, &ModelMode::CMD, &self.listing, self.git_context, &self.policy));
+            let mut intent = self.process_query();
+            while let Some(question) = Self::question_in(&intent) {
+                if !self.reply_to(&question) {
+                    return true;
+                }
+                intent = self.process_query();
+            }
+            let proposal = match intent {
+                Some(Intent::Answer(result)) => match Self::parse_proposal(&result) {
+                    Some(proposal) => self.check_proposal(proposal),
+                    None => return true,
+                },
+                Some(Intent::Save) => {
+                    self.save_session();
+                    return true;
+                }
+                Some(Intent::Exit) => return false,
+                None => return true,
+            };
+            candidates = vec![Candidate::single(proposal)];
+            chosen = 0;
+        }
+    }
+
+    fn repair_query(command: &str, outcome: &ExecOutcome) -> String {
+        let mut query = format!("The command `{}` failed with {}.", command, outcome.describe());
+        Self::push_output(&mut query, outcome);
+        query.push_str("\nExplain what went wrong in \"diagnosis\" and propose a corrected command that achieves the original request.");
+        query
+    }
+
+    fn push_output(query: &mut String, outcome: &ExecOutcome) {
+        for (name, output) in [("stdout", &outcome.stdout), ("stderr", &outcome.stderr)] {
+            if !output.trim().is_empty() {
+                query.push_str(&format!("\n{}:\n{}", name, output.trim_end()));
+            }
+        }
+    }
+
+    /// `shellm fix`: asks for a corrected version of `command`, which the user ran themselves
+    /// and which exited with `status` when known. With `rerun`, the command is run once more to
+    /// show its output to the model. The fix then goes through the usual confirmation.
+    pub fn fix(&mut self, command: &str, status: Option<i32>, rerun: bool) -> i32 {
+        if status == Some(0) {
+            eprintln!("{}", colorify("The last command exited with 0, asking for a fix anyway", 247., 200., 89.));
+        }
+        self.last_query = format!("fix `{}`", command);
+        let outcome = if rerun { self.rerun(command) } else { None };
+
+        let mut query = match (&outcome, status) {
+            (Some(outcome), _) => format!("The command `{}` failed with {}.", command, outcome.describe()),
+            (None, Some(code)) => format!("The command `{}` failed with exit code {}.", command, code),
+            (None, None) => format!("The command `{}` did not work.", command),
+        };
+        if let Some(outcome) = &outcome {
+            Self::push_output(&mut query, outcome);
+        }
+        query.push_str("\nExplain what went wrong in \"diagnosis\" and propose a corrected command that does what it was meant to do.");
+
+        let query = Self::augment_query(query, &ModelMode::CMD, &self.listing, self.git_context, &self.policy);
+        self.query.add_dialogue(ChatRole::User, &query);
+        self.run_from_mode();
+        self.exit_code
+    }
+
+    /// Runs a failed command again with its output captured, unless it is blocked or high-risk.
+    fn rerun(&self, command: &str) -> Option<ExecOutcome> {
+        let (report, verdict, review) = self.review(command);
+        if verdict.is_err() || report.is_high_risk() {
+            print!("{}", review);
+            eprintln!("{}", colorify("Not running the command again, asking without its output", 247., 200., 89.));
+            return None;
+        }
+        println!("{}", colorify("Running the command again to capture its output:", 150., 150., 150.));
+        println!("      {}", colorify(command, 59., 235., 115.));
+        println!();
+
+        let mut entry = self.audit_entry(command, &report);
+        entry.risk = report.risk().map(|r| r.to_string()).unwrap_or_default();
+        let outcome = self.execute(command, entry, true)?;
+        if outcome.success() {
+            eprintln!("{}", colorify("The command succeeded this time", 247., 200., 89.));
+        }
+        println!();
+        Some(outcome)
+    }
+
+    /// Prints the explained command with the local safety check, without offering to run it.
+    fn show_explanation(&mut self, result: &str, segments: &[Segment]) {
+        match Explanation::parse(result) {
+            Ok(explanation) => {
+                let mut output = explanation.render(&self.last_query, segments);
+                output.push('\n');
+                output.push_str(&self.review(&self.last_query).2);
+                print!("{}", output);
+            }
+            Err(e) => {
+                self.exit_code = 1;
+                eprintln!("{}", colorify(&e.0, 247., 89., 89.));
+                eprintln!("{}", colorify(result, 150., 150., 150.));
+            }
+        }
+    }
+
+    fn record(&self, entry: &AuditEntry) {
+        if let Err(e) = self.audit.append(entry) {
+            eprintln!("{}", colorify(&e.0, 247., 89., 89.));
+        }
+    }
+
+    fn get_wd() -> String {
+        env::current_dir().unwrap().display().to_string()
     }
 
     fn augment_sys_prompt(mut prompt: String) -> String {
-        prompt.push_str(" If anything related to saving the current session, respond with <SAVE>.");
-        prompt.push_str(" If anything is related to exiting or leaving the current session, respond with <EXIT>.");
+        prompt.push_str(&format!(" If anything related to saving the current session, respond with {}.", SAVE_SENTINEL));
+        prompt.push_str(&format!(" If anything is related to exiting or leaving the current session, respond with {}.", EXIT_SENTINEL));
         prompt
     }
 
-    fn augment_query(mut query: String) -> String {
-        query.push_str(" WD: ");
-        query.push_str(&Self::get_wd());
-        query.push_str(" FILES: ");
-        query.push_str(&Self::get_files().unwrap());
+    /// Adds the working directory to CMD and PLAN queries, and the git repository around it to
+    /// those and CODE queries. EXPLAIN queries get the segments of the command and the manual
+    /// excerpts for it instead, `--help` output only from binaries `policy` allows.
+    fn augment_query(mut query: String, mode: &ModelMode, listing: &ListingConfig, git: bool, policy: &Policy) -> String {
+        if matches!(mode, ModelMode::EXPLAIN) {
+            return explain_query(&query, &segment(&query), policy);
+        }
+        let wd = Self::get_wd();
+        if matches!(mode, ModelMode::CMD | ModelMode::PLAN) {
+            query.push_str(" WD: ");
+            query.push_str(&wd);
+            query.push_str(" FILES: ");
+            query.push_str(&list_dir(Path::new(&wd), listing));
+        }
+        if git && matches!(mode, ModelMode::CMD | ModelMode::PLAN | ModelMode::CODE) {
+            if let Some(context) = GitContext::collect(Path::new(&wd)) {
+                query.push(' ');
+                query.push_str(&context.render());
+            }
+        }
         query
     }
 
@@ -280,6 +1093,13 @@ impl<'a> Shellm<'a> {
         });
     }
 
+    fn save_session(&self) {
+        match self.instance.save_curr_session(self.save_path.clone()) {
+            Ok(()) => println!("{}", colorify("Session saved", 150., 150., 150.)),
+            Err(_e) => eprintln!("{}", colorify("Could not save session!", 247., 89., 89.)),
+        }
+    }
+
     fn exit_shell(&self) {
         let save = self.save_path.clone();
         if let Some(save_path) = save {
@@ -290,26 +1110,136 @@ impl<'a> Shellm<'a> {
         println!("{}", colorify("🔮 Bye", 201., 168., 255.))
     }
 
-    fn run_from_mode(&mut self) {
-        match self.model_mode {
-            ModelMode::CMD => {
-                let result = self.process_query();
-                Self::exec_bash_cmd(result)
-            }
-            ModelMode::CODE => {
-                let mut result = self.stream_query();
-                if let Some(out_file) = &self.program_out_file {
-                    result = Self::clean_code_output(result);
-                    let mut file = File::create(out_file).expect(&format!("Cannot create file: {}!", out_file));
-                    file.write_all(result.as_bytes()).expect("Could not save code to file!");
-                }
+    /// Answers the current query. Returns false when the model asked to end the session.
+    fn run_from_mode(&mut self) -> bool {
+        let segments = match self.model_mode {
+            ModelMode::EXPLAIN => segment(&self.last_query),
+            _ => vec![],
+        };
+        if matches!(self.model_mode, ModelMode::EXPLAIN) {
+            if segments.is_empty() {
+                self.exit_code = 1;
+                eprintln!("{}", colorify("Nothing to explain", 247., 89., 89.));
+                return true;
+            }
+            // one explanation per segment, so the constraint changes with every command
+            let constraint = Constraint::JsonSchema(explanation_schema(segments.len()))
+                .to_gbnf()
+                .and_then(|grammar| self.instance.set_constraint(Some(&Constraint::Gbnf(Self::allow_sentinels(&grammar)))));
+            if let Err(e) = constraint {
+                self.exit_code = 1;
+                eprintln!("{}", colorify(&format!("Could not constrain the explanation: {}", e.0), 247., 89., 89.));
+                return true;
+            }
+        }
+
+        let (mut intent, mut candidates) = self.answer_query();
+        // a CMD answer may be a question instead of a command: ask the user, then answer again
+        while let Some(question) = Self::question_in(&intent).filter(|_| matches!(self.model_mode, ModelMode::CMD)) {
+            if !self.reply_to(&question) {
+                self.exit_code = 1;
+                return true;
+            }
+            (intent, candidates) = self.answer_query();
+        }
+
+        match intent {
+            None => {
+                self.exit_code = 1;
+                true
+            }
+            Some(Intent::Save) => {
+                self.save_session();
+                true
             }
-            _ => {
-                self.stream_query();
+            Some(Intent::Exit) => false,
+            Some(Intent::Answer(mut result)) => {
+                match self.model_mode {
+                    ModelMode::CMD => {
+                        let candidates: Vec<Candidate> = if candidates.is_empty() {
+                            Self::parse_proposal(&result).map(|proposal| Candidate::single(self.check_proposal(proposal))).into_iter().collect()
+                        } else {
+                            self.rank_candidates(candidates)
+                        };
+                        if !candidates.is_empty() {
+                            if self.print_only {
+                                self.print_proposal(&candidates[0].proposal);
+                                return true;
+                            }
+                            return self.run_proposal(candidates);
+                        }
+                        self.exit_code = 1;
+                    }
+                    ModelMode::PLAN => match Plan::parse(&result) {
+                        Ok(plan) => self.exit_code = self.run_plan(&plan),
+                        Err(e) => {
+                            self.exit_code = 1;
+                            eprintln!("{}", colorify(&e.0, 247., 89., 89.));
+                            eprintln!("{}", colorify(&result, 150., 150., 150.));
+                        }
+                    },
+                    ModelMode::EXPLAIN => self.show_explanation(&result, &segments),
+                    ModelMode::CODE => {
+                        if let Some(out_file) = &self.program_out_file {
+                            result = Self::clean_code_output(result);
+                            let mut file = File::create(out_file).expect(&format!("Cannot create file: {}!", out_file));
+                            file.write_all(result.as_bytes()).expect("Could not save code to file!");
+                        }
+                    }
+                    _ => {}
+                }
+                true
             }
         }
     }
 
+    /// Generates the answer to the current query, along with the candidates it was picked from
+    /// when CMD answers come in several.
+    fn answer_query(&mut self) -> (Option<Intent>, Vec<Generation>) {
+        match self.model_mode {
+            // the first candidate is sampled like a single answer and decides about the sentinels
+            ModelMode::CMD if self.candidates > 1 => match self.process_candidates() {
+                Some(generations) => (Some(self.finish_generation(&generations[0])), generations),
+                None => (None, vec![]),
+            },
+            ModelMode::CMD | ModelMode::PLAN | ModelMode::EXPLAIN => (self.process_query(), vec![]),
+            _ => (self.stream_query(), vec![]),
+        }
+    }
+
+    /// The question of a CMD answer that asks the user for a missing detail.
+    fn question_in(intent: &Option<Intent>) -> Option<String> {
+        match intent {
+            Some(Intent::Answer(result)) => ClarifyingQuestion::parse(result).map(|q| q.question),
+            _ => None,
+        }
+    }
+
+    /// Shows the model's question and makes the user's answer the next query. Returns false
+    /// when the answer is left empty, which aborts.
+    fn reply_to(&mut self, question: &str) -> bool {
+        let prompt = format!(
+            "{} {}\n     {} ",
+            colorify("Question:", 201., 168., 255.),
+            question.trim(),
+            colorify("Answer (empty aborts):", 150., 150., 150.)
+        );
+        // stdout belongs to the generated command when printing only
+        if self.print_only {
+            eprint!("{}", prompt);
+        } else {
+            print!("{}", prompt);
+        }
+        let answer = self.read_line().trim().to_string();
+        if answer.is_empty() {
+            eprintln!("{}", colorify("Aborted", 247., 89., 89.));
+            return false;
+        }
+        self.query.clear();
+        self.query.add_dialogue(ChatRole::User, &answer);
+        true
+    }
+
     fn run_shell(&mut self) {
         let shell_tag = colorify("🔮", 129., 59., 235.);
         let tilda = colorify("~", 59., 150., 235.);
@@ -328,32 +1258,37 @@ impl<'a> Shellm<'a> {
                     continue;
                 }
 
-                buffer = match self.model_mode {
-                    ModelMode::CMD => Self::augment_query(buffer),
-                    _ => buffer,
-                };
+                self.last_query = buffer.trim().to_string();
+                buffer = Self::augment_query(buffer, &self.model_mode, &self.listing, self.git_context, &self.policy);
 
                 self.query.add_dialogue(ChatRole::User, &buffer);
             }
 
-            self.run_from_mode();
+            if !self.run_from_mode() {
+                self.exit_shell();
+                break;
+            }
 
             self.query.clear();
         }
     }
 
-    pub fn run(&mut self) {
+    /// Returns the exit code for shellm: the status of the executed command for a single query,
+    /// 1 when nothing ran, and 0 for the interactive shell.
+    pub fn run(&mut self) -> i32 {
         if self.shell_mode {
             Self::print_shell_start_msg();
             self.run_shell();
+            0
         } else {
             // process a single query
             if self.query.len() < 2 {
                 eprintln!("{}", colorify("No query provided", 247., 89., 89.));
-                return;
+                return 1;
             }
 
             self.run_from_mode();
+            self.exit_code
         }
     }
 }
@@ -0,0 +1,203 @@
+use crate::shell::command_parser::{base_name, expand_home, parse, tokenize, Token};
+use crate::shell::context::{find_in_path, SystemContext};
+use crate::shell::exec::{output_within, Shell, ShellKind};
+use std::process::Command;
+use std::time::Duration;
+
+const SYNTAX_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Builtins and keywords of bash, zsh and fish, never found on PATH.
+const BUILTINS: [&str; 101] = [
+    ".", ":", "[", "[[", "alias", "bg", "bind", "break", "builtin", "caller", "case", "cd", "command",
+    "compgen", "complete", "compopt", "continue", "coproc", "declare", "dirs", "disown", "echo", "enable",
+    "eval", "exec", "exit", "export", "false", "fc", "fg", "for", "function", "getopts", "hash", "help",
+    "history", "jobs", "kill", "let", "local", "logout", "mapfile", "popd", "printf", "pushd", "pwd",
+    "read", "readarray", "readonly", "return", "select", "set", "shift", "shopt", "source", "suspend",
+    "test", "time", "times", "trap", "true", "type", "typeset", "ulimit", "umask", "unalias", "unset",
+    "wait", "autoload", "bindkey", "emulate", "noglob", "print", "rehash", "setopt", "unsetopt", "whence",
+    "zmodload", "and", "or", "not", "begin", "end", "switch", "string", "math", "contains", "status",
+    "functions", "argparse", "count", "abbr", "block", "commandline", "emit", "path", "random",
+    "set_color", "fish_add_path", "funced", "funcsave",
+];
+
+/// Package names that differ from the binary they provide.
+const PACKAGES: [(&str, &str); 10] = [
+    ("rg", "ripgrep"),
+    ("fdfind", "fd-find"),
+    ("convert", "imagemagick"),
+    ("magick", "imagemagick"),
+    ("pip3", "python3-pip"),
+    ("node", "nodejs"),
+    ("7z", "p7zip"),
+    ("psql", "postgresql-client"),
+    ("ip", "iproute2"),
+    ("ifconfig", "net-tools"),
+];
+
+/// The error `shell` reports for `command` when parsing it without running anything. None when
+/// it parses, or when the shell cannot be started to check it.
+pub fn syntax_error(command: &str, shell: &Shell) -> Option<String> {
+    let mut check = Command::new(&shell.path);
+    match shell.kind {
+        ShellKind::Posix => check.arg("-n").arg("-c").arg(command),
+        ShellKind::Fish => check.arg("--no-execute").arg("-c").arg(command),
+    };
+    let (_, stderr, success) = output_within(&mut check, SYNTAX_TIMEOUT)?;
+    if success {
+        return None;
+    }
+    // "bash: -c: line 1: syntax error ..." without the prefix
+    let prefix = format!("{}: -c: ", shell.name());
+    let error: Vec<&str> = stderr.lines().map(|l| l.trim().trim_start_matches(&prefix)).filter(|l| !l.is_empty()).take(2).collect();
+    Some(if error.is_empty() { format!("{} -n failed", shell.name()) } else { error.join(" ") })
+}
+
+/// The programs `command` runs that are neither builtins nor found, by path or on PATH.
+/// Programs the command installs itself and functions it defines are left out.
+pub fn missing_binaries(command: &str) -> Vec<String> {
+    let script = parse(command);
+    let functions = defined_functions(command);
+    let installed: Vec<&str> = script
+        .commands()
+        .map(|c| c.effective_argv())
+        .filter(|argv| argv.first().is_some_and(|b| is_package_manager(base_name(b))))
+        .flatten()
+        .collect();
+
+    let mut missing: Vec<String> = vec![];
+    for simple in script.commands() {
+        let binaries = simple.wrappers().into_iter().chain(simple.effective_argv().into_iter().take(1));
+        for binary in binaries {
+            // variables and substitutions are only known when the command runs
+            if binary.contains(['$', '`', '(']) || BUILTINS.contains(&binary) || functions.iter().any(|f| f == binary) || installed.contains(&package_of(binary)) {
+                continue;
+            }
+            let path = expand_home(binary).to_string_lossy().into_owned();
+            if find_in_path(&path).is_none() && !missing.iter().any(|m| m == binary) {
+                missing.push(binary.to_string());
+            }
+        }
+    }
+    missing
+}
+
+/// How to install the package providing `binary` with the package manager found on this system.
+pub fn install_hint(binary: &str, system: &SystemContext) -> Option<String> {
+    if binary.contains('/') {
+        return None;
+    }
+    let package = package_of(binary);
+    let manager = system.package_manager.as_deref()?;
+    let install = match manager {
+        "apt" => format!("apt install {}", package),
+        "dnf" | "yum" => format!("{} install {}", manager, package),
+        "pacman" => format!("pacman -S {}", package),
+        "zypper" => format!("zypper install {}", package),
+        "apk" => format!("apk add {}", package),
+        "xbps-install" => format!("xbps-install {}", package),
+        "emerge" => format!("emerge {}", package),
+        "nix-env" => return Some(format!("nix-env -iA nixpkgs.{}", package)),
+        "brew" => return Some(format!("brew install {}", package)),
+        _ => return None,
+    };
+    Some(if system.root { install } else { format!("sudo {}", install) })
+}
+
+/// The names of the functions `command` defines, as `name() { ..; }` or `function name`.
+fn defined_functions(command: &str) -> Vec<String> {
+    let tokens: Vec<Token> = tokenize(command).into_iter().map(|(token, _)| token).collect();
+    let mut functions = vec![];
+    for window in tokens.windows(3) {
+        match window {
+            [Token::Word(name), Token::Op(open), Token::Op(close)] if open == "(" && close == ")" => functions.push(name.text.clone()),
+            [Token::Word(keyword), Token::Word(name), _] if keyword.text == "function" => functions.push(name.text.clone()),
+            _ => {}
+        }
+    }
+    if let [.., Token::Word(keyword), Token::Word(name)] = tokens.as_slice() {
+        if keyword.text == "function" {
+            functions.push(name.text.clone());
+        }
+    }
+    functions
+}
+
+fn package_of(binary: &str) -> &str {
+    PACKAGES.iter().find(|(b, _)| *b == binary).map(|(_, p)| *p).unwrap_or(binary)
+}
+
+fn is_package_manager(binary: &str) -> bool {
+    matches!(binary, "apt" | "apt-get" | "dnf" | "yum" | "pacman" | "zypper" | "apk" | "xbps-install" | "emerge" | "nix-env" | "brew" | "pip" | "pip3" | "npm" | "cargo")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn system(package_manager: Option<&str>, root: bool) -> SystemContext {
+        serde_json::from_value(json!({
+            "os": "Test Linux", "package_manager": package_manager, "shell": "bash", "root": root,
+            "sudo": true, "tools": [], "collected_at": 0, "path": "",
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn missing_programs() {
+        assert_eq!(missing_binaries("ls -la | shellm-no-such-tool --x"), vec!["shellm-no-such-tool"]);
+        assert_eq!(missing_binaries("nice shellm-no-such-tool && shellm-no-such-tool"), vec!["shellm-no-such-tool"]);
+        assert_eq!(missing_binaries("/nonexistent/bin/tool"), vec!["/nonexistent/bin/tool"]);
+        assert!(missing_binaries("sh -c 'echo hi'").is_empty());
+        // only known when the command runs
+        assert!(missing_binaries("$EDITOR notes.txt").is_empty());
+    }
+
+    #[test]
+    fn builtins_are_not_missing() {
+        assert!(missing_binaries("cd /tmp && export A=1; source env.sh; sleep 1 & wait").is_empty());
+        assert!(missing_binaries("for f in *.txt; do echo $f; done").is_empty());
+        assert!(missing_binaries("set -e; trap 'echo bye' EXIT; unalias ll; shopt -s globstar").is_empty());
+        assert!(missing_binaries("string upper abc; and set_color red").is_empty());
+    }
+
+    #[test]
+    fn functions_defined_in_the_command() {
+        assert!(missing_binaries("greet() { echo hi; }; greet").is_empty());
+        assert!(missing_binaries("function greet { echo hi; }; greet").is_empty());
+        assert!(missing_binaries("function greet() { echo hi; }; greet").is_empty());
+        assert_eq!(missing_binaries("greet() { shellm-no-such-tool; }; greet"), vec!["shellm-no-such-tool"]);
+        assert_eq!(defined_functions("a() { :; }; function b { :; }; function c"), vec!["a", "b", "c"]);
+    }
+
+    #[test]
+    fn programs_the_command_installs() {
+        // the package managers themselves may be missing on the test machine
+        let missing = |command: &str| missing_binaries(command).into_iter().filter(|b| !is_package_manager(b)).collect::<Vec<String>>();
+        assert!(missing("cargo install shellm-no-such-tool && shellm-no-such-tool").is_empty());
+        assert!(missing("env DEBIAN_FRONTEND=noninteractive apt-get install -y ripgrep && rg TODO").is_empty());
+        assert!(missing("pip3 install shellm-no-such-tool; shellm-no-such-tool").is_empty());
+        // installing something else does not count
+        assert_eq!(missing("npm install left-pad && shellm-no-such-tool"), vec!["shellm-no-such-tool"]);
+    }
+
+    #[test]
+    fn install_hints() {
+        let hint = |manager: &str, root: bool, binary: &str| install_hint(binary, &system(Some(manager), root));
+        assert_eq!(hint("apt", false, "rg").as_deref(), Some("sudo apt install ripgrep"));
+        assert_eq!(hint("apt", true, "jq").as_deref(), Some("apt install jq"));
+        assert_eq!(hint("dnf", false, "jq").as_deref(), Some("sudo dnf install jq"));
+        assert_eq!(hint("yum", true, "jq").as_deref(), Some("yum install jq"));
+        assert_eq!(hint("pacman", false, "convert").as_deref(), Some("sudo pacman -S imagemagick"));
+        assert_eq!(hint("zypper", false, "jq").as_deref(), Some("sudo zypper install jq"));
+        assert_eq!(hint("apk", true, "node").as_deref(), Some("apk add nodejs"));
+        assert_eq!(hint("xbps-install", false, "jq").as_deref(), Some("sudo xbps-install jq"));
+        assert_eq!(hint("emerge", false, "jq").as_deref(), Some("sudo emerge jq"));
+        // user level package managers never need sudo
+        assert_eq!(hint("nix-env", false, "jq").as_deref(), Some("nix-env -iA nixpkgs.jq"));
+        assert_eq!(hint("brew", false, "7z").as_deref(), Some("brew install p7zip"));
+        assert_eq!(hint("portage", false, "jq"), None);
+        assert_eq!(hint("apt", false, "./build/tool"), None);
+        assert_eq!(install_hint("jq", &system(None, false)), None);
+    }
+}
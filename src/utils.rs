@@ -1,4 +1,7 @@
 pub mod model_tool;
+pub mod config;
+pub mod sampling;
+pub mod grammar;
 
 pub mod color {
     use std::io::Write;
@@ -77,8 +80,36 @@ pub mod color {
 }
 
 pub mod utils {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
     pub fn get_sys_threads() -> usize {
         num_cpus::get()
     }
 
+    /// RFC 3339 in UTC, e.g. 2024-05-01T13:00:00Z.
+    pub fn format_timestamp(time: SystemTime) -> String {
+        let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let (days, rem) = (secs / 86400, secs % 86400);
+
+        // civil date from days since the epoch (Howard Hinnant's algorithm)
+        let z = days as i64 + 719468;
+        let era = z.div_euclid(146097);
+        let doe = z.rem_euclid(146097);
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+        format!(
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+            year,
+            month,
+            day,
+            rem / 3600,
+            rem % 3600 / 60,
+            rem % 60
+        )
+    }
 }
\ No newline at end of file
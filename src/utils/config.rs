@@ -0,0 +1,407 @@
+use crate::shell::listing::ListingConfig;
+use crate::utils::color::colorify;
+use crate::utils::model_tool::OverflowPolicy;
+use crate::utils::sampling::SamplingOverrides;
+use crate::utils::utils::get_sys_threads;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::{env, fs};
+use toml::{Table, Value};
+
+const CONFIG_DIR_NAME: &str = "shellm";
+const CONFIG_FILE_NAME: &str = "config.toml";
+const PROJECT_FILE_NAME: &str = ".shellm.toml";
+const ENV_PREFIX: &str = "SHELLM_";
+
+#[derive(Debug)]
+pub struct ConfigError(pub String);
+
+#[derive(Clone, Debug)]
+pub enum ConfigSource {
+    Default,
+    File(PathBuf),
+    Env(String),
+    Cli,
+}
+
+impl fmt::Display for ConfigSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigSource::Default => write!(f, "default"),
+            ConfigSource::File(path) => write!(f, "file {}", path.display()),
+            ConfigSource::Env(var) => write!(f, "env {}", var),
+            ConfigSource::Cli => write!(f, "command line"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Config {
+    pub model_path: Option<String>,
+    pub ctx_window: u32,
+    pub threads: i32,
+    pub threads_batch: Option<i32>,
+    pub gpu_layers: u32,
+    pub default_mode: String,
+    pub max_gen: i32,
+    pub audit_log: Option<String>,
+    pub repair_attempts: u32,
+    pub shell: Option<String>,
+    pub command_timeout: u64, // seconds, 0 waits forever
+    pub pass_stdin: bool,
+    pub git_context: bool,
+    pub manual_grounding: bool,
+    pub candidates: u32, // CMD answers generated per query, the user picks among them
+    pub overflow_policy: OverflowPolicy,
+    pub listing: ListingConfig,
+    pub sampling: SamplingOverrides,
+
+    #[serde(skip)]
+    sources: BTreeMap<String, ConfigSource>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            model_path: None,
+            ctx_window: 30000,
+            threads: (get_sys_threads() * 7 / 8).max(1) as i32,
+            threads_batch: None,
+            gpu_layers: 5000,
+            default_mode: "general".to_string(),
+            max_gen: 10000,
+            audit_log: None,
+            repair_attempts: 0,
+            shell: None,
+            command_timeout: 0,
+            pass_stdin: false,
+            git_context: true,
+            manual_grounding: true,
+            candidates: 1,
+            overflow_policy: OverflowPolicy::default(),
+            listing: ListingConfig::default(),
+            sampling: SamplingOverrides::default(),
+            sources: BTreeMap::new(),
+        }
+    }
+}
+
+impl Config {
+    /// Resolves the configuration from, in increasing priority: built-in defaults, the user
+    /// config file, the nearest per-project `.shellm.toml`, `SHELLM_*` variables and `cli`.
+    pub fn load(cli: Table) -> Result<Self, ConfigError> {
+        let user = Self::user_config_path();
+        let project = Self::project_config_path();
+        Self::load_from(user.as_deref(), project.as_deref(), env::vars().collect(), cli)
+    }
+
+    /// Same as `load`, with the config files, the environment variables and the command line
+    /// given explicitly.
+    pub fn load_from(user: Option<&Path>, project: Option<&Path>, vars: Vec<(String, String)>, cli: Table) -> Result<Self, ConfigError> {
+        let mut layers: Vec<(Table, ConfigSource)> = vec![];
+
+        for path in [user, project].into_iter().flatten() {
+            if let Some(table) = Self::read_file(path)? {
+                layers.push((table, ConfigSource::File(path.to_path_buf())));
+            }
+        }
+        for (var, table) in Self::env_layers(vars) {
+            layers.push((table, ConfigSource::Env(var)));
+        }
+        layers.push((cli, ConfigSource::Cli));
+
+        let mut merged = Table::new();
+        let mut sources = BTreeMap::new();
+        for (table, source) in layers {
+            // deserialize every layer on its own first so errors point at the offending source
+            let layer = match Config::deserialize(table.clone()) {
+                Ok(layer) => layer,
+                Err(e) => return Err(ConfigError(format!("Invalid configuration in {}: {}", source, e))),
+            };
+            if let Err(e) = layer.sampling.validate() {
+                return Err(ConfigError(format!("Invalid configuration in {}: {}", source, e.0)));
+            }
+            merge_table(&mut merged, table, &source, "", &mut sources);
+        }
+
+        let mut config = match Config::deserialize(merged) {
+            Ok(config) => config,
+            Err(e) => return Err(ConfigError(format!("Invalid configuration: {}", e))),
+        };
+        config.sources = sources;
+        Ok(config)
+    }
+
+    pub fn user_config_path() -> Option<PathBuf> {
+        let base = match env::var_os("XDG_CONFIG_HOME") {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => PathBuf::from(env::var_os("HOME")?).join(".config"),
+        };
+        Some(base.join(CONFIG_DIR_NAME).join(CONFIG_FILE_NAME))
+    }
+
+    pub fn project_config_path() -> Option<PathBuf> {
+        let cwd = env::current_dir().ok()?;
+        cwd.ancestors()
+            .map(|dir| dir.join(PROJECT_FILE_NAME))
+            .find(|path| path.is_file())
+    }
+
+    fn read_file(path: &Path) -> Result<Option<Table>, ConfigError> {
+        if !path.is_file() {
+            return Ok(None);
+        }
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) => return Err(ConfigError(format!("Cannot read {}: {}", path.display(), e))),
+        };
+        match content.parse::<Table>() {
+            Ok(table) => Ok(Some(table)),
+            Err(e) => Err(ConfigError(format!("Cannot parse {}: {}", path.display(), e))),
+        }
+    }
+
+    /// `SHELLM_MAX_GEN=512` maps to `max_gen`; a double underscore descends into a table.
+    /// Values are read as numbers or booleans when the key takes one, and as strings otherwise.
+    /// Variables naming no setting are reported and skipped.
+    fn env_layers(vars: Vec<(String, String)>) -> Vec<(String, Table)> {
+        let mut vars: Vec<(String, String)> = vars
+            .into_iter()
+            .filter(|(var, _)| var.starts_with(ENV_PREFIX) && var.len() > ENV_PREFIX.len())
+            .collect();
+        vars.sort();
+
+        let known = serde_json::to_value(Config::default()).unwrap_or_default();
+        let mut layers = vec![];
+        for (var, raw) in vars {
+            let key = var[ENV_PREFIX.len()..].to_lowercase();
+            let path: Vec<&str> = key.split("__").collect();
+            let is_setting = path.iter().try_fold(&known, |value, key| value.get(key)).is_some_and(|value| !value.is_object());
+            if !is_setting {
+                eprintln!("{}", colorify(&format!("Ignoring {}: {} is not a setting", var, path.join(".")), 247., 200., 89.));
+                continue;
+            }
+            let typed = nested_table(&path, parse_env_value(&raw));
+            // "123" is still a valid model path or shell name
+            let table = match Config::deserialize(typed.clone()) {
+                Ok(_) => typed,
+                Err(_e) => nested_table(&path, Value::String(raw)),
+            };
+            layers.push((var, table));
+        }
+        layers
+    }
+
+    pub fn source_of(&self, key: &str) -> ConfigSource {
+        self.sources.get(key).cloned().unwrap_or(ConfigSource::Default)
+    }
+
+    /// Every resolved setting as `(key, value, source)`, in key order.
+    pub fn describe(&self) -> Vec<(String, String, ConfigSource)> {
+        let mut entries = vec![];
+        if let Ok(value) = serde_json::to_value(self) {
+            flatten_value("", &value, &mut entries);
+        }
+        entries
+            .into_iter()
+            .map(|(key, value)| {
+                let source = self.source_of(&key);
+                (key, value, source)
+            })
+            .collect()
+    }
+}
+
+/// `{a = {b = value}}` for the path `a`, `b`.
+fn nested_table(path: &[&str], value: Value) -> Table {
+    let mut value = value;
+    for key in path.iter().rev() {
+        let mut table = Table::new();
+        table.insert(key.to_string(), value);
+        value = Value::Table(table);
+    }
+    match value {
+        Value::Table(table) => table,
+        _ => Table::new(),
+    }
+}
+
+fn parse_env_value(raw: &str) -> Value {
+    if let Ok(int) = raw.parse::<i64>() {
+        Value::Integer(int)
+    } else if let Ok(float) = raw.parse::<f64>() {
+        Value::Float(float)
+    } else if let Ok(boolean) = raw.parse::<bool>() {
+        Value::Boolean(boolean)
+    } else {
+        Value::String(raw.to_string())
+    }
+}
+
+fn merge_table(
+    base: &mut Table,
+    layer: Table,
+    source: &ConfigSource,
+    prefix: &str,
+    sources: &mut BTreeMap<String, ConfigSource>,
+) {
+    for (key, value) in layer {
+        let path = if prefix.is_empty() { key.clone() } else { format!("{}.{}", prefix, key) };
+        match value {
+            Value::Table(table) => {
+                let entry = base.entry(key).or_insert_with(|| Value::Table(Table::new()));
+                if !entry.is_table() {
+                    *entry = Value::Table(Table::new());
+                }
+                if let Value::Table(existing) = entry {
+                    merge_table(existing, table, source, &path, sources);
+                }
+            }
+            value => {
+                sources.insert(path, source.clone());
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+fn flatten_value(prefix: &str, value: &serde_json::Value, out: &mut Vec<(String, String)>) {
+    match value {
+        serde_json::Value::Object(map) => {
+            for (key, value) in map {
+                let path = if prefix.is_empty() { key.clone() } else { format!("{}.{}", prefix, key) };
+                flatten_value(&path, value, out);
+            }
+        }
+        serde_json::Value::Null => out.push((prefix.to_string(), "(unset)".to_string())),
+        serde_json::Value::String(s) => out.push((prefix.to_string(), s.clone())),
+        other => out.push((prefix.to_string(), other.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    /// Loads the given user and project files, written to a fresh directory, under `env` and `cli`.
+    fn load(name: &str, user: &str, project: &str, env: &[(&str, &str)], cli: &str) -> Config {
+        let dir = env::temp_dir().join(format!("shellm-config-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (user_path, project_path) = (dir.join(CONFIG_FILE_NAME), dir.join(PROJECT_FILE_NAME));
+        fs::write(&user_path, user).unwrap();
+        fs::write(&project_path, project).unwrap();
+        let config = Config::load_from(Some(&user_path), Some(&project_path), vars(env), cli.parse::<Table>().unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+        config.unwrap()
+    }
+
+    #[test]
+    fn defaults() {
+        let config = Config::load_from(None, None, vec![], Table::new()).unwrap();
+        assert_eq!(config.ctx_window, 30000);
+        assert_eq!(config.max_gen, 10000);
+        assert!(matches!(config.source_of("ctx_window"), ConfigSource::Default));
+    }
+
+    #[test]
+    fn layering_order() {
+        let config = load(
+            "layers",
+            "ctx_window = 1000\nmax_gen = 100\ncandidates = 2\nrepair_attempts = 1\nshell = \"bash\"",
+            "max_gen = 200\ncandidates = 3\nrepair_attempts = 2",
+            &[("SHELLM_CANDIDATES", "4"), ("SHELLM_REPAIR_ATTEMPTS", "3")],
+            "repair_attempts = 4",
+        );
+        assert_eq!(config.ctx_window, 1000);
+        assert_eq!(config.max_gen, 200);
+        assert_eq!(config.candidates, 4);
+        assert_eq!(config.repair_attempts, 4);
+        assert_eq!(config.shell.as_deref(), Some("bash"));
+        assert!(matches!(config.source_of("ctx_window"), ConfigSource::File(path) if path.ends_with(CONFIG_FILE_NAME)));
+        assert!(matches!(config.source_of("max_gen"), ConfigSource::File(path) if path.ends_with(PROJECT_FILE_NAME)));
+        assert!(matches!(config.source_of("candidates"), ConfigSource::Env(var) if var == "SHELLM_CANDIDATES"));
+        assert!(matches!(config.source_of("repair_attempts"), ConfigSource::Cli));
+    }
+
+    #[test]
+    fn nested_keys() {
+        let config = load(
+            "nested",
+            "[sampling]\ntemperature = 0.5\ntop_k = 20\n[listing]\ndepth = 2",
+            "[sampling]\ntop_k = 30",
+            &[("SHELLM_LISTING__MAX_ENTRIES", "7"), ("SHELLM_SAMPLING__SEED", "42")],
+            "",
+        );
+        assert_eq!(config.sampling.temperature, Some(0.5));
+        assert_eq!(config.sampling.top_k, Some(30));
+        assert_eq!(config.sampling.seed, Some(42));
+        assert_eq!((config.listing.depth, config.listing.max_entries), (2, 7));
+        assert!(matches!(config.source_of("sampling.temperature"), ConfigSource::File(_)));
+        assert!(matches!(config.source_of("listing.max_entries"), ConfigSource::Env(_)));
+    }
+
+    #[test]
+    fn env_values_take_the_type_of_their_key() {
+        let config = load(
+            "types",
+            "",
+            "",
+            &[
+                ("SHELLM_MODEL_PATH", "123"),
+                ("SHELLM_SHELL", "1e5"),
+                ("SHELLM_AUDIT_LOG", "true"),
+                ("SHELLM_PASS_STDIN", "true"),
+                ("SHELLM_SAMPLING__TEMPERATURE", "1"),
+                ("SHELLM_OVERFLOW_POLICY", "summarize"),
+            ],
+            "",
+        );
+        assert_eq!(config.model_path.as_deref(), Some("123"));
+        assert_eq!(config.shell.as_deref(), Some("1e5"));
+        assert_eq!(config.audit_log.as_deref(), Some("true"));
+        assert!(config.pass_stdin);
+        assert_eq!(config.sampling.temperature, Some(1.0));
+        assert_eq!(config.overflow_policy, OverflowPolicy::Summarize);
+    }
+
+    #[test]
+    fn unknown_and_invalid_env_values() {
+        let layers = Config::env_layers(vars(&[("SHELLM_NO_SUCH_KEY", "1"), ("SHELLM_LISTING", "x"), ("SHELLM_MAX_GEN", "5"), ("OTHER", "1")]));
+        let names: Vec<&str> = layers.iter().map(|(var, _)| var.as_str()).collect();
+        assert_eq!(names, vec!["SHELLM_MAX_GEN"]);
+
+        let error = Config::load_from(None, None, vars(&[("SHELLM_MAX_GEN", "lots")]), Table::new()).unwrap_err();
+        assert!(error.0.contains("env SHELLM_MAX_GEN"), "{}", error.0);
+    }
+
+    #[test]
+    fn invalid_files_are_named() {
+        let dir = env::temp_dir().join(format!("shellm-config-invalid-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(PROJECT_FILE_NAME);
+        fs::write(&path, "max_gen = \"many\"").unwrap();
+        let error = Config::load_from(None, Some(&path), vec![], Table::new()).unwrap_err();
+        fs::write(&path, "max_gen = ").unwrap();
+        let syntax = Config::load_from(None, Some(&path), vec![], Table::new()).unwrap_err();
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(error.0.contains(PROJECT_FILE_NAME), "{}", error.0);
+        assert!(syntax.0.starts_with("Cannot parse"), "{}", syntax.0);
+    }
+
+    #[test]
+    fn invalid_sampling_values_are_rejected() {
+        let error = Config::load_from(None, None, vars(&[("SHELLM_SAMPLING__TOP_P", "1.5")]), Table::new()).unwrap_err();
+        assert!(error.0.contains("env SHELLM_SAMPLING__TOP_P") && error.0.contains("top_p"), "{}", error.0);
+
+        let cli: Table = toml::from_str("[sampling]\ntemperature = -0.5").unwrap();
+        let error = Config::load_from(None, None, vec![], cli).unwrap_err();
+        assert!(error.0.contains("temperature"), "{}", error.0);
+    }
+}
@@ -0,0 +1,460 @@
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fs;
+
+const ROOT_RULE: &str = "root";
+
+const SPACE_RULE: &str = r#"| " " | "\n" [ \t]{0,20}"#;
+const CHAR_RULE: &str = r#"[^"\\\x7F\x00-\x1F] | [\\] (["\\bfnrt] | "u" [0-9a-fA-F]{4})"#;
+
+#[derive(Debug)]
+pub struct GrammarError(pub String);
+
+/// Restricts generation to a GBNF grammar, either given directly or compiled from a JSON Schema.
+#[derive(Debug, Clone)]
+pub enum Constraint {
+    Gbnf(String),
+    JsonSchema(Value),
+}
+
+impl Constraint {
+    pub fn from_gbnf_file(path: &str) -> Result<Self, GrammarError> {
+        match fs::read_to_string(path) {
+            Ok(grammar) => Ok(Constraint::Gbnf(grammar)),
+            Err(e) => Err(GrammarError(format!("Cannot read grammar {}: {}", path, e))),
+        }
+    }
+
+    pub fn from_json_schema_file(path: &str) -> Result<Self, GrammarError> {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) => return Err(GrammarError(format!("Cannot read JSON schema {}: {}", path, e))),
+        };
+        match serde_json::from_str(&content) {
+            Ok(schema) => Ok(Constraint::JsonSchema(schema)),
+            Err(e) => Err(GrammarError(format!("Cannot parse JSON schema {}: {}", path, e))),
+        }
+    }
+
+    pub fn to_gbnf(&self) -> Result<String, GrammarError> {
+        match self {
+            Constraint::Gbnf(grammar) => {
+                if !defines_rule(grammar, ROOT_RULE) {
+                    return Err(GrammarError("Grammar does not define a root rule".to_string()));
+                }
+                Ok(grammar.clone())
+            }
+            Constraint::JsonSchema(schema) => json_schema_to_gbnf(schema),
+        }
+    }
+}
+
+/// Lets the grammar produce any of `literals` instead of its usual root, e.g. control sentinels.
+pub fn allow_literals(grammar: &str, literals: &[&str]) -> String {
+    if literals.is_empty() {
+        return grammar.to_string();
+    }
+    let renamed = rename_rule(grammar, ROOT_RULE, "root-main");
+    let alternatives: Vec<String> = literals.iter().map(|literal| gbnf_literal(literal)).collect();
+    format!("{} ::= root-main | {}\n{}", ROOT_RULE, alternatives.join(" | "), renamed)
+}
+
+pub fn json_schema_to_gbnf(schema: &Value) -> Result<String, GrammarError> {
+    let mut converter = SchemaConverter { rules: BTreeMap::new() };
+    let body = converter.visit(schema, ROOT_RULE)?;
+    converter.rules.insert(ROOT_RULE.to_string(), body);
+
+    let mut grammar = format!("{} ::= {}\n", ROOT_RULE, converter.rules[ROOT_RULE]);
+    for (name, body) in &converter.rules {
+        if name != ROOT_RULE {
+            grammar.push_str(&format!("{} ::= {}\n", name, body));
+        }
+    }
+    Ok(grammar)
+}
+
+struct SchemaConverter {
+    rules: BTreeMap<String, String>,
+}
+
+impl SchemaConverter {
+    /// Returns a GBNF expression matching `schema`; nested schemas get their own rules.
+    fn visit(&mut self, schema: &Value, name: &str) -> Result<String, GrammarError> {
+        let schema = match schema {
+            Value::Bool(true) => return Ok(self.primitive("value")),
+            Value::Object(schema) => schema,
+            other => return Err(GrammarError(format!("Unsupported schema at {}: {}", name, other))),
+        };
+
+        if let Some(value) = schema.get("const") {
+            self.primitive("space");
+            return Ok(format!("{} space", gbnf_literal(&value.to_string())));
+        }
+        if let Some(Value::Array(values)) = schema.get("enum") {
+            let alternatives: Vec<String> = values.iter().map(|v| gbnf_literal(&v.to_string())).collect();
+            self.primitive("space");
+            return Ok(format!("({}) space", alternatives.join(" | ")));
+        }
+        for key in ["anyOf", "oneOf"] {
+            if let Some(Value::Array(options)) = schema.get(key) {
+                let mut alternatives = vec![];
+                for (i, option) in options.iter().enumerate() {
+                    let rule = format!("{}-{}", name, i);
+                    let body = self.visit(option, &rule)?;
+                    alternatives.push(self.add_rule(&rule, body));
+                }
+                return Ok(alternatives.join(" | "));
+            }
+        }
+
+        match schema.get("type") {
+            Some(Value::Array(types)) => {
+                let mut alternatives = vec![];
+                for t in types {
+                    let mut single = schema.clone();
+                    single.insert("type".to_string(), t.clone());
+                    let rule = format!("{}-{}", name, t.as_str().unwrap_or("value"));
+                    let body = self.visit(&Value::Object(single), &rule)?;
+                    alternatives.push(self.add_rule(&rule, body));
+                }
+                Ok(alternatives.join(" | "))
+            }
+            Some(Value::String(t)) => match t.as_str() {
+                "object" => self.visit_object(schema, name),
+                "array" => self.visit_array(schema, name),
+                "string" => Ok(self.visit_string(schema)),
+                "number" | "integer" | "boolean" | "null" => Ok(self.primitive(t)),
+                other => Err(GrammarError(format!("Unsupported type '{}' at {}", other, name))),
+            },
+            None if schema.contains_key("properties") => self.visit_object(schema, name),
+            None if schema.contains_key("items") => self.visit_array(schema, name),
+            None => Ok(self.primitive("value")),
+            Some(other) => Err(GrammarError(format!("Unsupported type {} at {}", other, name))),
+        }
+    }
+
+    fn visit_object(&mut self, schema: &serde_json::Map<String, Value>, name: &str) -> Result<String, GrammarError> {
+        let properties = match schema.get("properties") {
+            Some(Value::Object(properties)) if !properties.is_empty() => properties,
+            _ => return Ok(self.primitive("object")),
+        };
+        let required: Vec<&str> = match schema.get("required") {
+            Some(Value::Array(required)) => required.iter().filter_map(|r| r.as_str()).collect(),
+            _ => vec![],
+        };
+
+        let mut required_pairs = vec![];
+        let mut optional_pairs = vec![];
+        for (key, property) in properties {
+            let rule = format!("{}-{}", name, sanitize_name(key));
+            let body = self.visit(property, &rule)?;
+            let value_rule = self.add_rule(&rule, body);
+            let pair = format!("{} space \":\" space {}", gbnf_literal(&Value::String(key.clone()).to_string()), value_rule);
+            if required.contains(&key.as_str()) {
+                required_pairs.push(pair);
+            } else {
+                optional_pairs.push(pair);
+            }
+        }
+
+        let mut body = String::from("\"{\" space ");
+        body.push_str(&required_pairs.join(" \",\" space "));
+        if required_pairs.is_empty() {
+            // the first optional pair that is present must not start with a comma
+            let chains: Vec<String> = (0..optional_pairs.len())
+                .map(|i| format!("{}{}", optional_pairs[i], optional_tail(&optional_pairs[i + 1..])))
+                .collect();
+            body.push_str(&format!("({})?", chains.join(" | ")));
+        } else {
+            body.push_str(&optional_tail(&optional_pairs));
+        }
+        body.push_str(" \"}\" space");
+        self.primitive("space");
+        Ok(body)
+    }
+
+    fn visit_array(&mut self, schema: &serde_json::Map<String, Value>, name: &str) -> Result<String, GrammarError> {
+        let item = match schema.get("items") {
+            Some(items) => {
+                let rule = format!("{}-item", name);
+                let body = self.visit(items, &rule)?;
+                self.add_rule(&rule, body)
+            }
+            None => self.primitive("value"),
+        };
+        self.primitive("space");
+
+        let min_items = schema.get("minItems").and_then(|v| v.as_u64()).unwrap_or(0);
+        if let Some(max_items) = schema.get("maxItems").and_then(|v| v.as_u64()) {
+            return Ok(bounded_list(&item, min_items.min(max_items), max_items));
+        }
+        let list = format!("{} (\",\" space {})*", item, item);
+        if min_items > 0 {
+            Ok(format!("\"[\" space {} \"]\" space", list))
+        } else {
+            Ok(format!("\"[\" space ({})? \"]\" space", list))
+        }
+    }
+
+    fn visit_string(&mut self, schema: &serde_json::Map<String, Value>) -> String {
+        let min = schema.get("minLength").and_then(|v| v.as_u64());
+        let max = schema.get("maxLength").and_then(|v| v.as_u64());
+        if min.is_none() && max.is_none() {
+            return self.primitive("string");
+        }
+        self.primitive("char");
+        self.primitive("space");
+        let repeat = match max {
+            Some(max) => format!("{{{},{}}}", min.unwrap_or(0), max),
+            None => format!("{{{},}}", min.unwrap_or(0)),
+        };
+        format!("\"\\\"\" char{} \"\\\"\" space", repeat)
+    }
+
+    /// Adds one of the shared JSON rules, with its dependencies, and returns its name.
+    fn primitive(&mut self, name: &str) -> String {
+        let (name, body, deps): (&str, &str, &[&str]) = match name {
+            "space" => ("space", SPACE_RULE, &[]),
+            "char" => ("char", CHAR_RULE, &[]),
+            "string" => ("string", r#""\"" char* "\"" space"#, &["char", "space"]),
+            "integral-part" => ("integral-part", r#"[0] | [1-9] [0-9]{0,15}"#, &[]),
+            "number" => ("number", r#"("-"? integral-part) ("." [0-9]{1,16})? ([eE] [-+]? integral-part)? space"#, &["integral-part", "space"]),
+            "integer" => ("integer", r#"("-"? integral-part) space"#, &["integral-part", "space"]),
+            "boolean" => ("boolean", r#"("true" | "false") space"#, &["space"]),
+            "null" => ("null", r#""null" space"#, &["space"]),
+            "object" => ("object", r#""{" space (string ":" space value ("," space string ":" space value)*)? "}" space"#, &["string", "value", "space"]),
+            "array" => ("array", r#""[" space (value ("," space value)*)? "]" space"#, &["value", "space"]),
+            _ => ("value", r#"object | array | string | number | boolean | null"#, &["object", "array", "string", "number", "boolean", "null"]),
+        };
+        if !self.rules.contains_key(name) {
+            self.rules.insert(name.to_string(), body.to_string());
+            for dep in deps {
+                self.primitive(dep);
+            }
+        }
+        name.to_string()
+    }
+
+    fn add_rule(&mut self, name: &str, body: String) -> String {
+        let name = sanitize_name(name);
+        match self.rules.get(&name) {
+            Some(existing) if *existing != body => {
+                let unique = (1..).map(|i| format!("{}{}", name, i)).find(|n| !self.rules.contains_key(n)).unwrap();
+                self.rules.insert(unique.clone(), body);
+                unique
+            }
+            _ => {
+                self.rules.insert(name.clone(), body);
+                name
+            }
+        }
+    }
+}
+
+/// An array of `min` to `max` items, spelled out item by item.
+fn bounded_list(item: &str, min: u64, max: u64) -> String {
+    let mut optional = String::new();
+    for _ in min.max(1)..max {
+        optional = format!(" (\",\" space {}{})?", item, optional);
+    }
+    let mut list = item.to_string();
+    for _ in 1..min {
+        list.push_str(&format!(" \",\" space {}", item));
+    }
+    list.push_str(&optional);
+    match (min, max) {
+        (_, 0) => "\"[\" space \"]\" space".to_string(),
+        (0, _) => format!("\"[\" space ({})? \"]\" space", list),
+        _ => format!("\"[\" space {} \"]\" space", list),
+    }
+}
+
+fn optional_tail(pairs: &[String]) -> String {
+    pairs.iter().map(|pair| format!(" (\",\" space {})?", pair)).collect()
+}
+
+fn sanitize_name(name: &str) -> String {
+    name.chars().map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '-' }).collect()
+}
+
+/// Quotes `text` as a GBNF string literal.
+fn gbnf_literal(text: &str) -> String {
+    let mut literal = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => literal.push_str("\\\""),
+            '\\' => literal.push_str("\\\\"),
+            '\n' => literal.push_str("\\n"),
+            '\r' => literal.push_str("\\r"),
+            '\t' => literal.push_str("\\t"),
+            c => literal.push(c),
+        }
+    }
+    literal.push('"');
+    literal
+}
+
+fn is_rule_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '-' || c == '_'
+}
+
+fn defines_rule(grammar: &str, rule: &str) -> bool {
+    grammar.lines().any(|line| {
+        let line = line.trim_start();
+        line.strip_prefix(rule).is_some_and(|rest| rest.trim_start().starts_with("::="))
+    })
+}
+
+/// Renames every reference to the rule `from`, skipping string literals and character classes.
+fn rename_rule(grammar: &str, from: &str, to: &str) -> String {
+    let mut result = String::with_capacity(grammar.len());
+    let mut chars = grammar.chars().peekable();
+    let mut word = String::new();
+
+    let flush = |word: &mut String, result: &mut String| {
+        result.push_str(if word == from { to } else { word });
+        word.clear();
+    };
+
+    while let Some(c) = chars.next() {
+        if is_rule_char(c) {
+            word.push(c);
+            continue;
+        }
+        flush(&mut word, &mut result);
+        result.push(c);
+
+        let close = match c {
+            '"' => '"',
+            '[' => ']',
+            '#' => '\n',
+            _ => continue,
+        };
+        while let Some(c) = chars.next() {
+            result.push(c);
+            if c == '\\' && close != '\n' {
+                if let Some(escaped) = chars.next() {
+                    result.push(escaped);
+                }
+            } else if c == close {
+                break;
+            }
+        }
+    }
+    flush(&mut word, &mut result);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn compile(schema: Value) -> BTreeMap<String, String> {
+        let grammar = json_schema_to_gbnf(&schema).unwrap();
+        grammar
+            .lines()
+            .map(|line| {
+                let (name, body) = line.split_once(" ::= ").unwrap();
+                (name.to_string(), body.to_string())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn required_and_optional_properties() {
+        let rules = compile(json!({
+            "type": "object",
+            "properties": {"command": {"type": "string"}, "note": {"type": "string"}},
+            "required": ["command"]
+        }));
+        assert_eq!(rules["root"], r#""{" space "\"command\"" space ":" space root-command ("," space "\"note\"" space ":" space root-note)? "}" space"#);
+        assert_eq!(rules["root-command"], "string");
+    }
+
+    #[test]
+    fn only_optional_properties() {
+        let rules = compile(json!({
+            "properties": {"a": {"type": "integer"}, "b": {"type": "boolean"}}
+        }));
+        assert_eq!(rules["root"], r#""{" space ("\"a\"" space ":" space root-a ("," space "\"b\"" space ":" space root-b)? | "\"b\"" space ":" space root-b)? "}" space"#);
+    }
+
+    #[test]
+    fn array_bounds() {
+        let rules = compile(json!({"type": "array", "items": {"type": "number"}, "minItems": 1, "maxItems": 3}));
+        assert_eq!(rules["root"], r#""[" space root-item ("," space root-item ("," space root-item)?)? "]" space"#);
+
+        let rules = compile(json!({"type": "array", "items": {"type": "number"}, "minItems": 2, "maxItems": 2}));
+        assert_eq!(rules["root"], r#""[" space root-item "," space root-item "]" space"#);
+
+        let rules = compile(json!({"type": "array", "items": {"type": "number"}, "minItems": 1}));
+        assert_eq!(rules["root"], r#""[" space root-item ("," space root-item)* "]" space"#);
+
+        let rules = compile(json!({"type": "array", "items": {"type": "number"}}));
+        assert_eq!(rules["root"], r#""[" space (root-item ("," space root-item)*)? "]" space"#);
+
+        let rules = compile(json!({"type": "array", "maxItems": 0}));
+        assert_eq!(rules["root"], r#""[" space "]" space"#);
+    }
+
+    #[test]
+    fn enums_and_constants() {
+        let rules = compile(json!({"enum": ["low", "high", 3]}));
+        assert_eq!(rules["root"], r#"("\"low\"" | "\"high\"" | "3") space"#);
+        let rules = compile(json!({"const": "yes"}));
+        assert_eq!(rules["root"], r#""\"yes\"" space"#);
+        assert!(rules.contains_key("space"));
+    }
+
+    #[test]
+    fn alternatives() {
+        let rules = compile(json!({
+            "anyOf": [
+                {"type": "object", "properties": {"command": {"type": "string"}}, "required": ["command"]},
+                {"type": "object", "properties": {"question": {"type": "string"}}, "required": ["question"]}
+            ]
+        }));
+        assert_eq!(rules["root"], "root-0 | root-1");
+        assert_eq!(rules["root-1"], r#""{" space "\"question\"" space ":" space root-1-question "}" space"#);
+
+        let rules = compile(json!({"type": ["string", "null"]}));
+        assert_eq!(rules["root"], "root-string | root-null");
+        assert_eq!(rules["root-null"], "null");
+    }
+
+    #[test]
+    fn string_lengths() {
+        let rules = compile(json!({"type": "string", "minLength": 1, "maxLength": 8}));
+        assert_eq!(rules["root"], r#""\"" char{1,8} "\"" space"#);
+        assert!(rules.contains_key("char"));
+    }
+
+    #[test]
+    fn unsupported_schemas() {
+        assert!(json_schema_to_gbnf(&json!({"type": "date"})).is_err());
+        assert!(json_schema_to_gbnf(&json!(3)).is_err());
+        assert!(Constraint::Gbnf("start ::= \"a\"".to_string()).to_gbnf().is_err());
+        assert!(Constraint::Gbnf("root ::= \"a\"".to_string()).to_gbnf().is_ok());
+    }
+
+    #[test]
+    fn escaped_literals() {
+        assert_eq!(gbnf_literal("say \"hi\"\n"), r#""say \"hi\"\n""#);
+        assert_eq!(gbnf_literal(r"C:\tmp"), r#""C:\\tmp""#);
+        assert_eq!(gbnf_literal("a\tb\r"), r#""a\tb\r""#);
+        let rules = compile(json!({"properties": {"weird key\"": {"type": "null"}}, "required": ["weird key\""]}));
+        assert_eq!(rules["root"], r#""{" space "\"weird key\\\"\"" space ":" space root-weird-key- "}" space"#);
+    }
+
+    #[test]
+    fn renamed_root_with_literals() {
+        let grammar = "root ::= item (\"root\" [a-z root])* # root\nitem ::= root-ish | \"x\"\n";
+        assert_eq!(
+            allow_literals(grammar, &["<END>", "say \"no\""]),
+            "root ::= root-main | \"<END>\" | \"say \\\"no\\\"\"\nroot-main ::= item (\"root\" [a-z root])* # root\nitem ::= root-ish | \"x\"\n"
+        );
+        assert_eq!(allow_literals(grammar, &[]), grammar);
+        assert_eq!(rename_rule("root ::= \"\\\"root\" root", "root", "main"), "main ::= \"\\\"root\" main");
+    }
+}
@@ -10,9 +10,14 @@ use llama_cpp_2::llama_backend::LlamaBackend;
 use llama_cpp_2::llama_batch::LlamaBatch;
 use llama_cpp_2::model::{AddBos, LlamaChatMessage, LlamaModel, Special};
 use llama_cpp_2::model::params::LlamaModelParams;
-use llama_cpp_2::sampling::LlamaSampler;
-use llama_cpp_2::sampling::params::LlamaSamplerChainParams;
 use llama_cpp_2::token::LlamaToken;
+use serde::{Deserialize, Serialize};
+use crate::utils::grammar::{Constraint, GrammarError};
+use crate::utils::sampling::SamplingConfig;
+
+const SUMMARY_MAX_GEN: usize = 256;
+const SUMMARY_PROMPT_RESERVE: usize = 128; // instructions and chat template around the summarized text
+const SUMMARY_PROMPT: &str = "Summarize the following conversation in a few sentences. Keep the facts, decisions, file names and commands that later questions may refer to. Respond with the summary only.\n\n";
 
 #[derive(Debug)]
 pub struct SaveInstanceError;
@@ -20,6 +25,50 @@ pub struct SaveInstanceError;
 #[derive(Debug)]
 pub struct LoadInstanceError;
 
+#[derive(Debug)]
+pub struct LoadModelError;
+
+#[derive(Debug)]
+pub struct ContextOverflowError {
+    pub required: usize,
+    pub available: usize,
+}
+
+/// Why `ModelInstance::inference` stopped producing tokens.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    /// the model emitted an end-of-generation token
+    EndOfGeneration,
+    /// `max_gen` new tokens were generated
+    Budget,
+    /// the context window ran out before `max_gen` was reached
+    ContextFull,
+    /// the output ended with one of the requested stop sequences
+    StopSequence,
+}
+
+#[derive(Debug)]
+pub struct Generation {
+    pub tokens: Vec<LlamaToken>,
+    pub text: String, // decoded output, cut right before a matched stop sequence
+    pub stop_reason: StopReason,
+    pub stop_sequence: Option<String>,
+    pub logprob: f32, // sum over `tokens` of their log-probability under the model, before sampling
+}
+
+/// What to do when the history, the next query and the generation budget exceed the window.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum OverflowPolicy {
+    /// fail with a `ContextOverflowError`
+    Refuse,
+    /// keep the system prompt and drop the oldest turns
+    #[default]
+    Shift,
+    /// replace the oldest turns with a short assistant-written summary
+    Summarize,
+}
+
 pub struct ModelContainer {
     model: LlamaModel,
     backend: LlamaBackend
@@ -27,18 +76,20 @@ pub struct ModelContainer {
 
 impl ModelContainer {
 
-    pub fn new(model_path: &str) -> Self {
+    pub fn new(model_path: &str, gpu_layers: u32) -> Result<Self, LoadModelError> {
         let mut backend = LlamaBackend::init().unwrap();
         backend.void_logs();
 
-        let model_params  = LlamaModelParams::default().with_n_gpu_layers(5000);
-        let model =
-            LlamaModel::load_from_file(&backend, PathBuf::from(model_path), &model_params).unwrap();
+        let model_params  = LlamaModelParams::default().with_n_gpu_layers(gpu_layers);
+        let model = match LlamaModel::load_from_file(&backend, PathBuf::from(model_path), &model_params) {
+            Ok(model) => model,
+            Err(_e) => { return Err(LoadModelError); }
+        };
 
-        ModelContainer {
+        Ok(ModelContainer {
             model,
             backend
-        }
+        })
     }
 
 }
@@ -93,6 +144,13 @@ pub struct ModelInstance<'a> {
     ctx_window: u32,
     ctx: LlamaContext<'a>,
     tokens: Vec<LlamaToken>,
+    n_past: usize, // leading tokens of `tokens` already in the kv cache
+    turns: Vec<usize>, // start of every query appended to `tokens`
+    system_tokens: Vec<LlamaToken>,
+    overflow_policy: OverflowPolicy,
+    sampling: SamplingConfig,
+    stop_sequences: Vec<String>,
+    grammar: Option<String>,
 }
 
 impl <'a>ModelInstance<'a> {
@@ -116,13 +174,62 @@ impl <'a>ModelInstance<'a> {
         ModelInstance {
             ctx_window,
             ctx,
-            tokens: vec![]
+            tokens: vec![],
+            n_past: 0,
+            turns: vec![],
+            system_tokens: vec![],
+            overflow_policy: OverflowPolicy::default(),
+            sampling: SamplingConfig::greedy(),
+            stop_sequences: vec![],
+            grammar: None
         }
     }
 
+    /// Restricts every following generation to `constraint`, or lifts the restriction.
+    pub fn set_constraint(&mut self, constraint: Option<&Constraint>) -> Result<(), GrammarError> {
+        self.grammar = match constraint {
+            Some(constraint) => Some(constraint.to_gbnf()?),
+            None => None,
+        };
+        Ok(())
+    }
+
+    /// Generation stops as soon as the output contains one of `stop_sequences`.
+    pub fn set_stop_sequences(&mut self, stop_sequences: Vec<String>) {
+        self.stop_sequences = stop_sequences.into_iter().filter(|s| !s.is_empty()).collect();
+    }
+
+    pub fn set_overflow_policy(&mut self, policy: OverflowPolicy) {
+        self.overflow_policy = policy;
+    }
+
+    /// Marks the rendered system prompt as the part of the history that is never dropped.
+    pub fn pin_system_prompt(&mut self, content: &str) {
+        let chat = vec![LlamaChatMessage::new(ChatRole::System.value(), content.to_string()).unwrap()];
+        let prompt = self.ctx.model.apply_chat_template(None, chat, false).unwrap();
+        self.system_tokens = self.ctx.model.str_to_token(&prompt, AddBos::Always).unwrap();
+    }
+
+    pub fn set_sampling(&mut self, sampling: SamplingConfig) {
+        self.sampling = sampling;
+    }
+
+    pub fn sampling(&self) -> &SamplingConfig {
+        &self.sampling
+    }
+
+    /// Tokens for `chat` followed by the assistant header. Only a dialogue that starts the
+    /// history gets a BOS token, anything appended later would put one mid-sequence.
     fn create_chat_dialogue(&self, chat: Vec<LlamaChatMessage>) -> Vec<LlamaToken> {
         let prompt = self.ctx.model.apply_chat_template(None, chat, true).unwrap();
-        self.ctx.model.str_to_token(&prompt, AddBos::Always).unwrap()
+        let add_bos = if self.tokens.is_empty() { AddBos::Always } else { AddBos::Never };
+        self.ctx.model.str_to_token(&prompt, add_bos).unwrap()
+    }
+
+    fn create_chat_turn(&self, role: ChatRole, content: &str) -> Vec<LlamaToken> {
+        let chat = vec![LlamaChatMessage::new(role.value(), content.to_string()).unwrap()];
+        let prompt = self.ctx.model.apply_chat_template(None, chat, false).unwrap();
+        self.ctx.model.str_to_token(&prompt, AddBos::Never).unwrap()
     }
 
     pub fn load_from_session(
@@ -139,6 +246,8 @@ impl <'a>ModelInstance<'a> {
         };
 
         past_tokens.iter().for_each(|x| model_instance.tokens.push(x.clone()));
+        model_instance.n_past = model_instance.tokens.len(); // the session file restores the kv cache too
+        model_instance.turns.push(0);
         Ok(model_instance)
     }
 
@@ -152,15 +261,164 @@ impl <'a>ModelInstance<'a> {
     }
 
     fn stream_tokens(&mut self, tokens: &Vec<LlamaToken>) {
+        self.turns.push(self.tokens.len());
         tokens.iter().for_each(|x| self.tokens.push(*x));
     }
 
+    pub fn ctx_window(&self) -> u32 {
+        self.ctx_window
+    }
+
+    pub fn history(&self) -> &[LlamaToken] {
+        &self.tokens
+    }
+
+    /// Replaces the token history, keeping the cache for the prefix shared with the old one.
+    pub fn set_history(&mut self, tokens: Vec<LlamaToken>) {
+        let shared = self.tokens.iter().zip(tokens.iter()).take_while(|(a, b)| a == b).count();
+        self.truncate_history(shared);
+        if shared < tokens.len() {
+            self.turns.push(shared);
+        }
+        self.tokens = tokens;
+    }
+
+    /// Drops everything after the first `keep` tokens from the history and the kv cache.
+    pub fn truncate_history(&mut self, keep: usize) {
+        self.evict_cache(keep);
+        self.tokens.truncate(keep);
+        self.turns.retain(|&start| start < keep);
+    }
+
+    fn pinned_len(&self) -> usize {
+        if !self.system_tokens.is_empty() && self.tokens.starts_with(&self.system_tokens) {
+            self.system_tokens.len()
+        } else {
+            0
+        }
+    }
+
+    /// Makes sure `incoming` new tokens plus a generation budget of `reserve` fit in the window,
+    /// applying the overflow policy to the oldest turns when they do not.
+    fn make_room(&mut self, incoming: usize, reserve: usize) -> Result<(), ContextOverflowError> {
+        let available = self.ctx_window as usize;
+        let required = self.tokens.len() + incoming + reserve;
+        if required <= available {
+            return Ok(());
+        }
+
+        let overflow = ContextOverflowError { required, available };
+        if self.overflow_policy == OverflowPolicy::Refuse {
+            return Err(overflow);
+        }
+
+        let keep = self.pinned_len();
+        let extra = match self.overflow_policy {
+            OverflowPolicy::Summarize => SUMMARY_MAX_GEN + SUMMARY_PROMPT_RESERVE,
+            _ => 0,
+        };
+        // the first turn boundary that frees enough room, or the end of the history
+        let cut = self.turns.iter().copied()
+            .filter(|&start| start > keep)
+            .chain(std::iter::once(self.tokens.len()))
+            .find(|&cut| cut > keep && required + extra - (cut - keep) <= available);
+
+        match cut {
+            Some(cut) if self.overflow_policy == OverflowPolicy::Summarize => self.summarize_range(keep, cut),
+            Some(cut) => self.drop_range(keep, cut),
+            None => return Err(overflow),
+        }
+        Ok(())
+    }
+
+    /// Removes `tokens[start..end]`, shifting the cached positions after it instead of re-decoding.
+    fn drop_range(&mut self, start: usize, end: usize) {
+        let n_discard = end - start;
+        if self.n_past >= end {
+            self.ctx.clear_kv_cache_seq(Some(0), Some(start as u32), Some(end as u32)).unwrap();
+            self.ctx.kv_cache_seq_add(0, Some(end as u32), None, -(n_discard as i32)).unwrap();
+            self.n_past -= n_discard;
+        } else {
+            self.evict_cache(start);
+        }
+
+        self.tokens.drain(start..end);
+        self.turns.retain(|&turn| turn < start || turn >= end);
+        self.turns.iter_mut().filter(|turn| **turn >= end).for_each(|turn| *turn -= n_discard);
+    }
+
+    /// Replaces `tokens[start..end]` with an assistant note summarizing it.
+    fn summarize_range(&mut self, start: usize, end: usize) {
+        let budget = (self.ctx_window as usize).saturating_sub(start + SUMMARY_MAX_GEN + SUMMARY_PROMPT_RESERVE);
+        let dropped_start = end - (end - start).min(budget);
+        let dropped = self.tokens[dropped_start..end].to_vec();
+        let remaining = self.tokens[end..].to_vec();
+        let remaining_turns: Vec<usize> = self.turns.iter().filter(|&&turn| turn >= end).map(|turn| turn - end).collect();
+
+        self.truncate_history(start);
+
+        let conversation = self.decode_tokens(dropped, false);
+        let query = self.create_chat_dialogue(vec![
+            LlamaChatMessage::new(ChatRole::User.value(), format!("{}{}", SUMMARY_PROMPT, conversation)).unwrap()
+        ]);
+        let sampling = std::mem::replace(&mut self.sampling, SamplingConfig::greedy());
+        let stop_sequences = std::mem::take(&mut self.stop_sequences);
+        let grammar = self.grammar.take();
+        self.stream_tokens(&query);
+        let summary = self.generate(SUMMARY_MAX_GEN, false, || {});
+        self.sampling = sampling;
+        self.stop_sequences = stop_sequences;
+        self.grammar = grammar;
+
+        let summary = summary.text;
+        self.truncate_history(start);
+
+        let note = self.create_chat_turn(ChatRole::Assistant, &format!("Summary of the earlier conversation: {}", summary.trim()));
+        self.stream_tokens(&note);
+
+        let offset = self.tokens.len();
+        self.turns.extend(remaining_turns.into_iter().map(|turn| turn + offset));
+        self.tokens.extend(remaining);
+    }
+
+    fn evict_cache(&mut self, from: usize) {
+        if from < self.n_past {
+            self.ctx.clear_kv_cache_seq(Some(0), Some(from as u32), None).unwrap();
+            self.n_past = from;
+        }
+    }
+
+    /// Decodes the tokens that are not in the kv cache yet, in chunks of at most `n_batch`.
+    /// Returns the last batch, whose final token holds the logits to sample from.
+    fn decode_pending(&mut self) -> LlamaBatch {
+        let n_batch = self.ctx.n_batch() as usize;
+        let mut batch = LlamaBatch::new(n_batch, 1); // [S, B]
+
+        // nothing new to decode; re-decode the last token so there are logits to sample from
+        if self.n_past == self.tokens.len() {
+            self.evict_cache(self.n_past - 1);
+        }
+
+        let last_index = self.tokens.len() - 1;
+        while self.n_past < self.tokens.len() {
+            let end = (self.n_past + n_batch).min(self.tokens.len());
+            batch.clear();
+            for pos in self.n_past..end {
+                batch.add(self.tokens[pos], pos as i32, &[0], pos == last_index).unwrap();
+            }
+            self.ctx.decode(&mut batch).unwrap();
+            self.n_past = end;
+        }
+
+        batch
+    }
+
     pub fn decode_tokens(&self, tokens: Vec<LlamaToken>, output_buff: bool) -> String {
         let mut decoded: String = "".to_owned();
         let mut decoder = encoding_rs::UTF_8.new_decoder();
 
         for token in tokens {
-            let output_bytes = self.ctx.model.token_to_bytes(token, Special::Tokenize).unwrap(); // get token to utf bytes
+            let output_bytes = self.ctx.model.token_to_bytes(token, Special::Plaintext).unwrap(); // get token to utf bytes
 
             let mut output_string = String::with_capacity(32);
             let _decode_result = decoder.decode_to_string(&output_bytes, &mut output_string, false);
@@ -176,85 +434,152 @@ impl <'a>ModelInstance<'a> {
         decoded
     }
 
-    pub fn init_sys(&mut self, content: String, max_gen: i32, output: bool, yield_output: bool) -> Option<Vec<LlamaToken>> {
+    pub fn init_sys(&mut self, content: String, max_gen: i32, output: bool, yield_output: bool) -> Result<Option<Generation>, ContextOverflowError> {
         let mut chat: Vec<LlamaChatMessage> = vec![];
         chat.push(LlamaChatMessage::new("system".to_string(), content).unwrap());
 
         if output {
-            self.print_after_inference(self.create_chat_dialogue(chat), max_gen, yield_output, || {});
-            None
+            self.print_after_inference(self.create_chat_dialogue(chat), max_gen, yield_output, || {})?;
+            Ok(None)
         } else {
-            Some(self.inference(self.create_chat_dialogue(chat), max_gen, false, || {}))
+            Ok(Some(self.inference(self.create_chat_dialogue(chat), max_gen, false, || {})?))
         }
     }
 
-    pub fn user_query(&mut self, content: String, max_gen: i32, output: bool, yield_output: bool) -> Option<Vec<LlamaToken>> {
+    pub fn user_query(&mut self, content: String, max_gen: i32, output: bool, yield_output: bool) -> Result<Option<Generation>, ContextOverflowError> {
         let mut chat: Vec<LlamaChatMessage> = vec![];
         chat.push(LlamaChatMessage::new("user".to_string(), content).unwrap());
 
         if output {
-            self.print_after_inference(self.create_chat_dialogue(chat), max_gen, yield_output, || {});
-            None
+            self.print_after_inference(self.create_chat_dialogue(chat), max_gen, yield_output, || {})?;
+            Ok(None)
         } else {
-            Some(self.inference(self.create_chat_dialogue(chat), max_gen, false, || {}))
+            Ok(Some(self.inference(self.create_chat_dialogue(chat), max_gen, false, || {})?))
         }
     }
 
-    pub fn chat_query<F>(&mut self, chat: &ChatWrapper, max_gen: i32, output: bool, yield_output: bool, do_after: F) -> Option<Vec<LlamaToken>>
+    pub fn chat_query<F>(&mut self, chat: &ChatWrapper, max_gen: i32, output: bool, yield_output: bool, do_after: F) -> Result<Option<Generation>, ContextOverflowError>
     where
         F: Fn() -> () {
         if output {
-            self.print_after_inference(chat.to_tokens(&self.ctx), max_gen, false, do_after);
-            None
+            self.print_after_inference(self.create_chat_dialogue(chat.chat.clone()), max_gen, false, do_after)?;
+            Ok(None)
         }  else if yield_output {
-            Some(self.inference(chat.to_tokens(&self.ctx), max_gen, true, do_after))
+            Ok(Some(self.inference(self.create_chat_dialogue(chat.chat.clone()), max_gen, true, do_after)?))
         } else {
-            Some(self.inference(chat.to_tokens(&self.ctx), max_gen, false, do_after))
+            Ok(Some(self.inference(self.create_chat_dialogue(chat.chat.clone()), max_gen, false, do_after)?))
+        }
+    }
+
+    /// Answers `chat` `n` times, each candidate sampled with `SamplingConfig::for_candidate` from
+    /// the same history. The query is decoded once and only the generations are repeated.
+    /// Afterwards the history ends with the first candidate, see `replace_generation`.
+    pub fn chat_candidates(&mut self, chat: &ChatWrapper, max_gen: i32, n: u32) -> Result<Vec<Generation>, ContextOverflowError> {
+        let query = self.create_chat_dialogue(chat.chat.clone());
+        let max_gen = max_gen.max(0) as usize;
+        self.make_room(query.len(), max_gen)?;
+        self.stream_tokens(&query);
+
+        let start = self.tokens.len();
+        let sampling = self.sampling.clone();
+        let mut candidates = vec![];
+        for index in 0..n.max(1) {
+            self.truncate_history(start);
+            self.sampling = sampling.for_candidate(index);
+            candidates.push(self.generate(max_gen, false, || {}));
         }
+        self.sampling = sampling;
+        self.replace_generation(&candidates[candidates.len() - 1].tokens, &candidates[0].tokens);
+        Ok(candidates)
     }
 
-    pub fn print_after_inference<F>(&mut self, query: Vec<LlamaToken>, max_gen: i32, yield_output: bool, do_after: F) where F: Fn() -> () {
-        let result = self.inference(query, max_gen, yield_output, do_after);
+    /// Swaps the generation at the end of the history for another answer to the same query,
+    /// e.g. the candidate the user picked. Returns false, changing nothing, when the history
+    /// does not end with `current`.
+    pub fn replace_generation(&mut self, current: &[LlamaToken], replacement: &[LlamaToken]) -> bool {
+        let Some(start) = generation_start(&self.tokens, current) else { return false };
+        if current == replacement {
+            return true;
+        }
+        // the replacement is decoded along with the next query
+        self.truncate_history(start);
+        self.tokens.extend_from_slice(replacement);
+        true
+    }
+
+    pub fn print_after_inference<F>(&mut self, query: Vec<LlamaToken>, max_gen: i32, yield_output: bool, do_after: F) -> Result<(), ContextOverflowError> where F: Fn() -> () {
+        let result = self.inference(query, max_gen, yield_output, do_after)?;
         if !yield_output {
-            println!("{}", self.decode_tokens(result, false));
+            println!("{}", result.text);
         }
+        Ok(())
     }
 
-    pub fn inference<F>(&mut self, query: Vec<LlamaToken>, max_gen: i32, output: bool, do_on_start: F) -> Vec<LlamaToken>
+    /// Appends `query` to the history and generates up to `max_gen` new tokens after it.
+    pub fn inference<F>(&mut self, query: Vec<LlamaToken>, max_gen: i32, output: bool, do_on_start: F) -> Result<Generation, ContextOverflowError>
     where F: Fn() -> () {
-        let mut result: Vec<LlamaToken> = vec![];
+        let max_gen = max_gen.max(0) as usize;
+        self.make_room(query.len(), max_gen)?;
         self.stream_tokens(&query);
+        Ok(self.generate(max_gen, output, do_on_start))
+    }
 
-        let mut batch = LlamaBatch::new(self.ctx_window as usize, 1); // [S, B]
-
-        let tokens: &Vec<LlamaToken> = &self.tokens;
-
-        let last_index: i32 = (tokens.len() - 1) as i32;
-        for (i, token) in (0_i32..).zip(tokens.into_iter()) {
-            let is_last = i == last_index;
-            batch.add(*token, i, &[0], is_last).unwrap();
+    /// Samples at most `max_gen` new tokens, never past the end of the context window.
+    /// When streaming, text that could be the start of a stop sequence is held back until
+    /// it is clear that it is not one, so sentinels never reach the terminal.
+    fn generate<F>(&mut self, max_gen: usize, output: bool, do_on_start: F) -> Generation
+    where F: Fn() -> () {
+        let mut result: Vec<LlamaToken> = vec![];
+        let mut text = String::new();
+        if self.tokens.is_empty() {
+            return Generation { tokens: result, text, stop_reason: StopReason::EndOfGeneration, stop_sequence: None, logprob: 0.0 };
         }
 
-        self.ctx.decode(&mut batch).unwrap();
+        let mut batch = self.decode_pending();
 
-        let mut n_curr = batch.n_tokens();
+        let ceiling = (self.ctx_window as usize).saturating_sub(self.tokens.len());
+        let budget = max_gen.min(ceiling);
 
-        let mut sampler = LlamaSampler::new(LlamaSamplerChainParams::default()).unwrap();
-        sampler = LlamaSampler::add_greedy(sampler);
+        let sampler = self.sampling.build(self.ctx.model, self.grammar.as_deref());
+        let mut decoder = encoding_rs::UTF_8.new_decoder();
 
         let mut done_once = false;
+        let mut printed = 0;
+        let mut stop_sequence = None;
+        let mut logprob = 0.0;
+        let stop_reason;
+
+        loop {
+            if result.len() >= budget {
+                stop_reason = if budget < max_gen { StopReason::ContextFull } else { StopReason::Budget };
+                break;
+            }
 
-        while n_curr <= max_gen {
-            let token = sampler.sample(&self.ctx, batch.n_tokens() - 1); // get next token
-            sampler.accept(token); // not needed unless using different sampling method
-            if self.ctx.model.is_eog_token(token) { break; }
+            let index = batch.n_tokens() - 1;
+            let token = sampler.sample(&self.ctx, index); // get next token, also accepts it into the chain
+            if self.ctx.model.is_eog_token(token) {
+                stop_reason = StopReason::EndOfGeneration;
+                break;
+            }
+            logprob += token_logprob(self.ctx.get_logits_ith(index), token);
             result.push(token);
             self.tokens.push(token);
 
             batch.clear(); // clear batch
-            batch.add(token, n_curr, &[0], true).unwrap(); // add generated token to batch
+            batch.add(token, self.n_past as i32, &[0], true).unwrap(); // add generated token to batch
 
             self.ctx.decode(&mut batch).unwrap();
+            self.n_past += 1;
+
+            let bytes = self.ctx.model.token_to_bytes(token, Special::Tokenize).unwrap(); // get token to utf bytes
+            let search_from = text.len();
+            text.reserve(decoder.max_utf8_buffer_length(bytes.len()).unwrap_or(64));
+            let _decode_result = decoder.decode_to_string(&bytes, &mut text, false);
+
+            if let Some((index, sequence)) = find_stop_sequence(&text, search_from, &self.stop_sequences) {
+                text.truncate(index);
+                stop_sequence = Some(sequence);
+            }
 
             if output {
                 if !done_once {
@@ -262,16 +587,183 @@ impl <'a>ModelInstance<'a> {
                     sleep(Duration::from_millis(50));
                     done_once = true;
                 }
-                self.decode_tokens(vec![token], true);
+                let safe = if stop_sequence.is_some() {
+                    text.len()
+                } else {
+                    text.len() - partial_stop_suffix(&text, &self.stop_sequences)
+                };
+                if safe > printed {
+                    print!("{}", &text[printed..safe]);
+                    std::io::stdout().flush().unwrap(); // flush to stdout
+                    printed = safe;
+                }
             }
 
-            n_curr += 1;
+            if stop_sequence.is_some() {
+                stop_reason = StopReason::StopSequence;
+                break;
+            }
         }
 
         if output {
-            println!();
+            if !done_once {
+                do_on_start(); // nothing was generated, still let the caller stop its indicator
+                sleep(Duration::from_millis(50));
+            }
+            println!("{}", text.get(printed..).unwrap_or(""));
         }
 
-        result
+        Generation { tokens: result, text, stop_reason, stop_sequence, logprob }
+    }
+}
+
+/// Natural log of the softmax of `logits` at `token`.
+fn token_logprob(logits: &[f32], token: LlamaToken) -> f32 {
+    let Some(logit) = logits.get(token.0 as usize) else { return 0.0 };
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let sum: f32 = logits.iter().map(|l| (l - max).exp()).sum();
+    logit - max - sum.ln()
+}
+
+/// Where `generation` starts in `history`, when the history ends with it.
+fn generation_start(history: &[LlamaToken], generation: &[LlamaToken]) -> Option<usize> {
+    history.ends_with(generation).then(|| history.len() - generation.len())
+}
+
+/// Finds the earliest stop sequence that ends after `search_from`, the length of the text
+/// before the last token was appended.
+fn find_stop_sequence(text: &str, search_from: usize, stop_sequences: &[String]) -> Option<(usize, String)> {
+    stop_sequences.iter()
+        .filter_map(|sequence| {
+            let mut start = search_from.saturating_sub(sequence.len());
+            while !text.is_char_boundary(start) {
+                start -= 1;
+            }
+            text[start..].find(sequence.as_str()).map(|index| (start + index, sequence.clone()))
+        })
+        .min_by_key(|(index, _)| *index)
+}
+
+/// Length of the longest suffix of `text` that is a proper prefix of a stop sequence.
+fn partial_stop_suffix(text: &str, stop_sequences: &[String]) -> usize {
+    stop_sequences.iter()
+        .flat_map(|sequence| (1..sequence.len()).map(move |len| (sequence, len)))
+        .filter(|(sequence, len)| {
+            *len <= text.len()
+                && sequence.is_char_boundary(*len)
+                && text.is_char_boundary(text.len() - len)
+                && text.ends_with(&sequence[..*len])
+        })
+        .map(|(_, len)| len)
+        .max()
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(ids: &[i32]) -> Vec<LlamaToken> {
+        ids.iter().map(|id| LlamaToken::new(*id)).collect()
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn picked_candidates_replace_the_last_generation() {
+        // query 1-2, then the first candidate 10-11
+        let mut history = tokens(&[1, 2, 10, 11]);
+        let (first, best, other) = (tokens(&[10, 11]), tokens(&[20, 21, 22]), tokens(&[30]));
+        let start = generation_start(&history, &first).unwrap();
+        history.truncate(start);
+        history.extend_from_slice(&best);
+        assert_eq!(history, tokens(&[1, 2, 20, 21, 22]));
+
+        // a syntax repair asks again and the history ends with its answer, not with `best`
+        let repaired = tokens(&[40, 41]);
+        history.extend(tokens(&[3, 4]));
+        history.extend_from_slice(&repaired);
+        assert_eq!(generation_start(&history, &best), None);
+        let start = generation_start(&history, &repaired).unwrap();
+        history.truncate(start);
+        history.extend_from_slice(&other);
+        assert_eq!(history, tokens(&[1, 2, 20, 21, 22, 3, 4, 30]));
+
+        assert_eq!(generation_start(&history, &[]), Some(history.len()));
+        assert_eq!(generation_start(&tokens(&[30]), &tokens(&[1, 30])), None);
+    }
+
+    fn stops(sequences: &[&str]) -> Vec<String> {
+        sequences.iter().map(|s| s.to_string()).collect()
+    }
+
+    /// Feeds `pieces` the way `generate` appends decoded tokens and returns what is printed
+    /// after each one, the final text and the stop sequence that ended it.
+    fn stream(pieces: &[&str], stop_sequences: &[String]) -> (Vec<String>, String, Option<String>) {
+        let (mut text, mut printed, mut shown) = (String::new(), 0, vec![]);
+        for piece in pieces {
+            let search_from = text.len();
+            text.push_str(piece);
+            if let Some((index, sequence)) = find_stop_sequence(&text, search_from, stop_sequences) {
+                text.truncate(index);
+                shown.push(text[printed..].to_string());
+                return (shown, text, Some(sequence));
+            }
+            let safe = text.len() - partial_stop_suffix(&text, stop_sequences);
+            shown.push(text[printed..safe].to_string());
+            printed = safe.max(printed);
+        }
+        shown.push(text[printed..].to_string()); // the end of the stream flushes what was held back
+        (shown, text, None)
+    }
+
+    #[test]
+    fn stop_sequences_split_across_tokens() {
+        let sequences = stops(&["<SAVE>"]);
+        let (shown, text, stop) = stream(&["ls -la", " <S", "AV", "E> rest"], &sequences);
+        assert_eq!(shown, vec!["ls -la", " ", "", ""]);
+        assert_eq!(text, "ls -la ");
+        assert_eq!(stop.as_deref(), Some("<SAVE>"));
+
+        // only text that ends after `search_from` is searched, so a match needs the new token
+        assert_eq!(find_stop_sequence("a <SAVE> b", 9, &sequences), None);
+        assert_eq!(find_stop_sequence("a <SAVE> b", 7, &sequences), Some((2, "<SAVE>".to_string())));
+        // the search start is moved back to a char boundary
+        assert_eq!(find_stop_sequence("ééé<SAVE>", 9, &sequences), Some((6, "<SAVE>".to_string())));
+    }
+
+    #[test]
+    fn partial_stop_sequences_are_held_back() {
+        let sequences = stops(&["<SAVE>", "<EXIT>"]);
+        assert_eq!(partial_stop_suffix("rm -r <SA", &sequences), 3);
+        assert_eq!(partial_stop_suffix("rm -r <", &sequences), 1);
+        assert_eq!(partial_stop_suffix("rm -r <SAX", &sequences), 0);
+        assert_eq!(partial_stop_suffix("<SAVE", &sequences), 5);
+        assert_eq!(partial_stop_suffix("", &sequences), 0);
+
+        // held back until the stream ends without completing the stop sequence
+        let (shown, text, stop) = stream(&["done", " <SA", "VED"], &sequences);
+        assert_eq!(shown, vec!["done", " ", "<SAVED", ""]);
+        assert_eq!((text.as_str(), stop), ("done <SAVED", None));
+        let (shown, text, stop) = stream(&["done", " <EX"], &sequences);
+        assert_eq!(shown, vec!["done", " ", "<EX"]);
+        assert_eq!((text.as_str(), stop), ("done <EX", None));
+    }
+
+    #[test]
+    fn overlapping_stop_sequences() {
+        // the earliest match wins, whichever sequence is listed first
+        let sequences = stops(&["SAVE>", "<SAVE>"]);
+        assert_eq!(find_stop_sequence("ls <SAVE>", 0, &sequences), Some((3, "<SAVE>".to_string())));
+        let sequences = stops(&["<EXIT>", "<SAVE>"]);
+        assert_eq!(find_stop_sequence("a<SAVE><EXIT>", 0, &sequences), Some((1, "<SAVE>".to_string())));
+
+        // a suffix can be a prefix of one sequence and the middle of another
+        let sequences = stops(&["ab", "abc", "bcd"]);
+        assert_eq!(partial_stop_suffix("xab", &sequences), 2);
+        assert_eq!(partial_stop_suffix("xbc", &sequences), 2);
+        assert_eq!(partial_stop_suffix("xa", &sequences), 1);
+
+        let (shown, text, stop) = stream(&["x", "a", "bc"], &sequences);
+        assert_eq!(shown, vec!["x", "", ""]);
+        assert_eq!((text.as_str(), stop.as_deref()), ("x", Some("ab")));
+    }
+}
@@ -0,0 +1,325 @@
+use llama_cpp_2::model::LlamaModel;
+use llama_cpp_2::sampling::params::LlamaSamplerChainParams;
+use llama_cpp_2::sampling::LlamaSampler;
+use serde::{Deserialize, Serialize};
+
+const DEFAULT_SEED: u32 = 0xFFFFFFFF; // llama.cpp picks a random seed
+const MIROSTAT_M: i32 = 100;
+const CANDIDATE_TEMPERATURE: f32 = 0.7; // alternatives need room to differ from the first answer
+
+#[derive(Debug)]
+pub struct SamplingError(pub String);
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SamplingConfig {
+    pub temperature: f32, // 0 samples greedily
+    pub top_k: i32,       // 0 disables
+    pub top_p: f32,       // 1.0 disables
+    pub min_p: f32,       // 0.0 disables
+    pub typical_p: f32,   // 1.0 disables
+    pub repeat_penalty: f32,
+    pub repeat_last_n: i32,
+    pub frequency_penalty: f32,
+    pub presence_penalty: f32,
+    pub mirostat: u8, // 0 = off, 1 = mirostat, 2 = mirostat v2
+    pub mirostat_tau: f32,
+    pub mirostat_eta: f32,
+    pub seed: Option<u32>,
+}
+
+impl Default for SamplingConfig {
+    fn default() -> Self {
+        SamplingConfig {
+            temperature: 0.0,
+            top_k: 0,
+            top_p: 1.0,
+            min_p: 0.0,
+            typical_p: 1.0,
+            repeat_penalty: 1.0,
+            repeat_last_n: 64,
+            frequency_penalty: 0.0,
+            presence_penalty: 0.0,
+            mirostat: 0,
+            mirostat_tau: 5.0,
+            mirostat_eta: 0.1,
+            seed: None,
+        }
+    }
+}
+
+impl SamplingConfig {
+    pub fn greedy() -> Self {
+        SamplingConfig::default()
+    }
+
+    pub fn is_greedy(&self) -> bool {
+        self.temperature <= 0.0
+    }
+
+    /// Settings for the `index`th of several candidate answers. The first keeps these settings,
+    /// the others sample at a higher temperature, each with its own seed when one is set.
+    pub fn for_candidate(&self, index: u32) -> Self {
+        if index == 0 {
+            return self.clone();
+        }
+        SamplingConfig {
+            temperature: self.temperature.max(CANDIDATE_TEMPERATURE),
+            seed: self.seed.map(|seed| seed.wrapping_add(index)),
+            ..self.clone()
+        }
+    }
+
+    fn has_penalties(&self) -> bool {
+        self.repeat_penalty != 1.0 || self.frequency_penalty != 0.0 || self.presence_penalty != 0.0
+    }
+
+    /// Rejects settings llama.cpp would silently misuse, naming the first offending one.
+    pub fn validate(&self) -> Result<(), SamplingError> {
+        let probabilities = [("top_p", self.top_p), ("min_p", self.min_p), ("typical_p", self.typical_p)];
+        if !(0.0..).contains(&self.temperature) {
+            return Err(SamplingError(format!("temperature must not be negative, got {}", self.temperature)));
+        }
+        if let Some((name, value)) = probabilities.iter().find(|(_, value)| !(0.0..=1.0).contains(value)) {
+            return Err(SamplingError(format!("{} must be between 0 and 1, got {}", name, value)));
+        }
+        if self.top_k < 0 {
+            return Err(SamplingError(format!("top_k must not be negative, got {}", self.top_k)));
+        }
+        if self.repeat_last_n < -1 {
+            return Err(SamplingError(format!("repeat_last_n must be -1 (whole context) or more, got {}", self.repeat_last_n)));
+        }
+        if self.repeat_penalty.is_nan() || self.repeat_penalty <= 0.0 {
+            return Err(SamplingError(format!("repeat_penalty must be positive, got {}", self.repeat_penalty)));
+        }
+        if self.mirostat > 2 {
+            return Err(SamplingError(format!("mirostat must be 0, 1 or 2, got {}", self.mirostat)));
+        }
+        if !(0.0..).contains(&self.mirostat_tau) || !(0.0..).contains(&self.mirostat_eta) {
+            return Err(SamplingError("mirostat_tau and mirostat_eta must not be negative".to_string()));
+        }
+        Ok(())
+    }
+
+    /// The samplers of the chain in order: penalties, the optional GBNF grammar, then either
+    /// greedy, mirostat or the usual truncation samplers followed by temperature and a seeded
+    /// distribution sampler.
+    fn stages(&self, grammar: bool) -> Vec<Stage> {
+        let mut stages = vec![];
+        if self.has_penalties() {
+            stages.push(Stage::Penalties);
+        }
+        if grammar {
+            stages.push(Stage::Grammar);
+        }
+
+        if self.is_greedy() {
+            stages.push(Stage::Greedy);
+            return stages;
+        }
+
+        match self.mirostat {
+            1 => stages.extend([Stage::Temperature, Stage::Mirostat]),
+            2 => stages.extend([Stage::Temperature, Stage::MirostatV2]),
+            _ => {
+                if self.top_k > 0 {
+                    stages.push(Stage::TopK);
+                }
+                if self.typical_p < 1.0 {
+                    stages.push(Stage::Typical);
+                }
+                if self.top_p < 1.0 {
+                    stages.push(Stage::TopP);
+                }
+                if self.min_p > 0.0 {
+                    stages.push(Stage::MinP);
+                }
+                stages.extend([Stage::Temperature, Stage::Distribution]);
+            }
+        }
+        stages
+    }
+
+    /// Builds the llama sampler chain from `stages`.
+    pub fn build(&self, model: &LlamaModel, grammar: Option<&str>) -> LlamaSampler {
+        let mut sampler = LlamaSampler::new(LlamaSamplerChainParams::default()).unwrap();
+        let seed = self.seed.unwrap_or(DEFAULT_SEED);
+
+        for stage in self.stages(grammar.is_some()) {
+            sampler = match stage {
+                Stage::Penalties => sampler.add_penalties(
+                    model.n_vocab(),
+                    model.token_eos().0,
+                    model.token_nl().0,
+                    self.repeat_last_n,
+                    self.repeat_penalty,
+                    self.frequency_penalty,
+                    self.presence_penalty,
+                    false,
+                    false,
+                ),
+                Stage::Grammar => sampler.add_grammar(model, grammar.unwrap_or_default(), "root"),
+                Stage::Greedy => sampler.add_greedy(),
+                Stage::TopK => sampler.add_top_k(self.top_k),
+                Stage::Typical => sampler.add_typical(self.typical_p, 1),
+                Stage::TopP => sampler.add_top_p(self.top_p, 1),
+                Stage::MinP => sampler.add_min_p(self.min_p, 1),
+                Stage::Temperature => sampler.add_temp(self.temperature),
+                Stage::Mirostat => {
+                    sampler.add_mirostat(model.n_vocab(), seed, self.mirostat_tau, self.mirostat_eta, MIROSTAT_M)
+                }
+                Stage::MirostatV2 => sampler.add_mirostat_v2(seed, self.mirostat_tau, self.mirostat_eta),
+                Stage::Distribution => sampler.add_dist(seed),
+            };
+        }
+        sampler
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Stage {
+    Penalties,
+    Grammar,
+    Greedy,
+    TopK,
+    Typical,
+    TopP,
+    MinP,
+    Temperature,
+    Mirostat,
+    MirostatV2,
+    Distribution,
+}
+
+/// Sampling settings from the config, each one replacing the mode default when set.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct SamplingOverrides {
+    pub temperature: Option<f32>,
+    pub top_k: Option<i32>,
+    pub top_p: Option<f32>,
+    pub min_p: Option<f32>,
+    pub typical_p: Option<f32>,
+    pub repeat_penalty: Option<f32>,
+    pub repeat_last_n: Option<i32>,
+    pub frequency_penalty: Option<f32>,
+    pub presence_penalty: Option<f32>,
+    pub mirostat: Option<u8>,
+    pub mirostat_tau: Option<f32>,
+    pub mirostat_eta: Option<f32>,
+    pub seed: Option<u32>,
+}
+
+impl SamplingOverrides {
+    pub fn apply(&self, mut base: SamplingConfig) -> SamplingConfig {
+        if let Some(v) = self.temperature { base.temperature = v; }
+        if let Some(v) = self.top_k { base.top_k = v; }
+        if let Some(v) = self.top_p { base.top_p = v; }
+        if let Some(v) = self.min_p { base.min_p = v; }
+        if let Some(v) = self.typical_p { base.typical_p = v; }
+        if let Some(v) = self.repeat_penalty { base.repeat_penalty = v; }
+        if let Some(v) = self.repeat_last_n { base.repeat_last_n = v; }
+        if let Some(v) = self.frequency_penalty { base.frequency_penalty = v; }
+        if let Some(v) = self.presence_penalty { base.presence_penalty = v; }
+        if let Some(v) = self.mirostat { base.mirostat = v; }
+        if let Some(v) = self.mirostat_tau { base.mirostat_tau = v; }
+        if let Some(v) = self.mirostat_eta { base.mirostat_eta = v; }
+        if self.seed.is_some() { base.seed = self.seed; }
+        base
+    }
+
+    /// Checks the values that are set; the greedy defaults under them are always valid.
+    pub fn validate(&self) -> Result<(), SamplingError> {
+        self.apply(SamplingConfig::default()).validate()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chain_order() {
+        let greedy = SamplingConfig::greedy();
+        assert_eq!(greedy.stages(false), vec![Stage::Greedy]);
+        assert_eq!(greedy.stages(true), vec![Stage::Grammar, Stage::Greedy]);
+
+        let config = SamplingConfig {
+            temperature: 0.8,
+            top_k: 40,
+            top_p: 0.9,
+            min_p: 0.05,
+            typical_p: 0.95,
+            repeat_penalty: 1.1,
+            ..SamplingConfig::default()
+        };
+        assert_eq!(
+            config.stages(true),
+            vec![
+                Stage::Penalties,
+                Stage::Grammar,
+                Stage::TopK,
+                Stage::Typical,
+                Stage::TopP,
+                Stage::MinP,
+                Stage::Temperature,
+                Stage::Distribution,
+            ]
+        );
+
+        let plain = SamplingConfig { temperature: 0.8, ..SamplingConfig::default() };
+        assert_eq!(plain.stages(false), vec![Stage::Temperature, Stage::Distribution]);
+        // mirostat replaces the truncation samplers
+        let mirostat = SamplingConfig { mirostat: 2, ..config.clone() };
+        assert_eq!(mirostat.stages(false), vec![Stage::Penalties, Stage::Temperature, Stage::MirostatV2]);
+        let mirostat = SamplingConfig { mirostat: 1, repeat_penalty: 1.0, ..config };
+        assert_eq!(mirostat.stages(false), vec![Stage::Temperature, Stage::Mirostat]);
+    }
+
+    #[test]
+    fn overrides_replace_mode_defaults() {
+        let mode = SamplingConfig { temperature: 0.1, top_k: 20, top_p: 0.9, ..SamplingConfig::default() };
+        let overrides = SamplingOverrides { temperature: Some(0.0), top_p: Some(0.5), seed: Some(7), ..Default::default() };
+        let config = overrides.apply(mode.clone());
+        assert_eq!(config, SamplingConfig { temperature: 0.0, top_p: 0.5, seed: Some(7), ..mode.clone() });
+        assert!(config.is_greedy());
+        assert_eq!(SamplingOverrides::default().apply(mode.clone()), mode);
+    }
+
+    #[test]
+    fn candidates_sample_hotter_with_their_own_seed() {
+        let config = SamplingConfig { seed: Some(10), ..SamplingConfig::greedy() };
+        assert_eq!(config.for_candidate(0), config);
+        let second = config.for_candidate(2);
+        assert_eq!((second.temperature, second.seed), (CANDIDATE_TEMPERATURE, Some(12)));
+        let hot = SamplingConfig { temperature: 1.2, ..SamplingConfig::default() };
+        assert_eq!(hot.for_candidate(1).temperature, 1.2);
+    }
+
+    #[test]
+    fn invalid_values() {
+        assert!(SamplingConfig::default().validate().is_ok());
+        assert!(SamplingOverrides::default().validate().is_ok());
+
+        let invalid = [
+            SamplingOverrides { temperature: Some(-0.1), ..Default::default() },
+            SamplingOverrides { temperature: Some(f32::NAN), ..Default::default() },
+            SamplingOverrides { top_p: Some(1.5), ..Default::default() },
+            SamplingOverrides { top_p: Some(-0.1), ..Default::default() },
+            SamplingOverrides { min_p: Some(2.0), ..Default::default() },
+            SamplingOverrides { typical_p: Some(f32::NAN), ..Default::default() },
+            SamplingOverrides { top_k: Some(-1), ..Default::default() },
+            SamplingOverrides { repeat_last_n: Some(-2), ..Default::default() },
+            SamplingOverrides { repeat_penalty: Some(0.0), ..Default::default() },
+            SamplingOverrides { mirostat: Some(3), ..Default::default() },
+            SamplingOverrides { mirostat_eta: Some(-1.0), ..Default::default() },
+        ];
+        for overrides in invalid {
+            assert!(overrides.validate().is_err(), "{:?}", overrides);
+        }
+
+        let edges = SamplingOverrides { temperature: Some(0.0), top_p: Some(0.0), min_p: Some(1.0), repeat_last_n: Some(-1), ..Default::default() };
+        assert!(edges.validate().is_ok());
+        let error = SamplingOverrides { top_p: Some(1.5), ..Default::default() }.validate().unwrap_err();
+        assert_eq!(error.0, "top_p must be between 0 and 1, got 1.5");
+    }
+}